@@ -2,8 +2,11 @@
 use clap::{Parser, Subcommand};
 use iroh_base::ticket::BlobTicket;
 use iroh_blobs::{Hash, HashAndFormat};
-use iroh_net::NodeId;
-use std::{fmt::Display, net::SocketAddr, str::FromStr};
+use iroh_mainline_content_discovery::protocol::{
+    ContentKey, DetailLevel, SeedClass, VerificationMethod,
+};
+use iroh_net::{NodeAddr, NodeId};
+use std::{fmt::Display, net::SocketAddr, path::PathBuf, str::FromStr};
 
 #[derive(Parser, Debug)]
 pub struct Args {
@@ -16,6 +19,8 @@ pub enum Commands {
     Announce(AnnounceArgs),
     Query(QueryArgs),
     QueryDht(QueryDhtArgs),
+    QueryAll(QueryAllArgs),
+    Mirror(MirrorArgs),
 }
 
 /// Various ways to specify content.
@@ -28,14 +33,31 @@ pub enum ContentArg {
 
 impl ContentArg {
     /// Get the hash and format of the content.
+    ///
+    /// This is the same for all ways of specifying the same content, see [ContentKey].
     pub fn hash_and_format(&self) -> HashAndFormat {
         match self {
-            ContentArg::Hash(hash) => HashAndFormat::raw(*hash),
-            ContentArg::HashAndFormat(haf) => *haf,
-            ContentArg::Ticket(ticket) => HashAndFormat {
-                hash: ticket.hash(),
-                format: ticket.format(),
-            },
+            ContentArg::Hash(hash) => hash.content_key(),
+            ContentArg::HashAndFormat(haf) => haf.content_key(),
+            ContentArg::Ticket(ticket) => ticket.content_key(),
+        }
+    }
+
+    /// The node of the content, if it is a ticket.
+    ///
+    /// Tickets for collections are blob tickets with the hash seq format, so they
+    /// carry a node the same way.
+    pub fn host(&self) -> Option<NodeId> {
+        self.hosts().first().map(|addr| addr.node_id)
+    }
+
+    /// All nodes of the content, with the addrs given in the ticket.
+    ///
+    /// A blob ticket carries exactly one node. Hashes carry no nodes at all.
+    pub fn hosts(&self) -> Vec<NodeAddr> {
+        match self {
+            ContentArg::Hash(_) | ContentArg::HashAndFormat(_) => Vec::new(),
+            ContentArg::Ticket(ticket) => vec![ticket.node_addr().clone()],
         }
     }
 }
@@ -66,15 +88,58 @@ impl FromStr for ContentArg {
     }
 }
 
+/// A quic tracker, given as an address, optionally prefixed with the expected node id.
+///
+/// E.g. `1.2.3.4:4433` or `<node id>@1.2.3.4:4433`.
+#[derive(Debug, Clone)]
+pub struct QuicTrackerArg {
+    pub node_id: Option<NodeId>,
+    pub addr: SocketAddr,
+}
+
+impl Display for QuicTrackerArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.node_id {
+            Some(node_id) => write!(f, "{}@{}", node_id, self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+impl FromStr for QuicTrackerArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (node_id, addr) = match s.split_once('@') {
+            Some((node_id, addr)) => (Some(NodeId::from_str(node_id)?), addr),
+            None => (None, s),
+        };
+        let addr = SocketAddr::from_str(addr)?;
+        Ok(Self { node_id, addr })
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct AnnounceArgs {
     /// trackers to announce to via udp
     #[clap(long)]
     pub udp_tracker: Vec<SocketAddr>,
 
-    /// trackers to announce to via quic
+    /// trackers to announce to via quic, as `addr` or `node_id@addr`
+    #[clap(long)]
+    pub quic_tracker: Vec<QuicTrackerArg>,
+
+    /// Only warn if a quic tracker has a different node id than given.
+    ///
+    /// This is insecure, anybody who can intercept the connection can pose as the
+    /// tracker. Only use this for debugging.
+    #[clap(long)]
+    pub insecure_allow_id_mismatch: bool,
+
+    /// The ALPN of the trackers, for a tracker network that is separate from the
+    /// public one.
     #[clap(long)]
-    pub quic_tracker: Vec<SocketAddr>,
+    pub alpn: Option<String>,
 
     /// trackers to announce to via magicsock
     #[clap(long)]
@@ -85,12 +150,56 @@ pub struct AnnounceArgs {
     /// Content can be specified as a hash, a hash and format, or a ticket.
     /// If a hash is specified, the format is assumed to be raw.
     /// Unless a ticket is specified, the host must be specified.
-    pub content: ContentArg,
+    #[clap(required_unless_present = "path")]
+    pub content: Option<ContentArg>,
+
+    /// Local files to hash and announce as raw blobs.
+    ///
+    /// The MIME type of each file is inferred, unless --mime-type is given.
+    #[clap(long)]
+    pub path: Vec<PathBuf>,
+
+    /// The number of files to hash concurrently.
+    #[clap(long, default_value_t = 1)]
+    pub parallel: usize,
+
+    /// Announce all content or none of it, to each quic tracker.
+    ///
+    /// A tracker rejects the whole set if one of the announces is invalid. This is not
+    /// supported via udp.
+    #[clap(long, conflicts_with = "udp_tracker")]
+    pub atomic: bool,
 
     /// Announce that the peer has only partial data.
     #[clap(long)]
     pub partial: bool,
 
+    /// The namespace, for trackers shared by multiple applications.
+    ///
+    /// The name is hashed to get the namespace id.
+    #[clap(long)]
+    pub namespace: Option<String>,
+
+    /// How available this host intends to keep the content: transient, peer or mirror.
+    ///
+    /// This is a hint for downloaders, trackers do not check it.
+    #[clap(long, default_value_t = SeedClass::default())]
+    pub class: SeedClass,
+
+    /// Commit to serving the content for this many seconds.
+    ///
+    /// The commitment is signed, so queriers can tell you made it. Nothing enforces it.
+    #[clap(long)]
+    pub commit_for: Option<u64>,
+
+    /// A local file with the content, used to infer the MIME type of the content.
+    #[clap(long)]
+    pub file: Option<PathBuf>,
+
+    /// The MIME type of the content. Takes precedence over the inferred type.
+    #[clap(long)]
+    pub mime_type: Option<String>,
+
     /// the port to use for announcing via udp
     #[clap(long)]
     pub udp_port: Option<u16>,
@@ -102,6 +211,18 @@ pub struct AnnounceArgs {
     /// the port to use for announcing via quic
     #[clap(long)]
     pub quic_port: Option<u16>,
+
+    /// Also announce the content to the mainline DHT.
+    ///
+    /// Failing to announce to the trackers will not prevent the DHT announce, and vice versa.
+    #[clap(long)]
+    pub also_dht: bool,
+
+    /// The port to announce to the DHT, on which the tracker protocol is reachable via udp.
+    ///
+    /// Defaults to the udp port.
+    #[clap(long)]
+    pub dht_port: Option<u16>,
 }
 
 #[derive(Parser, Debug)]
@@ -110,6 +231,22 @@ pub struct QueryArgs {
     #[clap(long)]
     pub tracker: Vec<SocketAddr>,
 
+    /// trackers to query via quic, as `addr` or `node_id@addr`
+    #[clap(long)]
+    pub quic_tracker: Vec<QuicTrackerArg>,
+
+    /// Only warn if a quic tracker has a different node id than given.
+    ///
+    /// This is insecure, anybody who can intercept the connection can pose as the
+    /// tracker. Only use this for debugging.
+    #[clap(long)]
+    pub insecure_allow_id_mismatch: bool,
+
+    /// The ALPN of the trackers, for a tracker network that is separate from the
+    /// public one.
+    #[clap(long)]
+    pub alpn: Option<String>,
+
     /// The content to find hosts for.
     pub content: ContentArg,
 
@@ -117,13 +254,78 @@ pub struct QueryArgs {
     #[clap(long)]
     pub partial: bool,
 
+    /// The namespace, for trackers shared by multiple applications.
+    ///
+    /// The name is hashed to get the namespace id.
+    #[clap(long)]
+    pub namespace: Option<String>,
+
+    /// Only ask for hosts of at least this class: transient, peer or mirror
+    #[clap(long)]
+    pub min_class: Option<SeedClass>,
+
     /// Ask for hosts that were recently checked and found to have some data
     #[clap(long)]
     pub verified: bool,
 
+    /// Ask for hosts that were recently verified by at least this method: size or random-range
+    ///
+    /// This implies --verified.
+    #[clap(long)]
+    pub require_method: Option<VerificationMethod>,
+
+    /// Ask for a random sample of at most this many hosts
+    #[clap(long)]
+    pub sample: Option<u32>,
+
+    /// Also ask for hosts whose announce expired at most this many seconds ago
+    #[clap(long)]
+    pub include_expired: Option<u64>,
+
+    /// Ask for hosts that committed to serving the content first
+    #[clap(long)]
+    pub prefer_committed: bool,
+
+    /// Fetch hosts from quic trackers in pages of at most this many hosts
+    #[clap(long)]
+    pub page_size: Option<u32>,
+
+    /// How much to ask for per host: minimal, standard or full
+    ///
+    /// With minimal, quic trackers only return node ids.
+    #[clap(long, default_value_t = DetailLevel::default())]
+    pub detail: DetailLevel,
+
+    /// Ask quic trackers for the proofs they got when verifying hosts, and check them
+    #[clap(long)]
+    pub include_proofs: bool,
+
+    /// Print a shell script that downloads the content from the hosts found
+    ///
+    /// The script tries `iroh blobs get` with a ticket for each host in turn, until
+    /// one succeeds.
+    #[clap(long, conflicts_with_all = ["node_addrs", "by_relay"])]
+    pub emit_script: bool,
+
     /// the port to use for querying
     #[clap(long)]
     pub udp_port: Option<u16>,
+
+    /// the port to use for querying via quic
+    #[clap(long)]
+    pub quic_port: Option<u16>,
+
+    /// Print the node addr of each host as a line of JSON, for use by other tools.
+    ///
+    /// Addrs are only provided by quic trackers. For other hosts, only the node id is known.
+    #[clap(long)]
+    pub node_addrs: bool,
+
+    /// Print the hosts grouped by relay, to plan connections to many hosts.
+    ///
+    /// Like addrs, relays are only provided by quic trackers.
+    #[clap(long, conflicts_with = "node_addrs")]
+    pub by_relay: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -135,10 +337,20 @@ pub struct QueryDhtArgs {
     #[clap(long)]
     pub partial: bool,
 
+    /// The namespace, for trackers shared by multiple applications.
+    ///
+    /// The name is hashed to get the namespace id.
+    #[clap(long)]
+    pub namespace: Option<String>,
+
     /// Ask for hosts that were recently checked and found to have some data
     #[clap(long)]
     pub verified: bool,
 
+    /// Ask for a random sample of at most this many hosts
+    #[clap(long)]
+    pub sample: Option<u32>,
+
     /// Parallelism for querying the dht
     #[clap(long)]
     pub query_parallelism: Option<usize>,
@@ -147,3 +359,118 @@ pub struct QueryDhtArgs {
     #[clap(long)]
     pub udp_port: Option<u16>,
 }
+
+#[derive(Parser, Debug)]
+pub struct QueryAllArgs {
+    /// trackers to query via magicsock
+    #[clap(long)]
+    pub magicsock_tracker: Vec<NodeId>,
+
+    /// The ALPN of the trackers, for a tracker network that is separate from the
+    /// public one.
+    #[clap(long)]
+    pub alpn: Option<String>,
+
+    /// The content to find hosts for.
+    pub content: ContentArg,
+
+    /// Ask for hosts that were announced as having just partial data
+    #[clap(long)]
+    pub partial: bool,
+
+    /// The namespace, for trackers shared by multiple applications.
+    ///
+    /// The name is hashed to get the namespace id.
+    #[clap(long)]
+    pub namespace: Option<String>,
+
+    /// Ask for hosts that were recently checked and found to have some data
+    #[clap(long)]
+    pub verified: bool,
+
+    /// Ask for a random sample of at most this many hosts
+    #[clap(long)]
+    pub sample: Option<u32>,
+
+    /// Only query trackers found via the dht, ignoring the given trackers
+    #[clap(long, conflicts_with = "tracker_only")]
+    pub dht_only: bool,
+
+    /// Only query the given trackers, without consulting the dht
+    #[clap(long)]
+    pub tracker_only: bool,
+
+    /// Parallelism for querying
+    #[clap(long)]
+    pub query_parallelism: Option<usize>,
+
+    /// Dial at most this many trackers in total, e.g. on a metered connection
+    #[clap(long)]
+    pub max_dials: Option<usize>,
+
+    /// Print how effective the query via the DHT was
+    #[clap(long)]
+    pub stats: bool,
+
+    /// the port to use for querying via magicsock
+    #[clap(long)]
+    pub iroh_port: Option<u16>,
+
+    /// the port to use for querying trackers found via the dht
+    #[clap(long)]
+    pub quic_port: Option<u16>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MirrorArgs {
+    /// trackers to find hosts with and to announce to via quic, as `addr` or `node_id@addr`
+    #[clap(long)]
+    pub quic_tracker: Vec<QuicTrackerArg>,
+
+    /// Only warn if a quic tracker has a different node id than given.
+    ///
+    /// This is insecure, anybody who can intercept the connection can pose as the
+    /// tracker. Only use this for debugging.
+    #[clap(long)]
+    pub insecure_allow_id_mismatch: bool,
+
+    /// The ALPN of the trackers, for a tracker network that is separate from the
+    /// public one.
+    #[clap(long)]
+    pub alpn: Option<String>,
+
+    /// The content to mirror.
+    ///
+    /// If a ticket is specified, the host of the ticket is tried first.
+    pub content: ContentArg,
+
+    /// The directory to store the content in, one file per blob named by its hash.
+    #[clap(long)]
+    pub store: PathBuf,
+
+    /// The namespace, for trackers shared by multiple applications.
+    ///
+    /// The name is hashed to get the namespace id.
+    #[clap(long)]
+    pub namespace: Option<String>,
+
+    /// Only download from hosts that were recently checked and found to have some data
+    #[clap(long)]
+    pub verified: bool,
+
+    /// The class to announce with once the content is mirrored: transient, peer or mirror.
+    #[clap(long, default_value_t = SeedClass::Mirror)]
+    pub class: SeedClass,
+
+    /// Commit to serving the content for this many seconds, once it is mirrored.
+    #[clap(long)]
+    pub commit_for: Option<u64>,
+
+    /// the port to use for downloading via iroh
+    #[clap(long)]
+    pub iroh_port: Option<u16>,
+
+    /// the port to use for querying and announcing via quic
+    #[clap(long)]
+    pub quic_port: Option<u16>,
+}
@@ -1,27 +1,42 @@
 pub mod args;
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
 use anyhow::Context;
-use args::QueryDhtArgs;
+use args::{MirrorArgs, QueryAllArgs, QueryDhtArgs};
 use clap::Parser;
 use futures::StreamExt;
+use iroh_base::ticket::BlobTicket;
+use iroh_blobs::{
+    get::fsm::{ConnectedNext, EndBlobNext},
+    hashseq::HashSeq,
+    protocol::GetRequest,
+    BlobFormat, Hash, HashAndFormat,
+};
 use iroh_mainline_content_discovery::{
-    create_quinn_client,
-    protocol::{AbsoluteTime, Announce, AnnounceKind, Query, QueryFlags, SignedAnnounce},
-    to_infohash, UdpDiscovery,
+    announce_atomic, announce_batch, announce_dht, check_tracker_id, create_quinn_client,
+    mime::{infer_mime_type, mime_type_from_extension, mime_type_from_magic},
+    protocol::{
+        dedup_announces, validate_alpn, AbsoluteTime, Announce, AnnounceKind, AnnounceMetadata,
+        DetailLevel, DownloadSource, Namespace, Query, QueryFlags, QueryResponse, SignedAnnounce,
+        ALPN,
+    },
+    query_all, to_infohash, IdMismatch, UdpDiscovery,
 };
-use iroh_net::endpoint;
+use iroh_net::{endpoint, NodeAddr, NodeId};
 use tokio::io::AsyncWriteExt;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 use crate::args::{AnnounceArgs, Args, Commands, QueryArgs};
 
-async fn announce(args: AnnounceArgs) -> anyhow::Result<()> {
-    // todo: uncomment once the connection problems are fixed
+/// The secret key to announce with, from the `ANNOUNCE_SECRET` env var.
+fn announce_secret() -> anyhow::Result<iroh_net::key::SecretKey> {
     let Ok(key) = std::env::var("ANNOUNCE_SECRET") else {
         eprintln!("ANNOUNCE_SECRET environment variable must be set to a valid secret key");
         anyhow::bail!("ANNOUNCE_SECRET env var not set");
@@ -29,97 +44,565 @@ async fn announce(args: AnnounceArgs) -> anyhow::Result<()> {
     let Ok(key) = iroh_net::key::SecretKey::from_str(&key) else {
         anyhow::bail!("ANNOUNCE_SECRET env var is not a valid secret key");
     };
-    let content = args.content.hash_and_format();
-    let bind_addr = SocketAddr::V4(SocketAddrV4::new(
-        Ipv4Addr::UNSPECIFIED,
-        args.udp_port.unwrap_or_default(),
-    ));
+    Ok(key)
+}
+
+async fn announce(args: AnnounceArgs) -> anyhow::Result<()> {
+    // todo: uncomment once the connection problems are fixed
+    let key = announce_secret()?;
+    ensure_distinct_ports(&[
+        ("udp-port", args.udp_port),
+        ("iroh-port", args.iroh_port),
+        ("quic-port", args.quic_port),
+    ])?;
+    let dht_port = if args.also_dht {
+        match args.dht_port.or(args.udp_port) {
+            Some(port) if port != 0 => Some(port),
+            _ => anyhow::bail!("--also-dht requires a non zero --dht-port or --udp-port"),
+        }
+    } else {
+        None
+    };
     let kind = if args.partial {
         AnnounceKind::Partial
     } else {
         AnnounceKind::Complete
     };
+    let mut contents = Vec::new();
+    if let Some(content) = &args.content {
+        let hosts = content.hosts();
+        if !hosts.is_empty() && !hosts.iter().any(|addr| addr.node_id == key.public()) {
+            eprintln!(
+                "warning: the ticket is for {}, but announcing as {}",
+                content.host().expect("hosts is not empty"),
+                key.public()
+            );
+        }
+        let mime_type = match (&args.mime_type, &args.file) {
+            (Some(mime_type), _) => Some(mime_type.clone()),
+            (None, Some(file)) => infer_mime_type(file)?,
+            (None, None) => None,
+        };
+        contents.push((content.hash_and_format(), mime_type));
+    }
+    let (hashed, hash_failures) =
+        hash_paths(&args.path, args.parallel, args.mime_type.as_deref()).await;
+    contents.extend(hashed);
+    anyhow::ensure!(!contents.is_empty(), "no content to announce");
     let timestamp = AbsoluteTime::now();
-    let announce = Announce {
-        host: key.public(),
-        kind,
-        content,
-        timestamp,
+    let announces = contents
+        .into_iter()
+        .map(|(content, mime_type)| Announce {
+            host: key.public(),
+            kind,
+            content,
+            timestamp,
+            metadata: AnnounceMetadata { mime_type },
+            namespace: args.namespace.as_deref().map(Namespace::from_name),
+            class: args.class,
+            committed_until: args
+                .commit_for
+                .map(|secs| timestamp + Duration::from_secs(secs)),
+        })
+        .collect();
+    // several paths might have the same content
+    let announces = dedup_announces(announces)?;
+    let mut signed_announces = Vec::with_capacity(announces.len());
+    for announce in announces {
+        if let Some(mime_type) = &announce.metadata.mime_type {
+            println!("announcing {} as {}", announce.content, mime_type);
+        }
+        signed_announces.push(SignedAnnounce::new(announce, &key)?);
+    }
+    // announce to the trackers and the DHT independently, so a failure in one
+    // does not prevent the other.
+    let trackers_res = announce_trackers(&args, &signed_announces).await;
+    if let Err(cause) = &trackers_res {
+        println!("error announcing to trackers: {}", cause);
+    }
+    let dht_res = match dht_port {
+        Some(port) => {
+            let content = signed_announces.iter().map(|sa| sa.content).collect();
+            announce_to_dht(content, port, args.parallel).await
+        }
+        None => Ok(()),
     };
-    let signed_announce = SignedAnnounce::new(announce, &key)?;
+    if let Err(cause) = &dht_res {
+        println!("error announcing to dht: {}", cause);
+    }
+    trackers_res?;
+    dht_res?;
+    anyhow::ensure!(
+        hash_failures == 0,
+        "failed to hash {} of {} paths",
+        hash_failures,
+        args.path.len()
+    );
+
+    println!("done");
+    Ok(())
+}
+
+/// Hash local files as raw blobs, with at most `parallel` files at a time.
+///
+/// Progress is printed per file. A file that can not be read is reported and
+/// skipped, so one bad file does not abort the batch. Returns the hashed content
+/// with its MIME type, and the number of files that failed.
+async fn hash_paths(
+    paths: &[PathBuf],
+    parallel: usize,
+    mime_type: Option<&str>,
+) -> (Vec<(HashAndFormat, Option<String>)>, usize) {
+    let total = paths.len();
+    let mut results = futures::stream::iter(paths.iter().cloned())
+        .map(|path| async move {
+            let res = tokio::task::spawn_blocking({
+                let path = path.clone();
+                move || hash_file(&path)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|res| res);
+            (path, res)
+        })
+        .buffer_unordered(parallel.max(1));
+    let mut hashed = Vec::with_capacity(total);
+    let mut failed = 0;
+    let mut done = 0;
+    while let Some((path, res)) = results.next().await {
+        done += 1;
+        match res {
+            Ok((hash, inferred)) => {
+                println!("[{}/{}] hashed {}: {}", done, total, path.display(), hash);
+                let mime_type = mime_type.map(ToOwned::to_owned).or(inferred);
+                hashed.push((HashAndFormat::raw(hash), mime_type));
+            }
+            Err(cause) => {
+                println!(
+                    "[{}/{}] error hashing {}: {}",
+                    done,
+                    total,
+                    path.display(),
+                    cause
+                );
+                failed += 1;
+            }
+        }
+    }
+    (hashed, failed)
+}
+
+/// Hash a local file, and infer its MIME type from the same data.
+fn hash_file(path: &Path) -> anyhow::Result<(Hash, Option<String>)> {
+    let data = std::fs::read(path)?;
+    let hash = Hash::new(&data);
+    let mime_type = mime_type_from_magic(&data)
+        .or_else(|| mime_type_from_extension(path))
+        .map(ToOwned::to_owned);
+    Ok((hash, mime_type))
+}
+
+/// Announce to all trackers given in the args.
+///
+/// Each kind of tracker is connected to once, and all announces are sent over
+/// that connection.
+async fn announce_trackers(
+    args: &AnnounceArgs,
+    signed_announces: &[SignedAnnounce],
+) -> anyhow::Result<()> {
     if !args.udp_tracker.is_empty() {
+        let bind_addr = bind_addr("udp-port", args.udp_port)?;
         let discovery = UdpDiscovery::new(bind_addr).await?;
-        for tracker in args.udp_tracker {
-            println!("announcing via udp to {:?}: {}", tracker, content);
-            discovery.add_tracker(tracker).await?;
+        for tracker in &args.udp_tracker {
+            discovery.add_tracker(*tracker).await?;
+        }
+        for signed_announce in signed_announces {
+            println!(
+                "announcing via udp to {:?}: {}",
+                args.udp_tracker, signed_announce.content
+            );
+            discovery.announce_once(signed_announce.clone()).await?;
         }
-        discovery.announce_once(signed_announce).await?;
     }
     if !args.magicsock_tracker.is_empty() {
         let iroh_endpoint = endpoint::Endpoint::builder()
-            .bind(args.iroh_port.unwrap_or_default())
+            .bind(bind_addr("iroh-port", args.iroh_port)?.port())
             .await?;
-        for tracker in args.magicsock_tracker {
-            println!("announcing via magicsock to {:?}: {}", tracker, content);
+        for tracker in &args.magicsock_tracker {
             let connection = iroh_endpoint
-                .connect_by_node_id(&tracker, iroh_mainline_content_discovery::protocol::ALPN)
+                .connect_by_node_id(tracker, tracker_alpn(args.alpn.as_deref())?)
                 .await?;
-            iroh_mainline_content_discovery::announce(connection, signed_announce).await?;
+            let label = format!("magicsock to {}", tracker);
+            announce_tracker(connection, signed_announces, args.atomic, &label).await?;
         }
     }
     if !args.quic_tracker.is_empty() {
-        let bind_addr = SocketAddr::V4(SocketAddrV4::new(
-            Ipv4Addr::UNSPECIFIED,
-            args.quic_port.unwrap_or_default(),
-        ));
+        let bind_addr = bind_addr("quic-port", args.quic_port)?;
         let quinn_endpoint = create_quinn_client(
             bind_addr,
-            vec![iroh_mainline_content_discovery::protocol::ALPN.to_vec()],
+            vec![tracker_alpn(args.alpn.as_deref())?.to_vec()],
             false,
         )?;
-        for tracker in args.quic_tracker {
-            println!("announcing via quic to {:?}: {}", tracker, content);
-            let connection = quinn_endpoint.connect(tracker, "localhost")?.await?;
-            iroh_mainline_content_discovery::announce(connection, signed_announce).await?;
+        for tracker in &args.quic_tracker {
+            let connection = quinn_endpoint.connect(tracker.addr, "localhost")?.await?;
+            if let Some(node_id) = &tracker.node_id {
+                check_tracker_id(
+                    &connection,
+                    node_id,
+                    id_mismatch(args.insecure_allow_id_mismatch),
+                )?;
+            }
+            let label = format!("quic to {}", tracker);
+            announce_tracker(connection, signed_announces, args.atomic, &label).await?;
         }
     }
+    Ok(())
+}
 
-    println!("done");
+/// Announce to a single tracker, rendering progress on stderr.
+///
+/// The items are sent in batches, and the tracker reports the result per item. If
+/// `atomic` is set, all items are sent in one request instead, and the tracker
+/// accepts either all of them or none.
+async fn announce_tracker(
+    connection: endpoint::Connection,
+    signed_announces: &[SignedAnnounce],
+    atomic: bool,
+    label: &str,
+) -> anyhow::Result<()> {
+    let total = signed_announces.len();
+    if atomic {
+        println!("announcing {} items atomically via {}", total, label);
+        return announce_atomic(connection, signed_announces.to_vec()).await;
+    }
+    println!("announcing {} items via {}", total, label);
+    // announce in batches, a single stream per batch instead of one per announce
+    let mut results = announce_batch(connection, signed_announces.to_vec()).boxed();
+    let mut accepted = 0;
+    let mut failed = 0;
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(result) if result.accepted => accepted += 1,
+            Ok(result) => {
+                failed += 1;
+                eprintln!("\rtracker rejected announce for {}", result.content);
+            }
+            Err(cause) => {
+                eprintln!("\rerror announcing via {}: {}", label, cause);
+                break;
+            }
+        }
+        eprint!("\r[{}/{}] announced, {} failed", accepted, total, failed);
+    }
+    eprintln!();
+    let failed = total - accepted;
+    anyhow::ensure!(
+        failed == 0,
+        "{} of {} announces via {} failed",
+        failed,
+        total,
+        label
+    );
     Ok(())
 }
 
-async fn query(args: QueryArgs) -> anyhow::Result<()> {
-    let bind_addr = SocketAddr::V4(SocketAddrV4::new(
-        Ipv4Addr::UNSPECIFIED,
-        args.udp_port.unwrap_or_default(),
-    ));
-    let discovery = iroh_mainline_content_discovery::UdpDiscovery::new(bind_addr).await?;
-    for tracker in args.tracker {
-        discovery.add_tracker(tracker).await?;
+async fn announce_to_dht(
+    content: BTreeSet<HashAndFormat>,
+    port: u16,
+    parallelism: usize,
+) -> anyhow::Result<()> {
+    let dht = mainline::Dht::default();
+    for content in &content {
+        println!(
+            "announcing to dht as infohash {}: {}",
+            to_infohash(*content),
+            content
+        );
     }
+    let results = announce_dht(dht, content, port, parallelism.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let mut failed = false;
+    for (content, res) in results {
+        match res {
+            Ok(sqm) => println!(
+                "announced {} on {} dht nodes",
+                content,
+                sqm.stored_at().len()
+            ),
+            Err(cause) => {
+                println!("error announcing {} to dht: {}", content, cause);
+                failed = true;
+            }
+        }
+    }
+    anyhow::ensure!(!failed, "dht announce failed");
+    Ok(())
+}
+
+async fn query(args: QueryArgs) -> anyhow::Result<()> {
+    ensure_distinct_ports(&[("udp-port", args.udp_port), ("quic-port", args.quic_port)])?;
     let q = Query {
         content: args.content.hash_and_format(),
         flags: QueryFlags {
             complete: !args.partial,
             verified: args.verified,
+            sample: args.sample,
+            include_expired: args.include_expired.map(Duration::from_secs),
+            min_class: args.min_class,
+            require_method: args.require_method,
+            page_size: args.page_size,
+            prefer_committed: args.prefer_committed,
+            detail: args.detail,
+            include_proofs: args.include_proofs,
         },
+        namespace: args.namespace.as_deref().map(Namespace::from_name),
+        cursor: None,
     };
-    let res = discovery.query(q).await?;
-    for sa in res {
-        if sa.verify().is_ok() {
-            println!("{}: {:?}", sa.announce.host, sa.announce.kind);
-        } else {
-            println!("invalid announce");
+    // hosts for which we already printed a node addr
+    let mut printed = BTreeSet::new();
+    // hosts for the download script, with their addrs if known
+    let mut script_hosts = BTreeMap::new();
+    if !args.quic_tracker.is_empty() {
+        let bind_addr = bind_addr("quic-port", args.quic_port)?;
+        let quinn_endpoint = create_quinn_client(
+            bind_addr,
+            vec![tracker_alpn(args.alpn.as_deref())?.to_vec()],
+            false,
+        )?;
+        for tracker in &args.quic_tracker {
+            let connection = quinn_endpoint.connect(tracker.addr, "localhost")?.await?;
+            if let Some(node_id) = &tracker.node_id {
+                check_tracker_id(
+                    &connection,
+                    node_id,
+                    id_mismatch(args.insecure_allow_id_mismatch),
+                )?;
+            }
+            let response = iroh_mainline_content_discovery::query(connection, q).await?;
+            if let Some(size) = response.content_size {
+                eprintln!("content size from {}: {}", tracker, size);
+            }
+            for (host, proof) in &response.proofs {
+                match iroh_mainline_content_discovery::verify_proof(q.content.hash, proof) {
+                    Ok(()) => eprintln!("{} has a valid proof for chunk {}", host, proof.chunk),
+                    Err(cause) => eprintln!("{} has an invalid proof: {}", host, cause),
+                }
+            }
+            match response.empty_reason {
+                Some(reason) if response.hosts.is_empty() && response.host_ids.is_empty() => {
+                    eprintln!("no hosts from {}: {}", tracker, reason)
+                }
+                _ if args.emit_script => {
+                    add_script_hosts(&response, &mut script_hosts);
+                }
+                _ if !response.host_ids.is_empty() => {
+                    response.host_ids.iter().for_each(|id| println!("{}", id))
+                }
+                _ if args.node_addrs => {
+                    for sa in &response.hosts {
+                        print_node_addr(sa, &response.node_addrs, &mut printed)?;
+                    }
+                }
+                _ if args.by_relay => print_by_relay(response.clone()),
+                _ => response.hosts.iter().for_each(print_announce),
+            }
+            for host in &response.stale {
+                eprintln!("{} from {} is stale", host, tracker);
+            }
+        }
+    }
+    if !args.tracker.is_empty() {
+        let bind_addr = bind_addr("udp-port", args.udp_port)?;
+        let discovery = iroh_mainline_content_discovery::UdpDiscovery::new(bind_addr).await?;
+        for tracker in args.tracker {
+            discovery.add_tracker(tracker).await?;
         }
+        let res = discovery.query(q).await?;
+        for sa in res {
+            let sa = sa?;
+            if args.emit_script {
+                if sa.verify().is_ok() {
+                    // udp responses carry no addrs
+                    script_hosts
+                        .entry(sa.host)
+                        .or_insert_with(|| NodeAddr::new(sa.host));
+                }
+            } else if args.node_addrs {
+                // udp responses carry no addrs
+                print_node_addr(&sa, &[], &mut printed)?;
+            } else {
+                print_announce(&sa);
+            }
+        }
+    }
+    if args.emit_script {
+        print_script(q.content, script_hosts.into_values())?;
     }
     Ok(())
 }
 
-async fn query_dht(args: QueryDhtArgs) -> anyhow::Result<()> {
-    let bind_addr = SocketAddr::V4(SocketAddrV4::new(
+/// Add the validly announced hosts of a response to the hosts for the download script.
+fn add_script_hosts(response: &QueryResponse, hosts: &mut BTreeMap<NodeId, NodeAddr>) {
+    let announced = response
+        .hosts
+        .iter()
+        .filter(|sa| sa.verify().is_ok())
+        .map(|sa| sa.host);
+    for host in announced.chain(response.host_ids.iter().copied()) {
+        let addr = response
+            .node_addrs
+            .iter()
+            .find(|addr| addr.node_id == host)
+            .cloned()
+            .unwrap_or_else(|| NodeAddr::new(host));
+        // prefer an addr with more info, in case multiple trackers know the host
+        let entry = hosts.entry(host).or_insert_with(|| addr.clone());
+        if entry.info.relay_url.is_none() && entry.info.direct_addresses.is_empty() {
+            *entry = addr;
+        }
+    }
+}
+
+/// Print a shell script that tries to download the content from each host in turn.
+fn print_script(
+    content: HashAndFormat,
+    hosts: impl IntoIterator<Item = NodeAddr>,
+) -> anyhow::Result<()> {
+    println!("#!/bin/sh");
+    println!(
+        "# download {}, trying each host until one succeeds",
+        content
+    );
+    for addr in hosts {
+        let ticket = BlobTicket::new(addr, content.hash, content.format)?;
+        println!(
+            "iroh blobs get {} && exit 0",
+            shell_quote(&ticket.to_string())
+        );
+    }
+    println!("echo 'no host could provide the content' >&2");
+    println!("exit 1");
+    Ok(())
+}
+
+/// Quote a string for a POSIX shell.
+///
+/// The string is put in single quotes, which keep everything literal, except for
+/// single quotes themselves.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Print the node addr of a host as a line of JSON, once per host.
+///
+/// If the tracker did not provide addrs for the host, just the node id is printed.
+fn print_node_addr(
+    sa: &SignedAnnounce,
+    node_addrs: &[NodeAddr],
+    printed: &mut BTreeSet<NodeId>,
+) -> anyhow::Result<()> {
+    if let Err(cause) = sa.verify() {
+        eprintln!("invalid announce: {}", cause);
+        return Ok(());
+    }
+    if !printed.insert(sa.host) {
+        return Ok(());
+    }
+    let addr = node_addrs
+        .iter()
+        .find(|addr| addr.node_id == sa.host)
+        .cloned()
+        .unwrap_or_else(|| NodeAddr::new(sa.host));
+    println!("{}", serde_json::to_string(&addr)?);
+    Ok(())
+}
+
+/// Print the hosts of a response grouped by relay.
+fn print_by_relay(response: QueryResponse) {
+    let grouped = response.into_sources_by_relay();
+    let print = |label: &str, sources: &[DownloadSource]| {
+        println!("{}:", label);
+        for source in sources {
+            println!("  {}: {:?}", source.node_addr.node_id, source.kind);
+        }
+    };
+    for (relay_url, sources) in &grouped.relays {
+        print(&format!("via {}", relay_url), sources);
+    }
+    if !grouped.direct.is_empty() {
+        print("direct", &grouped.direct);
+    }
+    if !grouped.unknown.is_empty() {
+        print("no addrs", &grouped.unknown);
+    }
+}
+
+/// The address to bind to for a port option.
+///
+/// No port means any free port. A given port is checked up front, so that a port
+/// that is in use gives an error naming the option instead of an opaque bind error.
+fn bind_addr(option: &str, port: Option<u16>) -> anyhow::Result<SocketAddr> {
+    let addr = SocketAddr::V4(SocketAddrV4::new(
         Ipv4Addr::UNSPECIFIED,
-        args.udp_port.unwrap_or_default(),
+        port.unwrap_or_default(),
     ));
+    if addr.port() != 0 {
+        std::net::UdpSocket::bind(addr)
+            .with_context(|| format!("--{} {} is not available", option, addr.port()))?;
+    }
+    Ok(addr)
+}
+
+/// Check that no two port options were given the same port.
+fn ensure_distinct_ports(ports: &[(&str, Option<u16>)]) -> anyhow::Result<()> {
+    let mut seen = BTreeMap::new();
+    for (option, port) in ports {
+        let Some(port) = port.filter(|port| *port != 0) else {
+            continue;
+        };
+        if let Some(other) = seen.insert(port, option) {
+            anyhow::bail!(
+                "--{} and --{} can not both use port {}",
+                other,
+                option,
+                port
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The ALPN to connect to trackers with, the public one unless given.
+fn tracker_alpn(alpn: Option<&str>) -> anyhow::Result<&[u8]> {
+    let alpn = alpn.map(str::as_bytes).unwrap_or(ALPN);
+    validate_alpn(alpn)?;
+    Ok(alpn)
+}
+
+fn id_mismatch(insecure_allow_id_mismatch: bool) -> IdMismatch {
+    if insecure_allow_id_mismatch {
+        IdMismatch::InsecureWarn
+    } else {
+        IdMismatch::Fail
+    }
+}
+
+fn print_announce(sa: &SignedAnnounce) {
+    if sa.verify().is_ok() {
+        match &sa.announce.metadata.mime_type {
+            Some(mime_type) => {
+                println!("{}: {:?} {}", sa.announce.host, sa.announce.kind, mime_type)
+            }
+            None => println!("{}: {:?}", sa.announce.host, sa.announce.kind),
+        }
+    } else {
+        println!("invalid announce");
+    }
+}
+
+async fn query_dht(args: QueryDhtArgs) -> anyhow::Result<()> {
+    let bind_addr = bind_addr("udp-port", args.udp_port)?;
     let discovery = UdpDiscovery::new(bind_addr).await?;
     let dht = mainline::Dht::default();
     let q = Query {
@@ -127,7 +610,17 @@ async fn query_dht(args: QueryDhtArgs) -> anyhow::Result<()> {
         flags: QueryFlags {
             complete: !args.partial,
             verified: args.verified,
+            sample: args.sample,
+            include_expired: None,
+            min_class: None,
+            require_method: None,
+            page_size: None,
+            prefer_committed: false,
+            detail: DetailLevel::default(),
+            include_proofs: false,
         },
+        namespace: args.namespace.as_deref().map(Namespace::from_name),
+        cursor: None,
     };
     println!("content corresponds to infohash {}", to_infohash(q.content));
 
@@ -142,6 +635,270 @@ async fn query_dht(args: QueryDhtArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn query_all_sources(args: QueryAllArgs) -> anyhow::Result<()> {
+    ensure_distinct_ports(&[("iroh-port", args.iroh_port), ("quic-port", args.quic_port)])?;
+    let q = Query {
+        content: args.content.hash_and_format(),
+        flags: QueryFlags {
+            complete: !args.partial,
+            verified: args.verified,
+            sample: args.sample,
+            include_expired: None,
+            min_class: None,
+            require_method: None,
+            page_size: None,
+            prefer_committed: false,
+            detail: DetailLevel::default(),
+            include_proofs: false,
+        },
+        namespace: args.namespace.as_deref().map(Namespace::from_name),
+        cursor: None,
+    };
+    let trackers = if args.dht_only {
+        Vec::new()
+    } else {
+        args.magicsock_tracker
+    };
+    let dht = if args.tracker_only {
+        None
+    } else {
+        let bind_addr = bind_addr("quic-port", args.quic_port)?;
+        let quinn_endpoint = create_quinn_client(
+            bind_addr,
+            vec![tracker_alpn(args.alpn.as_deref())?.to_vec()],
+            false,
+        )?;
+        Some((quinn_endpoint, mainline::Dht::default()))
+    };
+    let iroh_endpoint = endpoint::Endpoint::builder()
+        .bind(bind_addr("iroh-port", args.iroh_port)?.port())
+        .await?;
+    let res = query_all(
+        iroh_endpoint,
+        trackers,
+        tracker_alpn(args.alpn.as_deref())?,
+        dht,
+        q,
+        args.query_parallelism.unwrap_or(4),
+        args.max_dials,
+    )
+    .await;
+    for host in res.hosts.values() {
+        let sources = host
+            .sources
+            .iter()
+            .map(|source| source.to_string())
+            .collect::<Vec<_>>();
+        println!(
+            "{}: {:?} via {}",
+            host.announce.host,
+            host.announce.kind,
+            sources.join(", ")
+        );
+    }
+    for (source, cause) in &res.unreachable {
+        eprintln!("could not query {}: {}", source, cause);
+    }
+    if res.dials_exhausted {
+        eprintln!(
+            "stopped after {} dials",
+            res.reachable.len() + res.unreachable.len()
+        );
+    }
+    if res.dht_consulted && !res.dht_reachable() {
+        eprintln!("no tracker found via the dht could be queried");
+    }
+    if let (true, Some(stats)) = (args.stats, &res.dht_stats) {
+        println!("dht: {}", stats);
+    }
+    Ok(())
+}
+
+/// Download content from discovered hosts, then announce as a complete host.
+///
+/// Hosts from the ticket are tried first, then the hosts found via the trackers.
+/// The content is only announced after every blob was downloaded and its hash
+/// checked, and only to the trackers that were queried.
+async fn mirror(args: MirrorArgs) -> anyhow::Result<()> {
+    let key = announce_secret()?;
+    ensure_distinct_ports(&[("iroh-port", args.iroh_port), ("quic-port", args.quic_port)])?;
+    let content = args.content.hash_and_format();
+    let namespace = args.namespace.as_deref().map(Namespace::from_name);
+    let q = Query {
+        content,
+        flags: QueryFlags {
+            complete: true,
+            verified: args.verified,
+            sample: None,
+            include_expired: None,
+            min_class: None,
+            require_method: None,
+            page_size: None,
+            prefer_committed: false,
+            detail: DetailLevel::default(),
+            include_proofs: false,
+        },
+        namespace,
+        cursor: None,
+    };
+    let mut candidates = args.content.hosts();
+    let quic_bind_addr = bind_addr("quic-port", args.quic_port)?;
+    let quinn_endpoint = create_quinn_client(
+        quic_bind_addr,
+        vec![tracker_alpn(args.alpn.as_deref())?.to_vec()],
+        false,
+    )?;
+    for tracker in &args.quic_tracker {
+        let connection = quinn_endpoint.connect(tracker.addr, "localhost")?.await?;
+        if let Some(node_id) = &tracker.node_id {
+            check_tracker_id(
+                &connection,
+                node_id,
+                id_mismatch(args.insecure_allow_id_mismatch),
+            )?;
+        }
+        let response = iroh_mainline_content_discovery::query(connection.clone(), q).await?;
+        connection.close(0u32.into(), b"query done");
+        for sa in &response.hosts {
+            if sa.verify().is_err() || (args.verified && !response.verified.contains(&sa.host)) {
+                continue;
+            }
+            let addr = response
+                .node_addrs
+                .iter()
+                .find(|addr| addr.node_id == sa.host)
+                .cloned()
+                .unwrap_or_else(|| NodeAddr::new(sa.host));
+            candidates.push(addr);
+        }
+    }
+    // we can not download from ourselves
+    let mut seen = BTreeSet::from([key.public()]);
+    candidates.retain(|addr| seen.insert(addr.node_id));
+    anyhow::ensure!(!candidates.is_empty(), "no hosts found for {}", content);
+    let iroh_endpoint = endpoint::Endpoint::builder()
+        .bind(bind_addr("iroh-port", args.iroh_port)?.port())
+        .await?;
+    let mut mirrored = false;
+    for addr in candidates {
+        let host = addr.node_id;
+        println!("downloading {} from {}", content, host);
+        match download_verified(&iroh_endpoint, addr, content).await {
+            Ok(blobs) => {
+                store_blobs(&args.store, &blobs).await?;
+                println!(
+                    "mirrored {} blobs of {} to {}",
+                    blobs.len(),
+                    content,
+                    args.store.display()
+                );
+                mirrored = true;
+                break;
+            }
+            Err(cause) => eprintln!("error downloading {} from {}: {}", content, host, cause),
+        }
+    }
+    anyhow::ensure!(mirrored, "could not download {} from any host", content);
+    let timestamp = AbsoluteTime::now();
+    let announce = Announce {
+        host: key.public(),
+        kind: AnnounceKind::Complete,
+        content,
+        timestamp,
+        metadata: AnnounceMetadata::default(),
+        namespace,
+        class: args.class,
+        committed_until: args
+            .commit_for
+            .map(|secs| timestamp + Duration::from_secs(secs)),
+    };
+    let signed_announce = SignedAnnounce::new(announce, &key)?;
+    for tracker in &args.quic_tracker {
+        // the download may have taken a while, so connect again instead of reusing
+        // the connection of the query, which may have timed out
+        let connection = quinn_endpoint.connect(tracker.addr, "localhost")?.await?;
+        if let Some(node_id) = &tracker.node_id {
+            check_tracker_id(
+                &connection,
+                node_id,
+                id_mismatch(args.insecure_allow_id_mismatch),
+            )?;
+        }
+        let label = format!("quic to {}", tracker);
+        announce_tracker(
+            connection,
+            std::slice::from_ref(&signed_announce),
+            false,
+            &label,
+        )
+        .await?;
+    }
+    println!(
+        "serve {} as {} to make the announce true",
+        args.store.display(),
+        key.public()
+    );
+    Ok(())
+}
+
+/// Download all blobs of the content from a host, and check their hashes.
+///
+/// The blobs are kept in memory, so nothing is written unless the whole content
+/// was received and verified.
+async fn download_verified(
+    endpoint: &endpoint::Endpoint,
+    addr: NodeAddr,
+    content: HashAndFormat,
+) -> anyhow::Result<Vec<(Hash, Vec<u8>)>> {
+    let connection = endpoint.connect(addr, iroh_blobs::protocol::ALPN).await?;
+    let request = match content.format {
+        BlobFormat::Raw => GetRequest::single(content.hash),
+        BlobFormat::HashSeq => GetRequest::all(content.hash),
+    };
+    let at_connected = iroh_blobs::get::fsm::start(connection, request)
+        .next()
+        .await?;
+    let ConnectedNext::StartRoot(start) = at_connected.next().await? else {
+        anyhow::bail!("host did not send the root");
+    };
+    let (at_content, _size) = start.next().next().await?;
+    let (mut at_end, root) = at_content.concatenate_into_vec().await?;
+    let children = match content.format {
+        BlobFormat::Raw => Vec::new(),
+        BlobFormat::HashSeq => HashSeq::try_from(bytes::Bytes::from(root.clone()))?
+            .iter()
+            .collect(),
+    };
+    let mut blobs = vec![(content.hash, root)];
+    for hash in children {
+        let EndBlobNext::MoreChildren(more) = at_end.next() else {
+            anyhow::bail!("host closed before sending {}", hash);
+        };
+        let (at_content, _size) = more.next(hash).next().await?;
+        let (next, data) = at_content.concatenate_into_vec().await?;
+        blobs.push((hash, data));
+        at_end = next;
+    }
+    match at_end.next() {
+        EndBlobNext::MoreChildren(more) => more.finish().next().await?,
+        EndBlobNext::Closing(closing) => closing.next().await?,
+    };
+    // the transfer is verified as it streams, but be explicit about what we announce
+    for (hash, data) in &blobs {
+        anyhow::ensure!(Hash::new(data) == *hash, "hash mismatch for {}", hash);
+    }
+    Ok(blobs)
+}
+
+/// Write blobs to a directory, one file per blob named by its hash.
+async fn store_blobs(dir: &Path, blobs: &[(Hash, Vec<u8>)]) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    for (hash, data) in blobs {
+        tokio::fs::write(dir.join(hash.to_hex()), data).await?;
+    }
+    Ok(())
+}
+
 // set the RUST_LOG env var to one of {debug,info,warn} to see logging info
 pub fn setup_logging() {
     tracing_subscriber::registry()
@@ -159,6 +916,8 @@ async fn main() -> anyhow::Result<()> {
         Commands::Announce(args) => announce(args).await,
         Commands::Query(args) => query(args).await,
         Commands::QueryDht(args) => query_dht(args).await,
+        Commands::QueryAll(args) => query_all_sources(args).await,
+        Commands::Mirror(args) => mirror(args).await,
     }
 }
 
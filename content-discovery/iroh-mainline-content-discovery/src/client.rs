@@ -1,11 +1,15 @@
 use genawaiter::sync::Gen;
 use std::{
-    collections::{BTreeMap, BTreeSet, HashSet},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashSet},
+    future::Future,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use futures::{
@@ -14,14 +18,47 @@ use futures::{
     stream::FusedStream,
     FutureExt, Stream, StreamExt,
 };
-use iroh_blobs::HashAndFormat;
-use iroh_net::{Endpoint, NodeId};
+use iroh_blobs::{hashseq::HashSeq, Hash, HashAndFormat};
+use iroh_net::{Endpoint, NodeAddr, NodeId};
 use iroh_pkarr_node_discovery::PkarrNodeDiscovery;
 
-use crate::protocol::{
-    AnnounceKind, Query, QueryResponse, Request, Response, SignedAnnounce, ALPN, REQUEST_SIZE_LIMIT,
+use crate::{
+    codec::{Codec, Postcard},
+    protocol::{
+        AnnounceKind, AnnounceSet, AnnounceSetResult, BatchQueryItem, DetailLevel, ErrorResponse,
+        ItemResult, Namespace, Query, QueryBatch, QueryFlags, QueryResponse, RefreshSummary,
+        Request, Response, SignedAnnounce, SignedAnnounceToken, SignedQuery, SignedRefresh,
+        SignedUnannounce, TooManyItems, VerificationProof, RESPONSE_SIZE_LIMIT,
+    },
 };
 
+/// Send a request to a tracker on a new bi stream, and read the response.
+async fn send_request(
+    connection: &iroh_net::endpoint::Connection,
+    request: &Request,
+    codec: &impl Codec,
+) -> anyhow::Result<Vec<u8>> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    tracing::debug!("opened bi stream");
+    let request = codec.encode(request)?;
+    send.write_all(&request).await?;
+    send.finish().await?;
+    let response = recv.read_to_end(RESPONSE_SIZE_LIMIT).await?;
+    Ok(response)
+}
+
+/// The error for a response of the wrong type.
+///
+/// Errors reported by the tracker are returned as their own types, so callers can
+/// check for them, e.g. for [crate::protocol::UnsupportedRequest] to fall back to an older request.
+fn unexpected_response(response: Response) -> anyhow::Error {
+    match response {
+        Response::Error(ErrorResponse::UnsupportedRequest(unsupported)) => unsupported.into(),
+        Response::Error(ErrorResponse::TooManyItems(too_many)) => too_many.into(),
+        other => anyhow::anyhow!("unexpected response: {:?}", other),
+    }
+}
+
 /// Announce to a tracker.
 ///
 /// You can only announce content you yourself claim to have, to avoid spamming other nodes.
@@ -34,181 +71,1626 @@ pub async fn announce(
     connection: iroh_net::endpoint::Connection,
     signed_announce: SignedAnnounce,
 ) -> anyhow::Result<()> {
-    let (mut send, mut recv) = connection.open_bi().await?;
-    tracing::debug!("opened bi stream");
-    let request = Request::Announce(signed_announce);
-    let request = postcard::to_stdvec(&request)?;
+    announce_with_codec(connection, signed_announce, &Postcard).await
+}
+
+/// Announce to a tracker, using the given codec.
+///
+/// See [announce].
+pub async fn announce_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    signed_announce: SignedAnnounce,
+    codec: &impl Codec,
+) -> anyhow::Result<()> {
     tracing::debug!("sending announce");
+    let request = Request::Announce(signed_announce);
+    let _response = send_request(&connection, &request, codec).await?;
+    Ok(())
+}
+
+/// Announce many announces to a tracker over one connection.
+///
+/// Each announce is a separate request, and at most `max_in_flight` requests are
+/// outstanding at any time. Announces are only taken from `announces` when a previous
+/// request has been acknowledged, so a slow tracker throttles the client instead of
+/// the whole batch being buffered. Pass a lazy iterator to also bound the memory
+/// for creating the announces.
+///
+/// Stops at the first failed announce. Returns the number of announces sent.
+pub async fn announce_all(
+    connection: iroh_net::endpoint::Connection,
+    announces: impl IntoIterator<Item = SignedAnnounce>,
+    max_in_flight: usize,
+) -> anyhow::Result<usize> {
+    send_bounded(announces, max_in_flight, |signed_announce| {
+        announce(connection.clone(), signed_announce)
+    })
+    .await
+}
+
+/// The maximum number of announces per request in [announce_batch].
+///
+/// This keeps batches of announces with small metadata within
+/// [crate::protocol::REQUEST_SIZE_LIMIT].
+pub const BATCH_ANNOUNCE_SIZE: usize = 32;
+
+/// Announce many announces to a tracker, and get the result per announce as soon as it
+/// is processed.
+///
+/// The announces are sent in batches of [BATCH_ANNOUNCE_SIZE], one batch after the
+/// other. The stream yields an [ItemResult] for each announce in order, so a caller
+/// can react to rejections before the whole batch is done. The stream ends after the
+/// last result, or after the first error. That is a [TooManyItems] error for trackers
+/// that accept fewer than [BATCH_ANNOUNCE_SIZE] items per request.
+pub fn announce_batch(
+    connection: iroh_net::endpoint::Connection,
+    announces: impl IntoIterator<Item = SignedAnnounce>,
+) -> impl Stream<Item = anyhow::Result<ItemResult>> {
+    announce_batch_with_codec(connection, announces.into_iter().collect(), &Postcard)
+}
+
+/// Announce many announces to a tracker in batches, using the given codec.
+///
+/// See [announce_batch].
+pub fn announce_batch_with_codec<'a>(
+    connection: iroh_net::endpoint::Connection,
+    announces: Vec<SignedAnnounce>,
+    codec: &'a impl Codec,
+) -> impl Stream<Item = anyhow::Result<ItemResult>> + 'a {
+    let mut announces = announces.into_iter();
+    Gen::new(move |co| async move {
+        loop {
+            let batch = announces
+                .by_ref()
+                .take(BATCH_ANNOUNCE_SIZE)
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                return;
+            }
+            let expected = batch.len();
+            let request = Request::BatchAnnounce(batch);
+            let mut recv = match open_stream(&connection, &request, codec).await {
+                Ok(recv) => recv,
+                Err(cause) => {
+                    co.yield_(Err(cause)).await;
+                    return;
+                }
+            };
+            for _ in 0..expected {
+                let response = match read_frame(&mut recv).await {
+                    Ok(Some(frame)) => codec.decode::<Response>(&frame),
+                    Ok(None) => Err(anyhow::anyhow!("tracker did not acknowledge all announces")),
+                    Err(cause) => Err(cause),
+                };
+                match response {
+                    Ok(Response::ItemResult(result)) => co.yield_(Ok(result)).await,
+                    Ok(other) => {
+                        co.yield_(Err(unexpected_response(other))).await;
+                        return;
+                    }
+                    Err(cause) => {
+                        co.yield_(Err(cause)).await;
+                        return;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Announce a set of announces to a tracker, all or nothing.
+///
+/// The tracker either stores all announces, or none of them. If one of them violates
+/// a constraint, this fails with an
+/// [AnnounceRejection](crate::protocol::AnnounceRejection) naming the first one. Unlike
+/// [announce_batch], the set is sent in one request, so it must fit in
+/// [crate::protocol::REQUEST_SIZE_LIMIT], and fails with [TooManyItems] if it has more
+/// announces than the tracker accepts per request.
+pub async fn announce_atomic(
+    connection: iroh_net::endpoint::Connection,
+    announces: Vec<SignedAnnounce>,
+) -> anyhow::Result<()> {
+    announce_atomic_with_codec(connection, announces, &Postcard).await
+}
+
+/// Announce a set of announces to a tracker, all or nothing, using the given codec.
+///
+/// See [announce_atomic].
+pub async fn announce_atomic_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    announces: Vec<SignedAnnounce>,
+    codec: &impl Codec,
+) -> anyhow::Result<()> {
+    let request = Request::AnnounceSet(AnnounceSet {
+        announces,
+        atomic: true,
+    });
+    let mut recv = open_stream(&connection, &request, codec).await?;
+    let frame = read_frame(&mut recv)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("tracker did not acknowledge the announce set"))?;
+    match codec.decode::<Response>(&frame)? {
+        Response::AnnounceSet(AnnounceSetResult::Accepted) => Ok(()),
+        Response::AnnounceSet(AnnounceSetResult::Rejected(rejection)) => Err(rejection.into()),
+        other => Err(unexpected_response(other)),
+    }
+}
+
+/// Progress of [announce_all_with_progress].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AnnounceProgress {
+    /// The number of announces accepted by the tracker so far.
+    pub accepted: usize,
+    /// The number of announces that failed so far.
+    pub failed: usize,
+}
+
+/// Like [announce_all], but report progress and continue after failed announces.
+///
+/// `on_progress` is called after each announce with its content, its result and the
+/// progress so far. Returns the final progress.
+pub async fn announce_all_with_progress(
+    connection: iroh_net::endpoint::Connection,
+    announces: impl IntoIterator<Item = SignedAnnounce>,
+    max_in_flight: usize,
+    mut on_progress: impl FnMut(HashAndFormat, &anyhow::Result<()>, AnnounceProgress),
+) -> AnnounceProgress {
+    let mut results = futures::stream::iter(announces)
+        .map(|signed_announce| {
+            let connection = connection.clone();
+            let content = signed_announce.content;
+            async move { (content, announce(connection, signed_announce).await) }
+        })
+        .buffer_unordered(max_in_flight.max(1));
+    let mut progress = AnnounceProgress::default();
+    while let Some((content, res)) = results.next().await {
+        if res.is_ok() {
+            progress.accepted += 1;
+        } else {
+            progress.failed += 1;
+        }
+        on_progress(content, &res, progress);
+    }
+    progress
+}
+
+/// Send items with at most `max_in_flight` sends outstanding.
+///
+/// Items are only taken from `items` when there is room.
+async fn send_bounded<T, F, Fut>(
+    items: impl IntoIterator<Item = T>,
+    max_in_flight: usize,
+    send: F,
+) -> anyhow::Result<usize>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut results = futures::stream::iter(items)
+        .map(send)
+        .buffer_unordered(max_in_flight.max(1));
+    let mut count = 0;
+    while let Some(res) = results.next().await {
+        res?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Announce to a tracker on behalf of another host, using a token the host has signed.
+///
+/// This can be used by archival or indexer services to keep content of hosts that are
+/// currently offline discoverable. The tracker will reject expired tokens.
+pub async fn announce_token(
+    connection: iroh_net::endpoint::Connection,
+    token: SignedAnnounceToken,
+) -> anyhow::Result<()> {
+    announce_token_with_codec(connection, token, &Postcard).await
+}
+
+/// Announce to a tracker using a token, using the given codec.
+///
+/// See [announce_token].
+pub async fn announce_token_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    token: SignedAnnounceToken,
+    codec: &impl Codec,
+) -> anyhow::Result<()> {
+    tracing::debug!("sending announce token");
+    let request = Request::AnnounceToken(token);
+    let _response = send_request(&connection, &request, codec).await?;
+    Ok(())
+}
+
+/// Retract all announces of a host from a tracker.
+///
+/// A host that shuts down should call this for every tracker it has announced to,
+/// so the tracker does not hand out a host that is gone.
+pub async fn unannounce(
+    connection: iroh_net::endpoint::Connection,
+    signed_unannounce: SignedUnannounce,
+) -> anyhow::Result<()> {
+    unannounce_with_codec(connection, signed_unannounce, &Postcard).await
+}
+
+/// Retract all announces of a host from a tracker, using the given codec.
+///
+/// See [unannounce].
+pub async fn unannounce_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    signed_unannounce: SignedUnannounce,
+    codec: &impl Codec,
+) -> anyhow::Result<()> {
+    tracing::debug!("sending unannounce");
+    let request = Request::Unannounce(signed_unannounce);
+    let _response = send_request(&connection, &request, codec).await?;
+    Ok(())
+}
+
+/// The mapping from an iroh [HashAndFormat] to a bittorrent infohash, aka [mainline::Id].
+///
+/// Since an infohash is just 20 bytes, this can not be a bidirectional mapping.
+pub fn to_infohash(haf: HashAndFormat) -> mainline::Id {
+    let mut data = [0u8; 20];
+    data.copy_from_slice(&haf.hash.as_bytes()[..20]);
+    mainline::Id::from_bytes(data).unwrap()
+}
+
+/// The distinct trackers in a get_peers response.
+///
+/// `dht_nodes` is updated with the number of distinct DHT nodes that answered so far.
+fn unique_tracker_addrs(
+    mut response: mainline::Response<mainline::GetPeerResponse>,
+    dht_nodes: Arc<AtomicUsize>,
+) -> impl Stream<Item = SocketAddr> {
+    Gen::new(|co| async move {
+        let mut found = HashSet::new();
+        let mut nodes = HashSet::new();
+        while let Some(response) = response.next_async().await {
+            tracing::info!("got get_peers response: {:?}", response);
+            nodes.insert(response.from.id);
+            dht_nodes.store(nodes.len(), Ordering::Relaxed);
+            let tracker = response.peer;
+            if !found.insert(tracker) {
+                continue;
+            }
+            co.yield_(tracker).await;
+        }
+    })
+}
+
+async fn query_socket_one(
+    endpoint: impl QuinnConnectionProvider<SocketAddr>,
+    addr: SocketAddr,
+    args: Query,
+) -> anyhow::Result<Vec<SignedAnnounce>> {
+    let connection = endpoint.connect(addr).await?;
+    let result = query(connection, args).await?;
+    Ok(result.hosts)
+}
+
+async fn query_iroh_one(
+    endpoint: Endpoint,
+    node_id: &NodeId,
+    alpn: &[u8],
+    args: Query,
+) -> anyhow::Result<Vec<SignedAnnounce>> {
+    let connection = endpoint.connect_by_node_id(node_id, alpn).await?;
+    let result = query(connection, args).await?;
+    Ok(result.hosts)
+}
+
+/// A connection provider that can be used to connect to a tracker.
+///
+/// This can either be a [`iroh_quinn::Endpoint`] where connections are created on demand,
+/// or some sort of connection pool.
+pub trait QuinnConnectionProvider<Addr>: Clone {
+    fn connect(&self, addr: Addr) -> BoxFuture<'_, anyhow::Result<iroh_quinn::Connection>>;
+}
+
+impl QuinnConnectionProvider<SocketAddr> for iroh_quinn::Endpoint {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, anyhow::Result<iroh_quinn::Connection>> {
+        async move { Ok(self.connect(addr, "localhost")?.await?) }.boxed()
+    }
+}
+
+/// Query multiple trackers in parallel and merge the results.
+///
+/// The trackers are dialed with `alpn`, which is [crate::protocol::ALPN] unless
+/// they are part of a separate tracker network.
+pub fn query_trackers(
+    endpoint: Endpoint,
+    trackers: impl IntoIterator<Item = NodeId>,
+    alpn: &[u8],
+    args: Query,
+    query_parallelism: usize,
+) -> impl Stream<Item = anyhow::Result<SignedAnnounce>> {
+    let alpn = alpn.to_vec();
+    futures::stream::iter(trackers)
+        .map(move |tracker| {
+            let endpoint = endpoint.clone();
+            let alpn = alpn.clone();
+            async move {
+                let hosts = match query_iroh_one(endpoint, &tracker, &alpn, args).await {
+                    Ok(hosts) => hosts.into_iter().map(anyhow::Ok).collect(),
+                    Err(cause) => vec![Err(cause)],
+                };
+                futures::stream::iter(hosts)
+            }
+        })
+        .buffer_unordered(query_parallelism)
+        .flatten()
+}
+
+/// Query the mainline DHT for trackers for the given content, then query each tracker for peers.
+pub fn query_dht(
+    endpoint: impl QuinnConnectionProvider<SocketAddr>,
+    dht: mainline::dht::Dht,
+    args: Query,
+    query_parallelism: usize,
+) -> impl Stream<Item = anyhow::Result<SignedAnnounce>> {
+    query_dht_with_stats(endpoint, dht, args, query_parallelism, |_| {})
+}
+
+/// How effective a DHT query was, see [query_dht_with_stats].
+#[derive(Debug, Clone, Default)]
+pub struct DhtQueryStats {
+    /// The number of distinct DHT nodes that answered with trackers.
+    ///
+    /// Over many queries, the median of this is a measure of how far the DHT has to
+    /// be walked to find trackers for content.
+    pub dht_nodes: usize,
+    /// The number of trackers found via the DHT and queried.
+    pub trackers: usize,
+    /// The number of trackers that returned at least one host.
+    pub trackers_with_hosts: usize,
+    /// The number of trackers that could not be queried.
+    pub trackers_failed: usize,
+    /// The number of hosts returned, over all trackers.
+    pub hosts: usize,
+    /// The time from starting the query to the first tracker that returned a host.
+    pub time_to_first_host: Option<Duration>,
+    /// The time from starting the query to the last tracker answering.
+    pub elapsed: Duration,
+}
+
+impl DhtQueryStats {
+    /// The fraction of queried trackers that returned at least one host.
+    ///
+    /// This is 0 if no trackers were found.
+    pub fn hit_rate(&self) -> f64 {
+        if self.trackers == 0 {
+            return 0.0;
+        }
+        self.trackers_with_hosts as f64 / self.trackers as f64
+    }
+
+    /// Record the result of querying a tracker.
+    ///
+    /// `hosts` is `None` if the tracker could not be queried.
+    fn record(&mut self, hosts: Option<usize>, elapsed: Duration) {
+        self.trackers += 1;
+        self.elapsed = elapsed;
+        match hosts {
+            Some(0) => {}
+            Some(n) => {
+                self.trackers_with_hosts += 1;
+                self.hosts += n;
+                self.time_to_first_host.get_or_insert(elapsed);
+            }
+            None => self.trackers_failed += 1,
+        }
+    }
+}
+
+impl std::fmt::Display for DhtQueryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} trackers had hosts ({:.0}%), {} failed, {} hosts, {} dht nodes",
+            self.trackers_with_hosts,
+            self.trackers,
+            self.hit_rate() * 100.0,
+            self.trackers_failed,
+            self.hosts,
+            self.dht_nodes,
+        )?;
+        if let Some(first) = self.time_to_first_host {
+            write!(f, ", first host after {:?}", first)?;
+        }
+        write!(f, ", done after {:?}", self.elapsed)
+    }
+}
+
+/// Query the mainline DHT like [query_dht], and report how effective the query was.
+///
+/// `on_stats` is called once all trackers found via the DHT have been queried, when
+/// the stream ends. It is not called if the stream is dropped before.
+pub fn query_dht_with_stats(
+    endpoint: impl QuinnConnectionProvider<SocketAddr>,
+    dht: mainline::dht::Dht,
+    args: Query,
+    query_parallelism: usize,
+    on_stats: impl FnOnce(DhtQueryStats),
+) -> impl Stream<Item = anyhow::Result<SignedAnnounce>> {
+    Gen::new(move |co| async move {
+        let t0 = Instant::now();
+        let dht = dht.as_async();
+        let info_hash = to_infohash(args.content);
+        let response: mainline::Response<mainline::GetPeerResponse> = dht.get_peers(info_hash);
+        let dht_nodes = Arc::new(AtomicUsize::new(0));
+        let mut results = unique_tracker_addrs(response, dht_nodes.clone())
+            .map(move |addr| {
+                let endpoint = endpoint.clone();
+                async move { query_socket_one(endpoint, addr, args).await }
+            })
+            .buffer_unordered(query_parallelism);
+        let mut stats = DhtQueryStats::default();
+        while let Some(res) = results.next().await {
+            match res {
+                Ok(hosts) => {
+                    stats.record(Some(hosts.len()), t0.elapsed());
+                    for host in hosts {
+                        co.yield_(Ok(host)).await;
+                    }
+                }
+                Err(cause) => {
+                    stats.record(None, t0.elapsed());
+                    co.yield_(Err(cause)).await;
+                }
+            }
+        }
+        stats.dht_nodes = dht_nodes.load(Ordering::Relaxed);
+        on_stats(stats);
+    })
+}
+
+/// Announce to the mainline DHT in parallel.
+///
+/// Note that this should only be called from a publicly reachable node, where port is the port
+/// on which the tracker protocol is reachable.
+pub fn announce_dht(
+    dht: mainline::dht::Dht,
+    content: BTreeSet<HashAndFormat>,
+    port: u16,
+    announce_parallelism: usize,
+) -> impl Stream<Item = (HashAndFormat, mainline::Result<mainline::StoreQueryMetdata>)> {
+    let dht = dht.as_async();
+    futures::stream::iter(content)
+        .map(move |content| {
+            let dht = dht.clone();
+            async move {
+                let info_hash = to_infohash(content);
+                let res = dht.announce_peer(info_hash, Some(port)).await;
+                (content, res)
+            }
+        })
+        .buffer_unordered(announce_parallelism)
+}
+
+/// The outcome of [announce_everywhere].
+#[derive(Debug)]
+pub struct AnnounceEverywhereResult {
+    /// The result of announcing to each tracker.
+    pub trackers: Vec<(NodeId, anyhow::Result<()>)>,
+    /// The result of announcing to the DHT.
+    pub dht: Vec<(HashAndFormat, mainline::Result<mainline::StoreQueryMetdata>)>,
+}
+
+/// Announce to a number of trackers and to the mainline DHT at the same time.
+///
+/// A failure to announce to one tracker or to the DHT does not abort the other announces,
+/// the individual results are reported in the returned [AnnounceEverywhereResult].
+///
+/// See [announce_dht] for the meaning of `port`. The trackers are dialed with `alpn`,
+/// which is [crate::protocol::ALPN] unless they are part of a separate tracker
+/// network.
+pub async fn announce_everywhere(
+    endpoint: Endpoint,
+    trackers: impl IntoIterator<Item = NodeId>,
+    alpn: &[u8],
+    dht: mainline::dht::Dht,
+    signed_announce: SignedAnnounce,
+    port: u16,
+    announce_parallelism: usize,
+) -> AnnounceEverywhereResult {
+    let trackers = futures::stream::iter(trackers)
+        .map(|tracker| {
+            let endpoint = endpoint.clone();
+            let signed_announce = signed_announce.clone();
+            async move {
+                let res = async {
+                    let connection = endpoint.connect_by_node_id(&tracker, alpn).await?;
+                    announce(connection, signed_announce).await
+                }
+                .await;
+                (tracker, res)
+            }
+        })
+        .buffer_unordered(announce_parallelism)
+        .collect::<Vec<_>>();
+    let content = BTreeSet::from([signed_announce.content]);
+    let dht = announce_dht(dht, content, port, announce_parallelism).collect::<Vec<_>>();
+    let (trackers, dht) = future::join(trackers, dht).await;
+    AnnounceEverywhereResult { trackers, dht }
+}
+
+/// A source of hosts for [query_all].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum QuerySource {
+    /// A tracker that was given by node id.
+    Tracker(NodeId),
+    /// A tracker that was found via the mainline DHT.
+    Dht(SocketAddr),
+}
+
+impl std::fmt::Display for QuerySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuerySource::Tracker(node_id) => write!(f, "{}", node_id),
+            QuerySource::Dht(addr) => write!(f, "dht:{}", addr),
+        }
+    }
+}
+
+/// A host found by [query_all], together with the sources that reported it.
+#[derive(Debug, Clone)]
+pub struct MergedHost {
+    /// The most recent announce for the host.
+    pub announce: SignedAnnounce,
+    /// All sources that reported the host.
+    pub sources: BTreeSet<QuerySource>,
+}
+
+/// The merged result of [query_all].
+#[derive(Debug, Default)]
+pub struct QueryAllResult {
+    /// The hosts that were found, with the sources that reported them.
+    pub hosts: BTreeMap<NodeId, MergedHost>,
+    /// Sources that were queried successfully, even if they did not know any hosts.
+    pub reachable: BTreeSet<QuerySource>,
+    /// Sources that could not be queried, with the reason.
+    pub unreachable: BTreeMap<QuerySource, anyhow::Error>,
+    /// True if the DHT was consulted to find additional trackers.
+    pub dht_consulted: bool,
+    /// True if there were sources left that were not dialed, because of `max_dials`.
+    pub dials_exhausted: bool,
+    /// How effective the DHT was, if it was consulted.
+    ///
+    /// This only covers the trackers found via the DHT.
+    pub dht_stats: Option<DhtQueryStats>,
+}
+
+impl QueryAllResult {
+    /// True if at least one tracker found via the DHT could be queried.
+    pub fn dht_reachable(&self) -> bool {
+        self.reachable
+            .iter()
+            .any(|source| matches!(source, QuerySource::Dht(_)))
+    }
+
+    /// Merge the result of querying a single source.
+    fn add(&mut self, source: QuerySource, result: anyhow::Result<Vec<SignedAnnounce>>) {
+        let hosts = match result {
+            Ok(hosts) => hosts,
+            Err(cause) => {
+                tracing::debug!("error querying {}: {}", source, cause);
+                self.unreachable.insert(source, cause);
+                return;
+            }
+        };
+        self.reachable.insert(source);
+        for announce in hosts {
+            if let Err(cause) = announce.verify() {
+                tracing::warn!("invalid announce from {}: {}", source, cause);
+                continue;
+            }
+            match self.hosts.entry(announce.host) {
+                Entry::Occupied(mut entry) => {
+                    let entry = entry.get_mut();
+                    if entry.announce.timestamp < announce.timestamp {
+                        entry.announce = announce;
+                    }
+                    entry.sources.insert(source);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(MergedHost {
+                        announce,
+                        sources: BTreeSet::from([source]),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Query a number of trackers, and optionally the trackers found via the mainline DHT,
+/// and merge the results.
+///
+/// The result contains, for each host, which sources reported it, as well as which
+/// sources could be reached at all. This can be used to decide whether to keep searching.
+///
+/// At most `concurrency` sources are queried at the same time. Results are merged as
+/// they arrive, so a slow source does not hold up the others.
+///
+/// If `max_dials` is set, at most that many sources are dialed in total, no matter how
+/// many trackers are given or found via the DHT. The result then contains whatever was
+/// found so far, see [QueryAllResult::dials_exhausted].
+///
+/// The given trackers are dialed with `alpn`, which is [crate::protocol::ALPN]
+/// unless they are part of a separate tracker network. Trackers found via the DHT are
+/// dialed with the ALPNs the quinn endpoint was created with.
+pub async fn query_all<P>(
+    endpoint: Endpoint,
+    trackers: impl IntoIterator<Item = NodeId>,
+    alpn: &[u8],
+    dht: Option<(P, mainline::dht::Dht)>,
+    args: Query,
+    concurrency: usize,
+    max_dials: Option<usize>,
+) -> QueryAllResult
+where
+    P: QuinnConnectionProvider<SocketAddr> + Send + Sync + 'static,
+{
+    query_all_with_progress(
+        endpoint,
+        trackers,
+        alpn,
+        dht,
+        args,
+        concurrency,
+        max_dials,
+        |_, _| {},
+    )
+    .await
+}
+
+/// Like [query_all], but report the result of each source as it arrives.
+///
+/// `on_result` is called with each source and its result, fastest source first,
+/// before the result is merged.
+#[allow(clippy::too_many_arguments)]
+pub async fn query_all_with_progress<P>(
+    endpoint: Endpoint,
+    trackers: impl IntoIterator<Item = NodeId>,
+    alpn: &[u8],
+    dht: Option<(P, mainline::dht::Dht)>,
+    args: Query,
+    concurrency: usize,
+    max_dials: Option<usize>,
+    mut on_result: impl FnMut(QuerySource, &anyhow::Result<Vec<SignedAnnounce>>),
+) -> QueryAllResult
+where
+    P: QuinnConnectionProvider<SocketAddr> + Send + Sync + 'static,
+{
+    let trackers = trackers.into_iter().collect::<Vec<_>>();
+    let trackers = futures::stream::iter(trackers)
+        .map(QuerySource::Tracker)
+        .boxed();
+    let mut result = QueryAllResult {
+        dht_consulted: dht.is_some(),
+        ..Default::default()
+    };
+    let t0 = Instant::now();
+    let dht_nodes = Arc::new(AtomicUsize::new(0));
+    let mut dht_stats = dht.as_ref().map(|_| DhtQueryStats::default());
+    let (candidates, quinn_endpoint) = match dht {
+        Some((quinn_endpoint, dht)) => {
+            let info_hash = to_infohash(args.content);
+            let response = dht.as_async().get_peers(info_hash);
+            let dht = unique_tracker_addrs(response, dht_nodes.clone())
+                .map(QuerySource::Dht)
+                .boxed();
+            (
+                futures::stream::select(trackers, dht).boxed(),
+                Some(quinn_endpoint),
+            )
+        }
+        None => (trackers, None),
+    };
+    let budget = max_dials.unwrap_or(usize::MAX);
+    let mut dialed = 0;
+    let exhausted = Arc::new(AtomicBool::new(false));
+    let candidates = candidates.take_while({
+        let exhausted = exhausted.clone();
+        move |_| {
+            if dialed < budget {
+                dialed += 1;
+                future::ready(true)
+            } else {
+                // there is a candidate we will not dial
+                exhausted.store(true, Ordering::Relaxed);
+                future::ready(false)
+            }
+        }
+    });
+    let mut sources = fan_out(candidates, concurrency, move |source| {
+        let endpoint = endpoint.clone();
+        let quinn_endpoint = quinn_endpoint.clone();
+        async move {
+            match (source, quinn_endpoint) {
+                (QuerySource::Tracker(tracker), _) => {
+                    query_iroh_one(endpoint, &tracker, alpn, args).await
+                }
+                (QuerySource::Dht(addr), Some(quinn_endpoint)) => {
+                    query_socket_one(quinn_endpoint, addr, args).await
+                }
+                (QuerySource::Dht(_), None) => unreachable!("dht source without dht"),
+            }
+        }
+    });
+    while let Some((source, res)) = sources.next().await {
+        on_result(source, &res);
+        if let (QuerySource::Dht(_), Some(stats)) = (source, &mut dht_stats) {
+            let hosts = res.as_ref().ok().map(|hosts| hosts.len());
+            stats.record(hosts, t0.elapsed());
+        }
+        result.add(source, res);
+    }
+    result.dials_exhausted = exhausted.load(Ordering::Relaxed);
+    result.dht_stats = dht_stats.map(|mut stats| {
+        stats.dht_nodes = dht_nodes.load(Ordering::Relaxed);
+        stats
+    });
+    result
+}
+
+/// Query sources with at most `concurrency` queries in flight.
+///
+/// Sources are only taken from `sources` when there is room. Results are yielded in
+/// the order the queries complete, together with their source.
+fn fan_out<S, F, Fut>(
+    sources: impl Stream<Item = S>,
+    concurrency: usize,
+    mut query: F,
+) -> impl Stream<Item = (S, Fut::Output)>
+where
+    S: Copy,
+    F: FnMut(S) -> Fut,
+    Fut: Future,
+{
+    sources
+        .map(move |source| query(source).map(move |res| (source, res)))
+        .buffer_unordered(concurrency.max(1))
+}
+
+/// Assume an existing connection to a tracker and query it for peers for some content.
+///
+/// If the query sets [QueryFlags::page_size], all pages are fetched and combined
+/// into one response. Use [query_page] to fetch pages one by one.
+pub async fn query(
+    connection: iroh_net::endpoint::Connection,
+    args: Query,
+) -> anyhow::Result<QueryResponse> {
+    query_with_codec(connection, args, &Postcard).await
+}
+
+/// Query a tracker over an existing connection, using the given codec.
+///
+/// See [query].
+pub async fn query_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    args: Query,
+    codec: &impl Codec,
+) -> anyhow::Result<QueryResponse> {
+    let mut response = query_page_with_codec(connection.clone(), args, codec).await?;
+    while let Some(cursor) = response.next_cursor {
+        let page = Query {
+            cursor: Some(cursor),
+            ..args
+        };
+        let page = query_page_with_codec(connection.clone(), page, codec).await?;
+        // the cursor must advance, or a broken tracker would keep us paging forever
+        anyhow::ensure!(
+            page.next_cursor.is_none_or(|next| next > cursor),
+            "tracker returned a cursor that does not advance"
+        );
+        append_page(&mut response, page);
+    }
+    Ok(response)
+}
+
+/// Query a single page of hosts.
+///
+/// The next page is queried with [QueryResponse::next_cursor] as [Query::cursor].
+/// If the query does not set [QueryFlags::page_size], there is just one page.
+pub async fn query_page(
+    connection: iroh_net::endpoint::Connection,
+    args: Query,
+) -> anyhow::Result<QueryResponse> {
+    query_page_with_codec(connection, args, &Postcard).await
+}
+
+/// Query a single page of hosts, using the given codec.
+///
+/// See [query_page].
+pub async fn query_page_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    args: Query,
+    codec: &impl Codec,
+) -> anyhow::Result<QueryResponse> {
+    tracing::info!("connected to {:?}", connection.remote_address());
+    let request = Request::Query(args);
+    tracing::info!("sending query");
+    let response = send_request(&connection, &request, codec).await?;
+    let response = codec.decode::<Response>(&response)?;
+    match response {
+        Response::QueryResponse(response) => Ok(response),
+        other => Err(unexpected_response(other)),
+    }
+}
+
+/// Add the hosts of a later page to a response.
+fn append_page(response: &mut QueryResponse, page: QueryResponse) {
+    response.hosts.extend(page.hosts);
+    response.host_ids.extend(page.host_ids);
+    response.node_addrs.extend(page.node_addrs);
+    response.verified.extend(page.verified);
+    response.stale.extend(page.stale);
+    if !response.hosts.is_empty() || !response.host_ids.is_empty() {
+        response.empty_reason = None;
+    }
+    response.cache_for = match (response.cache_for, page.cache_for) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    response.server_time = page.server_time;
+    response.next_cursor = page.next_cursor;
+    response.content_size = response.content_size.or(page.content_size);
+    response.proofs.extend(page.proofs);
+}
+
+/// Query a tracker with a signed query, so the tracker can attribute it to the querier.
+///
+/// If the tracker does not support signed queries, the query is sent unsigned, on
+/// another stream of the same connection. Trackers that answer with
+/// [crate::protocol::UnsupportedRequest] also serve more than one request per
+/// connection. See [query].
+pub async fn query_signed(
+    connection: iroh_net::endpoint::Connection,
+    signed_query: SignedQuery,
+) -> anyhow::Result<QueryResponse> {
+    query_signed_with_codec(connection, signed_query, &Postcard).await
+}
+
+/// Query a tracker with a signed query, using the given codec.
+///
+/// See [query_signed].
+pub async fn query_signed_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    signed_query: SignedQuery,
+    codec: &impl Codec,
+) -> anyhow::Result<QueryResponse> {
+    tracing::info!("connected to {:?}", connection.remote_address());
+    let request = Request::SignedQuery(signed_query);
+    tracing::info!("sending signed query");
+    let response = send_request(&connection, &request, codec).await?;
+    let response = codec.decode::<Response>(&response)?;
+    match response {
+        Response::QueryResponse(response) => Ok(response),
+        Response::Error(ErrorResponse::UnsupportedRequest(_)) => {
+            // the query is the same, the tracker just can't attribute it
+            tracing::debug!("tracker does not support signed queries, sending unsigned");
+            query_with_codec(connection, signed_query.query.query, codec).await
+        }
+        other => Err(unexpected_response(other)),
+    }
+}
+
+/// Ask a tracker for the most queried content.
+///
+/// Returns at most `limit` items with the number of recent queries, most queried
+/// first. Trackers cap the number of items, so this can be fewer than `limit`.
+/// Trackers only collect query statistics if configured to, otherwise the
+/// result is empty.
+pub async fn top_content(
+    connection: iroh_net::endpoint::Connection,
+    limit: u32,
+) -> anyhow::Result<Vec<(HashAndFormat, u64)>> {
+    top_content_with_codec(connection, limit, &Postcard).await
+}
+
+/// Ask a tracker for the most queried content, using the given codec.
+///
+/// See [top_content].
+pub async fn top_content_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    limit: u32,
+    codec: &impl Codec,
+) -> anyhow::Result<Vec<(HashAndFormat, u64)>> {
+    let request = Request::TopContent(limit);
+    let response = send_request(&connection, &request, codec).await?;
+    let response = codec.decode::<Response>(&response)?;
+    match response {
+        Response::TopContent(top) => Ok(top),
+        other => Err(unexpected_response(other)),
+    }
+}
+
+/// Ask a tracker to probe all hosts of some content now.
+///
+/// The refresh must be signed with the secret key of the tracker, see
+/// [crate::protocol::Refresh]. This returns once the tracker has probed all hosts,
+/// which can take a while for content with many hosts.
+pub async fn refresh_verification(
+    connection: iroh_net::endpoint::Connection,
+    refresh: SignedRefresh,
+) -> anyhow::Result<RefreshSummary> {
+    refresh_verification_with_codec(connection, refresh, &Postcard).await
+}
+
+/// Ask a tracker to probe all hosts of some content now, using the given codec.
+///
+/// See [refresh_verification].
+pub async fn refresh_verification_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    refresh: SignedRefresh,
+    codec: &impl Codec,
+) -> anyhow::Result<RefreshSummary> {
+    let request = Request::Refresh(refresh);
+    let response = send_request(&connection, &request, codec).await?;
+    let response = codec.decode::<Response>(&response)?;
+    match response {
+        Response::Refresh(summary) => Ok(summary),
+        other => Err(unexpected_response(other)),
+    }
+}
+
+/// Query a tracker for multiple items at once.
+///
+/// The result has one item per content in the batch, in order. With
+/// [QueryFlags::verified], the tracker probes the hosts right away, dialing each
+/// host once for all of its items. See [QueryBatch].
+///
+/// If the batch has more items than the tracker accepts, it is split into batches
+/// of the limit the tracker reports with [TooManyItems].
+pub async fn query_batch(
+    connection: iroh_net::endpoint::Connection,
+    batch: QueryBatch,
+) -> anyhow::Result<Vec<BatchQueryItem>> {
+    query_batch_with_codec(connection, batch, &Postcard).await
+}
+
+/// Query a tracker for multiple items at once, using the given codec.
+///
+/// See [query_batch].
+pub async fn query_batch_with_codec(
+    connection: iroh_net::endpoint::Connection,
+    batch: QueryBatch,
+    codec: &impl Codec,
+) -> anyhow::Result<Vec<BatchQueryItem>> {
+    let cause = match query_batch_once(&connection, batch.clone(), codec).await {
+        Err(cause) if cause.is::<TooManyItems>() => cause,
+        res => return res,
+    };
+    // split the batch into batches the tracker accepts
+    let limit = cause.downcast_ref::<TooManyItems>().unwrap().limit.max(1) as usize;
+    tracing::debug!("{}, splitting the batch", cause);
+    let mut items = Vec::with_capacity(batch.content.len());
+    for content in batch.content.chunks(limit) {
+        let chunk = QueryBatch {
+            content: content.to_vec(),
+            flags: batch.flags,
+            namespace: batch.namespace,
+        };
+        items.extend(query_batch_once(&connection, chunk, codec).await?);
+    }
+    Ok(items)
+}
+
+/// Send a single batch query.
+async fn query_batch_once(
+    connection: &iroh_net::endpoint::Connection,
+    batch: QueryBatch,
+    codec: &impl Codec,
+) -> anyhow::Result<Vec<BatchQueryItem>> {
+    let expected = batch.content.clone();
+    let request = Request::QueryBatch(batch);
+    let response = send_request(connection, &request, codec).await?;
+    let response = codec.decode::<Response>(&response)?;
+    let items = match response {
+        Response::QueryBatch(items) => items,
+        other => return Err(unexpected_response(other)),
+    };
+    anyhow::ensure!(
+        items.iter().map(|item| item.content).eq(expected),
+        "tracker returned items that do not match the batch"
+    );
+    Ok(items)
+}
+
+/// Query a tracker, and keep getting updated responses as hosts announce the content.
+///
+/// Unlike [subscribe], this does not poll. The tracker sends an update when the hosts
+/// for the content may have changed, after collecting changes for a short while. Each
+/// update is a full response. The stream ends when the tracker closes the stream, or
+/// after the first error.
+pub fn query_updates(
+    connection: iroh_net::endpoint::Connection,
+    args: Query,
+) -> impl Stream<Item = anyhow::Result<QueryResponse>> {
+    query_updates_with_codec(connection, args, &Postcard)
+}
+
+/// Query a tracker and keep getting updated responses, using the given codec.
+///
+/// See [query_updates].
+pub fn query_updates_with_codec<'a>(
+    connection: iroh_net::endpoint::Connection,
+    args: Query,
+    codec: &'a impl Codec,
+) -> impl Stream<Item = anyhow::Result<QueryResponse>> + 'a {
+    Gen::new(move |co| async move {
+        let mut recv = match open_stream(&connection, &Request::Subscribe(args), codec).await {
+            Ok(recv) => recv,
+            Err(cause) => {
+                co.yield_(Err(cause)).await;
+                return;
+            }
+        };
+        loop {
+            let response = match read_frame(&mut recv).await {
+                Ok(Some(frame)) => codec.decode::<Response>(&frame),
+                Ok(None) => return,
+                Err(cause) => Err(cause),
+            };
+            match response {
+                Ok(Response::QueryResponse(response)) => co.yield_(Ok(response)).await,
+                Ok(other) => {
+                    co.yield_(Err(unexpected_response(other))).await;
+                    return;
+                }
+                Err(cause) => {
+                    co.yield_(Err(cause)).await;
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Send a request on a new bi stream, and return the stream for the framed responses.
+async fn open_stream(
+    connection: &iroh_net::endpoint::Connection,
+    request: &Request,
+    codec: &impl Codec,
+) -> anyhow::Result<iroh_quinn::RecvStream> {
+    let (mut send, recv) = connection.open_bi().await?;
+    let request = codec.encode(request)?;
     send.write_all(&request).await?;
     send.finish().await?;
-    let _response = recv.read_to_end(REQUEST_SIZE_LIMIT).await?;
-    Ok(())
+    Ok(recv)
+}
+
+/// Read a frame as written by [crate::codec::encode_frame].
+///
+/// Returns `None` if the stream was finished before the frame.
+async fn read_frame(recv: &mut iroh_quinn::RecvStream) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match recv.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(iroh_quinn::ReadExactError::FinishedEarly) => return Ok(None),
+        Err(cause) => return Err(cause.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    anyhow::ensure!(len <= RESPONSE_SIZE_LIMIT, "frame too large: {}", len);
+    let mut frame = vec![0u8; len];
+    recv.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+/// An event of a [subscribe] stream.
+#[derive(Debug, Clone)]
+pub enum SubscribeEvent {
+    /// A host that was not emitted before.
+    Host(Box<SignedAnnounce>),
+    /// The subscription reconnected and caught up with the tracker.
+    ///
+    /// All hosts the tracker knew about when reconnecting have been emitted, as far
+    /// as they were not emitted before the reconnect.
+    Resynced,
+}
+
+/// Subscribe to the hosts for a query, by polling the tracker at the given interval.
+///
+/// `connect` is called to get a connection to the tracker, and called again whenever
+/// a query fails, e.g. because the connection was lost. Each host is emitted only
+/// once, also across reconnects, so a consumer can maintain its own view of the hosts.
+pub fn subscribe<F, Fut>(
+    connect: F,
+    args: Query,
+    interval: Duration,
+) -> impl Stream<Item = SubscribeEvent>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<iroh_net::endpoint::Connection>>,
+{
+    resubscribe(
+        connect,
+        move |connection: &iroh_net::endpoint::Connection| {
+            let connection = connection.clone();
+            async move { Ok(query(connection, args).await?.hosts) }
+        },
+        interval,
+    )
+}
+
+/// The reconnect and dedup logic of [subscribe], independent of the connection type.
+fn resubscribe<C, F, FFut, Q, QFut>(
+    mut connect: F,
+    mut query: Q,
+    interval: Duration,
+) -> impl Stream<Item = SubscribeEvent>
+where
+    F: FnMut() -> FFut,
+    FFut: Future<Output = anyhow::Result<C>>,
+    Q: FnMut(&C) -> QFut,
+    QFut: Future<Output = anyhow::Result<Vec<SignedAnnounce>>>,
+{
+    Gen::new(|co| async move {
+        let mut seen = HashSet::new();
+        let mut connected_before = false;
+        loop {
+            let connection = match connect().await {
+                Ok(connection) => connection,
+                Err(cause) => {
+                    tracing::debug!("error connecting to tracker: {}", cause);
+                    tokio::time::sleep(interval).await;
+                    continue;
+                }
+            };
+            let mut resyncing = connected_before;
+            connected_before = true;
+            loop {
+                let hosts = match query(&connection).await {
+                    Ok(hosts) => hosts,
+                    Err(cause) => {
+                        tracing::debug!("error querying tracker, reconnecting: {}", cause);
+                        break;
+                    }
+                };
+                for sa in hosts {
+                    if seen.insert(sa.host) {
+                        co.yield_(SubscribeEvent::Host(Box::new(sa))).await;
+                    }
+                }
+                if resyncing {
+                    resyncing = false;
+                    co.yield_(SubscribeEvent::Resynced).await;
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }
+    })
+}
+
+/// The availability of the children of a collection, see [verify_collection].
+#[derive(Debug, Clone, Default)]
+pub struct CollectionAvailability {
+    /// Hosts that have the whole collection.
+    ///
+    /// These count as hosts for every child.
+    pub complete: BTreeSet<NodeId>,
+    /// Children with hosts, and the hosts that have them individually.
+    pub available: BTreeMap<Hash, BTreeSet<NodeId>>,
+    /// Children that have no hosts on any of the trackers.
+    pub missing: BTreeSet<Hash>,
+    /// Children without hosts on the trackers that answered, and that could not be
+    /// checked on at least one other tracker, because the query failed.
+    pub failed: BTreeSet<Hash>,
+}
+
+impl CollectionAvailability {
+    /// True if every child has at least one host.
+    pub fn is_available(&self) -> bool {
+        self.missing.is_empty() && self.failed.is_empty()
+    }
+}
+
+/// The maximum number of children per [QueryBatch] in [verify_collection].
+const VERIFY_BATCH_SIZE: usize = 32;
+
+/// Check whether every child of a collection can be found on some tracker.
+///
+/// `root` is the hash of the hash sequence `children`. The caller has to provide the
+/// hash sequence, e.g. from a local store or by downloading it first.
+///
+/// Each tracker is queried for the collection as a whole, and for the children as raw
+/// blobs with a [QueryBatch] per [VERIFY_BATCH_SIZE] children, all on one connection.
+/// Trackers that don't support batches are queried for each child. A child is
+/// available if some host on some tracker has either the child or the whole
+/// collection. At most `query_parallelism` trackers are queried at the same time.
+/// `flags` apply to every query.
+///
+/// A failed query does not fail the whole check. The children it was for end up in
+/// [CollectionAvailability::failed], unless another tracker has hosts for them.
+///
+/// The trackers are dialed with `alpn`, which is [crate::protocol::ALPN] unless
+/// they are part of a separate tracker network.
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_collection(
+    endpoint: Endpoint,
+    trackers: impl IntoIterator<Item = NodeId>,
+    alpn: &[u8],
+    root: Hash,
+    children: &HashSeq,
+    flags: QueryFlags,
+    namespace: Option<Namespace>,
+    query_parallelism: usize,
+) -> CollectionAvailability {
+    let children = children.iter().collect::<BTreeSet<_>>();
+    let children = &children.into_iter().collect::<Vec<_>>();
+    let mut responses = futures::stream::iter(trackers)
+        .map(|tracker| {
+            let endpoint = endpoint.clone();
+            async move {
+                let res =
+                    tracker_collection(&endpoint, tracker, alpn, root, children, flags, namespace)
+                        .await;
+                (tracker, res)
+            }
+        })
+        .buffer_unordered(query_parallelism.max(1));
+    let mut complete = BTreeSet::new();
+    let mut hosts = BTreeMap::<Hash, BTreeSet<NodeId>>::new();
+    let mut failed = BTreeSet::new();
+    while let Some((tracker, res)) = responses.next().await {
+        match res {
+            Ok(availability) => {
+                complete.extend(availability.complete);
+                for (child, res) in availability.children {
+                    match res {
+                        Some(child_hosts) => hosts.entry(child).or_default().extend(child_hosts),
+                        None => {
+                            failed.insert(child);
+                        }
+                    }
+                }
+            }
+            Err(cause) => {
+                tracing::warn!(
+                    "error checking collection {} on {}: {}",
+                    root,
+                    tracker,
+                    cause
+                );
+                failed.extend(children.iter().copied());
+            }
+        }
+    }
+    let mut result = CollectionAvailability {
+        complete,
+        ..Default::default()
+    };
+    for child in children {
+        let child_hosts = hosts.remove(child).unwrap_or_default();
+        if !child_hosts.is_empty() || !result.complete.is_empty() {
+            result.available.insert(*child, child_hosts);
+        } else if failed.contains(child) {
+            result.failed.insert(*child);
+        } else {
+            result.missing.insert(*child);
+        }
+    }
+    result
 }
 
-/// The mapping from an iroh [HashAndFormat] to a bittorrent infohash, aka [mainline::Id].
-///
-/// Since an infohash is just 20 bytes, this can not be a bidirectional mapping.
-pub fn to_infohash(haf: HashAndFormat) -> mainline::Id {
-    let mut data = [0u8; 20];
-    data.copy_from_slice(&haf.hash.as_bytes()[..20]);
-    mainline::Id::from_bytes(data).unwrap()
+/// The availability of a collection on a single tracker.
+struct TrackerCollection {
+    /// Hosts that have the whole collection.
+    complete: BTreeSet<NodeId>,
+    /// The hosts of each child, or `None` if the query for the child failed.
+    children: Vec<(Hash, Option<Vec<NodeId>>)>,
 }
 
-fn unique_tracker_addrs(
-    mut response: mainline::Response<mainline::GetPeerResponse>,
-) -> impl Stream<Item = SocketAddr> {
-    Gen::new(|co| async move {
-        let mut found = HashSet::new();
-        while let Some(response) = response.next_async().await {
-            tracing::info!("got get_peers response: {:?}", response);
-            let tracker = response.peer;
-            if !found.insert(tracker) {
-                continue;
+async fn tracker_collection(
+    endpoint: &Endpoint,
+    tracker: NodeId,
+    alpn: &[u8],
+    root: Hash,
+    children: &[Hash],
+    flags: QueryFlags,
+    namespace: Option<Namespace>,
+) -> anyhow::Result<TrackerCollection> {
+    let connection = endpoint.connect_by_node_id(&tracker, alpn).await?;
+    let make_query = |content| Query {
+        content,
+        flags,
+        namespace,
+        cursor: None,
+    };
+    let response = query(
+        connection.clone(),
+        make_query(HashAndFormat::hash_seq(root)),
+    )
+    .await?;
+    let mut result = TrackerCollection {
+        complete: verified_hosts(response.hosts),
+        children: Vec::with_capacity(children.len()),
+    };
+    for batch in children.chunks(VERIFY_BATCH_SIZE) {
+        let query_batch = QueryBatch {
+            content: batch
+                .iter()
+                .map(|child| HashAndFormat::raw(*child))
+                .collect(),
+            flags,
+            namespace,
+        };
+        match crate::query_batch(connection.clone(), query_batch).await {
+            Ok(items) => {
+                let items = items
+                    .into_iter()
+                    .map(|item| (item.content.hash, Some(item.hosts)));
+                result.children.extend(items);
+            }
+            Err(cause) if cause.is::<crate::protocol::UnsupportedRequest>() => {
+                // an older tracker, so one query per child
+                for child in batch {
+                    let query = make_query(HashAndFormat::raw(*child));
+                    let hosts = match crate::query(connection.clone(), query).await {
+                        Ok(response) => Some(verified_hosts(response.hosts).into_iter().collect()),
+                        Err(cause) => {
+                            tracing::warn!("error querying {} on {}: {}", child, tracker, cause);
+                            None
+                        }
+                    };
+                    result.children.push((*child, hosts));
+                }
+            }
+            Err(cause) => {
+                tracing::warn!(
+                    "error querying children of {} on {}: {}",
+                    root,
+                    tracker,
+                    cause
+                );
+                result
+                    .children
+                    .extend(batch.iter().map(|child| (*child, None)));
             }
-            co.yield_(tracker).await;
         }
-    })
+    }
+    Ok(result)
 }
 
-async fn query_socket_one(
-    endpoint: impl QuinnConnectionProvider<SocketAddr>,
-    addr: SocketAddr,
-    args: Query,
-) -> anyhow::Result<Vec<SignedAnnounce>> {
-    let connection = endpoint.connect(addr).await?;
-    let result = query(connection, args).await?;
-    Ok(result.hosts)
+/// The hosts of validly signed announces.
+fn verified_hosts(hosts: Vec<SignedAnnounce>) -> BTreeSet<NodeId> {
+    hosts
+        .into_iter()
+        .filter(|sa| sa.verify().is_ok())
+        .map(|sa| sa.host)
+        .collect()
 }
 
-async fn query_iroh_one(
-    endpoint: Endpoint,
-    node_id: &NodeId,
-    args: Query,
-) -> anyhow::Result<Vec<SignedAnnounce>> {
-    let connection = endpoint.connect_by_node_id(node_id, ALPN).await?;
-    let result = query(connection, args).await?;
-    Ok(result.hosts)
+/// The size of a blake3 chunk.
+const CHUNK_LEN: u64 = 1024;
+
+/// Check a proof a tracker got when verifying a host against the hash of the blob.
+///
+/// This succeeds if the parents and data of the proof are a valid part of the bao
+/// encoding of the blob with hash `root`, covering [VerificationProof::chunk]. It does
+/// not prove that the host still has the data, only that it had it when the tracker
+/// probed it.
+pub fn verify_proof(root: Hash, proof: &VerificationProof) -> anyhow::Result<()> {
+    let chunks = proof.size.div_ceil(CHUNK_LEN).max(1);
+    anyhow::ensure!(proof.chunk < chunks, "chunk {} out of range", proof.chunk);
+    let mut parents = proof.parents.iter();
+    let mut expected = blake3::Hash::from(*root.as_bytes());
+    let (mut start, mut len, mut is_root) = (0u64, chunks, true);
+    // walk down from the root to the chunk, iroh sends parents down to single chunks
+    while len > 1 {
+        let (left, right) = parents
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("missing parent"))?;
+        let left = blake3::Hash::from(*left.as_bytes());
+        let right = blake3::Hash::from(*right.as_bytes());
+        anyhow::ensure!(
+            blake3::guts::parent_cv(&left, &right, is_root) == expected,
+            "parent hash mismatch"
+        );
+        let left_len = left_subtree_chunks(len);
+        if proof.chunk < start + left_len {
+            (expected, len) = (left, left_len);
+        } else {
+            (expected, start, len) = (right, start + left_len, len - left_len);
+        }
+        is_root = false;
+    }
+    anyhow::ensure!(parents.next().is_none(), "too many parents");
+    let end = ((start + len) * CHUNK_LEN).min(proof.size);
+    anyhow::ensure!(
+        proof.data.len() as u64 == end.saturating_sub(start * CHUNK_LEN),
+        "unexpected data size"
+    );
+    anyhow::ensure!(
+        subtree_cv(&proof.data, start, is_root) == expected,
+        "data hash mismatch"
+    );
+    Ok(())
 }
 
-/// A connection provider that can be used to connect to a tracker.
+/// The number of chunks in the left subtree of a blake3 tree with more than one chunk.
 ///
-/// This can either be a [`iroh_quinn::Endpoint`] where connections are created on demand,
-/// or some sort of connection pool.
-pub trait QuinnConnectionProvider<Addr>: Clone {
-    fn connect(&self, addr: Addr) -> BoxFuture<anyhow::Result<iroh_quinn::Connection>>;
+/// This is the largest power of two that is smaller than `chunks`.
+fn left_subtree_chunks(chunks: u64) -> u64 {
+    1 << (63 - (chunks - 1).leading_zeros())
 }
 
-impl QuinnConnectionProvider<SocketAddr> for iroh_quinn::Endpoint {
-    fn connect(&self, addr: SocketAddr) -> BoxFuture<anyhow::Result<iroh_quinn::Connection>> {
-        async move { Ok(self.connect(addr, "localhost")?.await?) }.boxed()
+/// The chaining value of the subtree with the given data, starting at `start_chunk`.
+fn subtree_cv(data: &[u8], start_chunk: u64, is_root: bool) -> blake3::Hash {
+    let chunks = (data.len() as u64).div_ceil(CHUNK_LEN).max(1);
+    if chunks == 1 {
+        let mut state = blake3::guts::ChunkState::new(start_chunk);
+        state.update(data);
+        return state.finalize(is_root);
     }
+    let left_len = left_subtree_chunks(chunks);
+    let (left, right) = data.split_at((left_len * CHUNK_LEN) as usize);
+    blake3::guts::parent_cv(
+        &subtree_cv(left, start_chunk, false),
+        &subtree_cv(right, start_chunk + left_len, false),
+        is_root,
+    )
 }
 
-/// Query multiple trackers in parallel and merge the results.
-pub fn query_trackers(
+/// The number of trackers to query, and of hosts to probe, at the same time in
+/// [find_one_host].
+const FIND_PARALLELISM: usize = 4;
+
+/// Find a single host that has the complete content and is reachable right now.
+///
+/// This queries the trackers for verified hosts, and probes hosts as soon as they come
+/// in, best first. The first host that accepts a connection for the blobs protocol is
+/// returned, and all other queries and probes are cancelled.
+///
+/// The trackers are dialed with `alpn`, which is [crate::protocol::ALPN] unless
+/// they are part of a separate tracker network.
+pub async fn find_one_host(
     endpoint: Endpoint,
     trackers: impl IntoIterator<Item = NodeId>,
-    args: Query,
-    query_parallelism: usize,
-) -> impl Stream<Item = anyhow::Result<SignedAnnounce>> {
-    futures::stream::iter(trackers)
-        .map(move |tracker| {
+    alpn: &[u8],
+    content: HashAndFormat,
+) -> anyhow::Result<NodeAddr> {
+    let args = Query {
+        content,
+        flags: QueryFlags {
+            complete: true,
+            verified: true,
+            sample: None,
+            include_expired: None,
+            min_class: None,
+            require_method: None,
+            page_size: None,
+            prefer_committed: false,
+            detail: DetailLevel::default(),
+            include_proofs: false,
+        },
+        namespace: None,
+        cursor: None,
+    };
+    let trackers = trackers.into_iter().collect::<Vec<_>>();
+    let mut seen = BTreeSet::new();
+    let mut probes = futures::stream::iter(trackers)
+        .map(|tracker| {
             let endpoint = endpoint.clone();
             async move {
-                let hosts = match query_iroh_one(endpoint, &tracker, args).await {
-                    Ok(hosts) => hosts.into_iter().map(anyhow::Ok).collect(),
-                    Err(cause) => vec![Err(cause)],
-                };
-                futures::stream::iter(hosts)
+                let connection = endpoint.connect_by_node_id(&tracker, alpn).await?;
+                query(connection, args).await
             }
         })
-        .buffer_unordered(query_parallelism)
-        .flatten()
-}
-
-/// Query the mainline DHT for trackers for the given content, then query each tracker for peers.
-pub fn query_dht(
-    endpoint: impl QuinnConnectionProvider<SocketAddr>,
-    dht: mainline::dht::Dht,
-    args: Query,
-    query_parallelism: usize,
-) -> impl Stream<Item = anyhow::Result<SignedAnnounce>> {
-    let dht = dht.as_async();
-    let info_hash = to_infohash(args.content);
-    let response: mainline::Response<mainline::GetPeerResponse> = dht.get_peers(info_hash);
-    let unique_tracker_addrs = unique_tracker_addrs(response);
-    unique_tracker_addrs
-        .map(move |addr| {
+        .buffer_unordered(FIND_PARALLELISM)
+        .flat_map(|response| {
+            let sources = match response {
+                Ok(mut response) => {
+                    response.hosts.retain(|sa| sa.verify().is_ok());
+                    response.into_download_sources()
+                }
+                Err(cause) => {
+                    tracing::debug!("error querying tracker: {}", cause);
+                    Vec::new()
+                }
+            };
+            futures::stream::iter(sources)
+        })
+        .filter(move |source| future::ready(seen.insert(source.node_addr.node_id)))
+        .map(|source| {
             let endpoint = endpoint.clone();
             async move {
-                let hosts = match query_socket_one(endpoint, addr, args).await {
-                    Ok(hosts) => hosts.into_iter().map(anyhow::Ok).collect(),
-                    Err(cause) => vec![Err(cause)],
-                };
-                futures::stream::iter(hosts)
+                let node_addr = source.node_addr;
+                endpoint
+                    .connect(node_addr.clone(), iroh_blobs::protocol::ALPN)
+                    .await?;
+                anyhow::Ok(node_addr)
             }
         })
-        .buffer_unordered(query_parallelism)
-        .flatten()
+        .buffer_unordered(FIND_PARALLELISM);
+    while let Some(res) = probes.next().await {
+        match res {
+            Ok(node_addr) => return Ok(node_addr),
+            Err(cause) => tracing::debug!("error probing host: {}", cause),
+        }
+    }
+    anyhow::bail!("no reachable host found for {}", content)
 }
 
-/// Announce to the mainline DHT in parallel.
-///
-/// Note that this should only be called from a publicly reachable node, where port is the port
-/// on which the tracker protocol is reachable.
-pub fn announce_dht(
-    dht: mainline::dht::Dht,
-    content: BTreeSet<HashAndFormat>,
-    port: u16,
-    announce_parallelism: usize,
-) -> impl Stream<Item = (HashAndFormat, mainline::Result<mainline::StoreQueryMetdata>)> {
-    let dht = dht.as_async();
-    futures::stream::iter(content)
-        .map(move |content| {
-            let dht = dht.clone();
-            async move {
-                let info_hash = to_infohash(content);
-                let res = dht.announce_peer(info_hash, Some(port)).await;
-                (content, res)
-            }
-        })
-        .buffer_unordered(announce_parallelism)
+/// Timeouts for connections to a tracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// Close the connection if nothing was received for this long.
+    pub idle_timeout: Duration,
+    /// Send keepalives at this interval, to keep an idle connection open.
+    ///
+    /// This also keeps NAT mappings alive. It should be well below both the idle
+    /// timeout and the typical NAT mapping timeout of about 30 seconds.
+    pub keep_alive_interval: Option<Duration>,
 }
 
-/// Assume an existing connection to a tracker and query it for peers for some content.
-pub async fn query(
-    connection: iroh_net::endpoint::Connection,
-    args: Query,
-) -> anyhow::Result<QueryResponse> {
-    tracing::info!("connected to {:?}", connection.remote_address());
-    let (mut send, mut recv) = connection.open_bi().await?;
-    tracing::info!("opened bi stream");
-    let request = Request::Query(args);
-    let request = postcard::to_stdvec(&request)?;
-    tracing::info!("sending query");
-    send.write_all(&request).await?;
-    send.finish().await?;
-    let response = recv.read_to_end(REQUEST_SIZE_LIMIT).await?;
-    let response = postcard::from_bytes::<Response>(&response)?;
-    Ok(match response {
-        Response::QueryResponse(response) => response,
-    })
+impl Default for ConnectionOptions {
+    /// Options for short lived connections, e.g. for a few queries.
+    ///
+    /// There are no keepalives, so a connection that is no longer used is closed
+    /// after the idle timeout.
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(30),
+            keep_alive_interval: None,
+        }
+    }
+}
+
+impl ConnectionOptions {
+    /// Options for long lived connections, e.g. subscriptions or a pooled client.
+    ///
+    /// Keepalives are sent so the connection survives long idle periods.
+    pub fn long_lived() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(60),
+            keep_alive_interval: Some(Duration::from_secs(15)),
+        }
+    }
+
+    /// The transport config for these options.
+    ///
+    /// Use this to configure an iroh endpoint for connecting to trackers by node id.
+    pub fn transport_config(&self) -> anyhow::Result<iroh_quinn::TransportConfig> {
+        let mut transport_config = iroh_quinn::TransportConfig::default();
+        transport_config
+            .max_idle_timeout(Some(self.idle_timeout.try_into()?))
+            .keep_alive_interval(self.keep_alive_interval);
+        Ok(transport_config)
+    }
 }
 
 /// Create a quinn client endpoint.
+///
+/// This uses the default [ConnectionOptions].
 pub fn create_quinn_client(
     bind_addr: SocketAddr,
     alpn_protocols: Vec<Vec<u8>>,
     keylog: bool,
+) -> anyhow::Result<iroh_quinn::Endpoint> {
+    create_quinn_client_with_options(
+        bind_addr,
+        alpn_protocols,
+        keylog,
+        &ConnectionOptions::default(),
+    )
+}
+
+/// Create a quinn client endpoint with the given connection options.
+pub fn create_quinn_client_with_options(
+    bind_addr: SocketAddr,
+    alpn_protocols: Vec<Vec<u8>>,
+    keylog: bool,
+    options: &ConnectionOptions,
 ) -> anyhow::Result<iroh_quinn::Endpoint> {
     let secret_key = iroh_net::key::SecretKey::generate();
     let tls_client_config =
         iroh_net::tls::make_client_config(&secret_key, None, alpn_protocols, keylog)?;
     let mut client_config = iroh_quinn::ClientConfig::new(Arc::new(tls_client_config));
     let mut endpoint = iroh_quinn::Endpoint::client(bind_addr)?;
-    let mut transport_config = iroh_quinn::TransportConfig::default();
-    transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
-    client_config.transport_config(Arc::new(transport_config));
+    client_config.transport_config(Arc::new(options.transport_config()?));
     endpoint.set_default_client_config(client_config);
     Ok(endpoint)
 }
@@ -217,6 +1699,7 @@ async fn create_endpoint(
     key: iroh_net::key::SecretKey,
     port: u16,
     publish: bool,
+    alpn: &[u8],
 ) -> anyhow::Result<Endpoint> {
     let mainline_discovery = if publish {
         PkarrNodeDiscovery::builder()
@@ -227,8 +1710,9 @@ async fn create_endpoint(
     };
     iroh_net::Endpoint::builder()
         .secret_key(key)
+        .transport_config(ConnectionOptions::default().transport_config()?)
         .discovery(Box::new(mainline_discovery))
-        .alpns(vec![ALPN.to_vec()])
+        .alpns(vec![alpn.to_vec()])
         .bind(port)
         .await
 }
@@ -265,44 +1749,89 @@ impl std::str::FromStr for TrackerId {
     }
 }
 
-/// Connect to a tracker using the [crate::protocol::ALPN] protocol, using either
-/// a node id or an address.
+/// What to do if a tracker has a different node id than expected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdMismatch {
+    /// Fail. This is the default.
+    #[default]
+    Fail,
+    /// Log a warning and continue.
+    ///
+    /// This is insecure, since anybody who can intercept the connection can pose as
+    /// the tracker. Only use this for debugging, e.g. when a tracker key was regenerated.
+    InsecureWarn,
+}
+
+/// Check that a connection is to the tracker with the expected node id.
+///
+/// Connections by address, e.g. via [create_quinn_client], do not check the node id of
+/// the tracker, so this should be called if the node id is known.
+pub fn check_tracker_id(
+    connection: &iroh_net::endpoint::Connection,
+    expected: &NodeId,
+    on_mismatch: IdMismatch,
+) -> anyhow::Result<()> {
+    let actual = iroh_net::endpoint::get_remote_node_id(connection)?;
+    if actual != *expected {
+        match on_mismatch {
+            IdMismatch::Fail => {
+                anyhow::bail!("tracker has node id {}, expected {}", actual, expected)
+            }
+            IdMismatch::InsecureWarn => {
+                tracing::warn!(
+                    "tracker has node id {}, expected {}. continuing anyway",
+                    actual,
+                    expected
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Connect to a tracker using the given ALPN, using either a node id or an address.
+///
+/// The ALPN is [crate::protocol::ALPN] unless the tracker is part of a separate
+/// tracker network.
 ///
 /// Note that this is less efficient than using an existing endpoint when doing multiple requests.
 /// It is provided as a convenience function for short lived utilities.
 pub async fn connect(
     tracker: &TrackerId,
+    alpn: &[u8],
     local_port: u16,
 ) -> anyhow::Result<iroh_net::endpoint::Connection> {
     match tracker {
-        TrackerId::Quinn(tracker) => connect_socket(*tracker, local_port).await,
-        TrackerId::Iroh(tracker) => connect_iroh(tracker, local_port).await,
+        TrackerId::Quinn(tracker) => connect_socket(*tracker, alpn, local_port).await,
+        TrackerId::Iroh(tracker) => connect_iroh(tracker, alpn, local_port).await,
         TrackerId::Udp(_) => anyhow::bail!("can not connect to udp tracker"),
     }
 }
 
-/// Create a iroh endpoint and connect to a tracker using the [crate::protocol::ALPN] protocol.
+/// Create a iroh endpoint and connect to a tracker using the given ALPN.
 async fn connect_iroh(
     tracker: &NodeId,
+    alpn: &[u8],
     local_port: u16,
 ) -> anyhow::Result<iroh_net::endpoint::Connection> {
     // todo: uncomment once the connection problems are fixed
     // for now, a random node id is more reliable.
     // let key = load_secret_key(tracker_path(CLIENT_KEY)?).await?;
     let key = iroh_net::key::SecretKey::generate();
-    let endpoint = create_endpoint(key, local_port, false).await?;
+    let endpoint = create_endpoint(key, local_port, false, alpn).await?;
     tracing::info!("trying to connect to tracker at {:?}", tracker);
-    let connection = endpoint.connect_by_node_id(tracker, ALPN).await?;
+    let connection = endpoint.connect_by_node_id(tracker, alpn).await?;
     Ok(connection)
 }
 
-/// Create a quinn endpoint and connect to a tracker using the [crate::protocol::ALPN] protocol.
+/// Create a quinn endpoint and connect to a tracker using the given ALPN.
 async fn connect_socket(
     tracker: SocketAddr,
+    alpn: &[u8],
     local_port: u16,
 ) -> anyhow::Result<iroh_net::endpoint::Connection> {
     let bind_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port));
-    let endpoint = create_quinn_client(bind_addr, vec![ALPN.to_vec()], false)?;
+    let endpoint = create_quinn_client(bind_addr, vec![alpn.to_vec()], false)?;
     tracing::info!("trying to connect to tracker at {:?}", tracker);
     let connection = endpoint.connect(tracker, "localhost")?.await?;
     Ok(connection)
@@ -310,6 +1839,15 @@ async fn connect_socket(
 
 const MAX_MSG_SIZE: usize = 1200;
 
+/// Encode a request into a single datagram.
+///
+/// Fails instead of panicking if the request does not fit into `buf`.
+fn encode_datagram<'a>(request: &Request, buf: &'a mut [u8]) -> anyhow::Result<&'a mut [u8]> {
+    let len = buf.len();
+    postcard::to_slice(request, buf)
+        .map_err(|cause| anyhow::anyhow!("request does not fit into {} bytes: {}", len, cause))
+}
+
 pub struct MergeUntilFirstEnds<S1, S2> {
     stream1: S1,
     stream2: S2,
@@ -383,7 +1921,18 @@ impl UdpDiscovery {
         dht: mainline::dht::Dht,
         args: Query,
     ) -> anyhow::Result<impl Stream<Item = SignedAnnounce>> {
-        let results = self.query(args).await?.into_stream().boxed();
+        let results = self
+            .query(args)
+            .await?
+            .into_stream()
+            .take_while(|res| {
+                if let Err(cause) = res {
+                    tracing::warn!("stopping dht query: {}", cause);
+                }
+                future::ready(res.is_ok())
+            })
+            .filter_map(|res| future::ready(res.ok()))
+            .boxed();
         let dht = dht.as_async();
         let this = self.clone();
         let find_new_trackers = async move {
@@ -417,10 +1966,37 @@ impl UdpDiscovery {
             .await?)
     }
 
-    pub async fn query(&self, query: Query) -> anyhow::Result<flume::Receiver<SignedAnnounce>> {
+    /// Query all trackers, with a buffer of [DEFAULT_QUERY_BUFFER] hosts.
+    ///
+    /// See [UdpDiscovery::query_with_capacity].
+    pub async fn query(
+        &self,
+        query: Query,
+    ) -> anyhow::Result<flume::Receiver<Result<SignedAnnounce, BufferOverflow>>> {
+        self.query_with_capacity(query, DEFAULT_QUERY_BUFFER).await
+    }
+
+    /// Query all trackers, including trackers that are added later.
+    ///
+    /// Hosts are buffered until they are received, up to `capacity` hosts. When the
+    /// buffer is full, reading from the network waits for the receiver, for up to
+    /// [QUERY_BACKPRESSURE_TIMEOUT]. If the receiver does not make room in time, it
+    /// gets a [BufferOverflow] error after the buffered hosts, and the query is
+    /// cancelled, so a stuck receiver can not stall other queries for long. This
+    /// bounds the memory used for popular content. Pick a capacity that fits the
+    /// memory budget, and query again with a fresh buffer after an overflow if needed.
+    pub async fn query_with_capacity(
+        &self,
+        query: Query,
+        capacity: usize,
+    ) -> anyhow::Result<flume::Receiver<Result<SignedAnnounce, BufferOverflow>>> {
         let (tx, rx) = oneshot::channel();
         self.tx
-            .send_async(UdpActorMessage::Query { query, tx })
+            .send_async(UdpActorMessage::Query {
+                query,
+                capacity,
+                tx,
+            })
             .await?;
         Ok(rx.await?)
     }
@@ -430,16 +2006,27 @@ impl UdpDiscovery {
         self.tx
             .send_async(UdpActorMessage::AnnounceOnce { announce, tx })
             .await?;
-        rx.await?;
-        Ok(())
+        rx.await?
+    }
+
+    /// Stop all periodic announces, and retract all announces of the host from the trackers.
+    ///
+    /// This should be called when shutting down.
+    pub async fn unannounce(&self, unannounce: SignedUnannounce) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send_async(UdpActorMessage::Unannounce { unannounce, tx })
+            .await?;
+        rx.await?
     }
 
     pub async fn announce(&self, announce: SignedAnnounce) -> anyhow::Result<()> {
         let this = self.clone();
+        let announce2 = announce.clone();
         let task = tokio::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_secs(10)).await;
-                this.announce_once(announce).await.ok();
+                this.announce_once(announce2.clone()).await.ok();
                 tokio::time::sleep(Duration::from_secs(110)).await;
             }
         })
@@ -451,11 +2038,73 @@ impl UdpDiscovery {
     }
 }
 
+/// The default number of hosts buffered for a [UdpDiscovery] query.
+pub const DEFAULT_QUERY_BUFFER: usize = 1024;
+
+/// The buffer of a query was full, so the query was cancelled.
+///
+/// See [UdpDiscovery::query_with_capacity].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferOverflow {
+    /// The capacity of the buffer.
+    pub capacity: usize,
+}
+
+impl std::fmt::Display for BufferOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "query buffer of {} hosts overflowed", self.capacity)
+    }
+}
+
+impl std::error::Error for BufferOverflow {}
+
+/// How long reading from the network waits for a query with a full buffer.
+///
+/// See [UdpDiscovery::query_with_capacity].
+pub const QUERY_BACKPRESSURE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The sending side of a query, with a bounded buffer.
+struct Listener {
+    tx: flume::Sender<Result<SignedAnnounce, BufferOverflow>>,
+    capacity: usize,
+}
+
+impl Listener {
+    fn new(
+        capacity: usize,
+    ) -> (
+        Self,
+        flume::Receiver<Result<SignedAnnounce, BufferOverflow>>,
+    ) {
+        let (tx, rx) = flume::bounded(capacity);
+        (Self { tx, capacity }, rx)
+    }
+
+    /// Deliver a host, waiting for room in the buffer.
+    ///
+    /// Returns false if the listener should be removed, because the receiver is gone
+    /// or the buffer stayed full for [QUERY_BACKPRESSURE_TIMEOUT].
+    async fn deliver(&self, sa: SignedAnnounce) -> bool {
+        match tokio::time::timeout(QUERY_BACKPRESSURE_TIMEOUT, self.tx.send_async(Ok(sa))).await {
+            Ok(res) => res.is_ok(),
+            Err(_) => {
+                // the buffer is full, so the error goes after the buffered hosts
+                let tx = self.tx.clone();
+                let overflow = BufferOverflow {
+                    capacity: self.capacity,
+                };
+                tokio::spawn(async move { tx.send_async(Err(overflow)).await.ok() });
+                false
+            }
+        }
+    }
+}
+
 struct UdpActor {
     socket: tokio::net::UdpSocket,
     rx: flume::Receiver<UdpActorMessage>,
     trackers: BTreeSet<SocketAddr>,
-    listeners: BTreeMap<Query, Vec<flume::Sender<SignedAnnounce>>>,
+    listeners: BTreeMap<Query, Vec<Listener>>,
     announces: BTreeMap<(HashAndFormat, AnnounceKind), AbortingJoinHandle<()>>,
 }
 
@@ -479,7 +2128,8 @@ impl<T> Drop for AbortingJoinHandle<T> {
 enum UdpActorMessage {
     Query {
         query: Query,
-        tx: oneshot::Sender<flume::Receiver<SignedAnnounce>>,
+        capacity: usize,
+        tx: oneshot::Sender<flume::Receiver<Result<SignedAnnounce, BufferOverflow>>>,
     },
     AddTracker {
         tracker: SocketAddr,
@@ -494,7 +2144,11 @@ enum UdpActorMessage {
     },
     AnnounceOnce {
         announce: SignedAnnounce,
-        tx: oneshot::Sender<()>,
+        tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Unannounce {
+        unannounce: SignedUnannounce,
+        tx: oneshot::Sender<anyhow::Result<()>>,
     },
 }
 
@@ -506,23 +2160,29 @@ impl UdpActor {
                 msg = self.rx.recv_async() => {
                     tracing::trace!("got msg {:?}", msg);
                     match msg {
-                        Ok(UdpActorMessage::Query { query, tx  }) => {
-                            let (announce_tx, announce_rx) = flume::bounded(1024);
-                            self.listeners.entry(query).or_default().push(announce_tx);
-                            let msg = Request::Query(query);
-                            let msg = postcard::to_slice(&msg, &mut buf).unwrap();
-                            for tracker in &self.trackers {
-                                tracing::info!("sending query to {}, {} bytes", tracker, msg.len());
-                                self.socket.send_to(msg, tracker).await.ok();
+                        Ok(UdpActorMessage::Query { query, capacity, tx  }) => {
+                            let (listener, announce_rx) = Listener::new(capacity);
+                            self.listeners.entry(query).or_default().push(listener);
+                            match encode_datagram(&Request::Query(query), &mut buf) {
+                                Ok(msg) => {
+                                    for tracker in &self.trackers {
+                                        tracing::info!("sending query to {}, {} bytes", tracker, msg.len());
+                                        self.socket.send_to(msg, tracker).await.ok();
+                                    }
+                                }
+                                Err(cause) => tracing::warn!("not sending query: {}", cause),
                             }
                             tx.send(announce_rx).ok();
                         }
                         Ok(UdpActorMessage::AddTracker { tracker }) => {
                             if self.trackers.insert(tracker) {
                                 for query in self.listeners.keys() {
-                                    let msg = Request::Query(*query);
-                                    let msg = postcard::to_slice(&msg, &mut buf).unwrap();
-                                    self.socket.send_to(msg, tracker).await.ok();
+                                    match encode_datagram(&Request::Query(*query), &mut buf) {
+                                        Ok(msg) => {
+                                            self.socket.send_to(msg, tracker).await.ok();
+                                        }
+                                        Err(cause) => tracing::warn!("not sending query: {}", cause),
+                                    }
                                 }
                             }
                         }
@@ -534,11 +2194,24 @@ impl UdpActor {
                             self.announces.insert(key, task);
                         }
                         Ok(UdpActorMessage::AnnounceOnce { announce, tx }) => {
-                            let msg = postcard::to_slice(&Request::Announce(announce), &mut buf).unwrap();
-                            for tracker in &self.trackers {
-                                self.socket.send_to(msg, tracker).await.ok();
+                            let res = encode_datagram(&Request::Announce(announce), &mut buf);
+                            if let Ok(msg) = &res {
+                                for tracker in &self.trackers {
+                                    self.socket.send_to(msg, tracker).await.ok();
+                                }
                             }
-                            tx.send(()).ok();
+                            tx.send(res.map(|_| ())).ok();
+                        }
+                        Ok(UdpActorMessage::Unannounce { unannounce, tx }) => {
+                            // stop announcing, otherwise the periodic announces would undo the unannounce
+                            self.announces.clear();
+                            let res = encode_datagram(&Request::Unannounce(unannounce), &mut buf);
+                            if let Ok(msg) = &res {
+                                for tracker in &self.trackers {
+                                    self.socket.send_to(msg, tracker).await.ok();
+                                }
+                            }
+                            tx.send(res.map(|_| ())).ok();
                         }
                         Err(flume::RecvError::Disconnected) => break,
                     }
@@ -566,20 +2239,21 @@ impl UdpActor {
                                 }
                                 let mut queries_to_remove = Vec::new();
                                 for (query, senders) in self.listeners.iter_mut() {
-                                    if sa.announce.content == query.content {
+                                    if sa.announce.content == query.content && sa.announce.namespace == query.namespace {
                                         if sa.announce.kind == AnnounceKind::Partial && query.flags.complete {
                                             // We only want complete announces, so we skip partial ones.
                                             continue;
                                         }
-                                        let mut to_remove = Vec::new();
-                                        for (i, sender) in senders.iter().enumerate() {
-                                            if sender.send_async(sa).await.is_err() {
-                                                to_remove.push(i);
-                                            }
+                                        if query.flags.min_class.is_some_and(|min| sa.announce.class < min) {
+                                            continue;
                                         }
-                                        for i in to_remove.into_iter().rev() {
-                                            senders.remove(i);
+                                        let mut keep = Vec::with_capacity(senders.len());
+                                        for listener in senders.drain(..) {
+                                            if listener.deliver(sa.clone()).await {
+                                                keep.push(listener);
+                                            }
                                         }
+                                        *senders = keep;
                                     }
                                     if senders.is_empty() {
                                         queries_to_remove.push(*query);
@@ -597,3 +2271,330 @@ impl UdpActor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+    use crate::{
+        protocol::{AbsoluteTime, AnnounceMetadata, UnsupportedRequest, MAX_MIME_TYPE_LEN},
+        testing::{test_node_id, AnnounceBuilder, QueryResponseBuilder},
+    };
+
+    /// Yield to the executor once, like a tracker that is slow to respond.
+    async fn yield_now() {
+        let mut yielded = false;
+        future::poll_fn(move |cx| {
+            if yielded {
+                Poll::Ready(())
+            } else {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    #[test]
+    fn verify_proof_checks_hashes() {
+        // a single chunk, so the proof is just the data
+        let data = (0..1000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut proof = VerificationProof {
+            size: data.len() as u64,
+            chunk: 0,
+            parents: Vec::new(),
+            data: data.clone(),
+        };
+        verify_proof(Hash::new(&data), &proof).unwrap();
+        proof.data[0] ^= 1;
+        assert!(verify_proof(Hash::new(&data), &proof).is_err());
+
+        // five chunks, proving the partial last chunk below the root and one parent
+        let data = (0..4 * 1024 + 100u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (left, right) = data.split_at(4 * 1024);
+        let parents = vec![(
+            Hash::from(subtree_cv(left, 0, false)),
+            Hash::from(subtree_cv(right, 4, false)),
+        )];
+        let mut proof = VerificationProof {
+            size: data.len() as u64,
+            chunk: 4,
+            parents: parents.clone(),
+            data: right.to_vec(),
+        };
+        verify_proof(Hash::new(&data), &proof).unwrap();
+        proof.chunk = 2;
+        assert!(verify_proof(Hash::new(&data), &proof).is_err());
+
+        // a chunk in the left subtree needs the parents all the way down
+        let (chunks01, chunks23) = left.split_at(2 * 1024);
+        let (chunk2, chunk3) = chunks23.split_at(1024);
+        let parents = vec![
+            parents[0],
+            (
+                Hash::from(subtree_cv(chunks01, 0, false)),
+                Hash::from(subtree_cv(chunks23, 2, false)),
+            ),
+            (
+                Hash::from(subtree_cv(chunk2, 2, false)),
+                Hash::from(subtree_cv(chunk3, 3, false)),
+            ),
+        ];
+        let mut proof = VerificationProof {
+            size: data.len() as u64,
+            chunk: 3,
+            parents,
+            data: chunk3.to_vec(),
+        };
+        verify_proof(Hash::new(&data), &proof).unwrap();
+        proof.parents.pop();
+        assert!(verify_proof(Hash::new(&data), &proof).is_err());
+    }
+
+    #[test]
+    fn dht_query_stats_record() {
+        let mut stats = DhtQueryStats::default();
+        assert_eq!(stats.hit_rate(), 0.0);
+        stats.record(Some(0), Duration::from_millis(10));
+        stats.record(None, Duration::from_millis(20));
+        stats.record(Some(3), Duration::from_millis(30));
+        stats.record(Some(1), Duration::from_millis(40));
+        assert_eq!(stats.trackers, 4);
+        assert_eq!(stats.trackers_with_hosts, 2);
+        assert_eq!(stats.trackers_failed, 1);
+        assert_eq!(stats.hosts, 4);
+        assert_eq!(stats.hit_rate(), 0.5);
+        assert_eq!(stats.time_to_first_host, Some(Duration::from_millis(30)));
+        assert_eq!(stats.elapsed, Duration::from_millis(40));
+    }
+
+    #[test]
+    fn unsupported_request_is_surfaced() {
+        let unsupported = UnsupportedRequest { variant: 9 };
+        let response = Response::Error(ErrorResponse::UnsupportedRequest(unsupported));
+        let err = unexpected_response(response);
+        assert_eq!(err.downcast_ref::<UnsupportedRequest>(), Some(&unsupported));
+        let err = unexpected_response(Response::TopContent(Vec::new()));
+        assert!(err.downcast_ref::<UnsupportedRequest>().is_none());
+    }
+
+    #[test]
+    fn append_page_combines_hosts() {
+        let mut first = QueryResponseBuilder::new()
+            .host(AnnounceBuilder::new(1).sign())
+            .verified(test_node_id(1))
+            .build();
+        first.cache_for = Some(Duration::from_secs(10));
+        let mut second = QueryResponseBuilder::new()
+            .host(AnnounceBuilder::new(2).sign())
+            .stale(test_node_id(2))
+            .build();
+        second.cache_for = Some(Duration::from_secs(5));
+        append_page(&mut first, second);
+        let hosts = first.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>();
+        assert_eq!(hosts, vec![test_node_id(1), test_node_id(2)]);
+        assert_eq!(first.verified, vec![test_node_id(1)]);
+        assert_eq!(first.stale, vec![test_node_id(2)]);
+        assert_eq!(first.cache_for, Some(Duration::from_secs(5)));
+        assert_eq!(first.next_cursor, None);
+    }
+
+    #[test]
+    fn send_bounded_does_not_buffer_batch() {
+        let (taken, acked, max_buffered) = (&Cell::new(0), &Cell::new(0), &Cell::new(0));
+        let items = (0..100).inspect(|_| taken.set(taken.get() + 1));
+        let count = futures::executor::block_on(send_bounded(items, 4, move |_| async move {
+            max_buffered.set(max_buffered.get().max(taken.get() - acked.get()));
+            yield_now().await;
+            acked.set(acked.get() + 1);
+            Ok(())
+        }))
+        .unwrap();
+        assert_eq!(count, 100);
+        assert!(max_buffered.get() <= 4);
+    }
+
+    #[test]
+    fn fan_out_is_bounded_and_fastest_first() {
+        // mock trackers that answer slower the earlier they are in the list
+        let latency = |tracker: u64| Duration::from_millis(4 * (20 - tracker));
+        let (in_flight, max_in_flight) = (&Cell::new(0), &Cell::new(0));
+        let completed = &Cell::new(0);
+        let trackers = futures::stream::iter(0..20u64);
+        let results = fan_out(trackers, 4, move |tracker| async move {
+            in_flight.set(in_flight.get() + 1);
+            max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+            tokio::time::sleep(latency(tracker)).await;
+            in_flight.set(in_flight.get() - 1);
+            // the position of this tracker in completion order
+            let position = completed.get();
+            completed.set(position + 1);
+            position
+        });
+        // the clock only advances when all trackers are waiting, so the completion
+        // order is exactly the order of the latencies
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let results = runtime.block_on(results.collect::<Vec<_>>());
+        assert_eq!(results.len(), 20);
+        assert_eq!(max_in_flight.get(), 4);
+        // results are yielded as soon as they complete
+        let positions = results.iter().map(|(_, position)| *position);
+        assert!(positions.eq(0..20));
+        // so the fastest of the first four comes first, not the first one
+        assert_eq!(results[0].0, 3);
+    }
+
+    #[test]
+    fn resubscribe_does_not_repeat_hosts() {
+        // the hosts returned by each query, per connection. queries beyond the
+        // script fail, like a dropped connection.
+        let script = [vec![vec![1, 2], vec![1, 2, 3]], vec![vec![1, 2, 3, 4]]];
+        let connects = Cell::new(0);
+        let steps = RefCell::new(BTreeMap::<usize, usize>::new());
+        let events = resubscribe(
+            || {
+                let connection = connects.get();
+                connects.set(connection + 1);
+                future::ready(anyhow::Ok(connection))
+            },
+            |connection: &usize| {
+                let mut steps = steps.borrow_mut();
+                let step = steps.entry(*connection).or_default();
+                let res = script
+                    .get(*connection)
+                    .and_then(|queries| queries.get(*step))
+                    .map(|seeds| seeds.iter().map(|seed| AnnounceBuilder::new(*seed).sign()))
+                    .map(|hosts| hosts.collect::<Vec<_>>())
+                    .ok_or_else(|| anyhow::anyhow!("connection lost"));
+                *step += 1;
+                future::ready(res)
+            },
+            Duration::from_millis(1),
+        );
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let events = runtime.block_on(events.take(5).collect::<Vec<_>>());
+        let hosts = events
+            .iter()
+            .filter_map(|event| match event {
+                SubscribeEvent::Host(sa) => Some(sa.host),
+                SubscribeEvent::Resynced => None,
+            })
+            .collect::<Vec<_>>();
+        let expected = (1..=4).map(test_node_id).collect::<Vec<_>>();
+        assert_eq!(hosts, expected);
+        assert!(matches!(events[4], SubscribeEvent::Resynced));
+        assert_eq!(connects.get(), 2);
+    }
+
+    /// A runtime with a paused clock, so waiting for a receiver takes no real time.
+    fn paused_runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn listener_overflows_for_slow_consumer() {
+        paused_runtime().block_on(async {
+            let (listener, rx) = Listener::new(2);
+            assert!(listener.deliver(AnnounceBuilder::new(1).sign()).await);
+            assert!(listener.deliver(AnnounceBuilder::new(2).sign()).await);
+            assert_eq!(rx.len(), 2);
+            // the consumer did not receive anything, so the buffer stays full
+            let start = tokio::time::Instant::now();
+            assert!(!listener.deliver(AnnounceBuilder::new(3).sign()).await);
+            assert_eq!(start.elapsed(), QUERY_BACKPRESSURE_TIMEOUT);
+            assert_eq!(rx.len(), 2);
+            drop(listener);
+            let received = rx.into_stream().collect::<Vec<_>>().await;
+            assert_eq!(received.len(), 3);
+            assert_eq!(received[0].as_ref().unwrap().host, test_node_id(1));
+            assert_eq!(received[1].as_ref().unwrap().host, test_node_id(2));
+            assert_eq!(
+                received[2].as_ref().unwrap_err(),
+                &BufferOverflow { capacity: 2 }
+            );
+        });
+    }
+
+    #[test]
+    fn listener_waits_for_consumer() {
+        paused_runtime().block_on(async {
+            let (listener, rx) = Listener::new(1);
+            let delay = QUERY_BACKPRESSURE_TIMEOUT / 2;
+            let consumer = tokio::spawn(async move {
+                let mut hosts = Vec::new();
+                while let Ok(res) = rx.recv_async().await {
+                    tokio::time::sleep(delay).await;
+                    hosts.push(res.unwrap().host);
+                }
+                hosts
+            });
+            let start = tokio::time::Instant::now();
+            for seed in 0..4 {
+                assert!(listener.deliver(AnnounceBuilder::new(seed).sign()).await);
+            }
+            // the consumer takes a host per delay, so delivering waited instead of failing
+            assert_eq!(start.elapsed(), delay * 2);
+            drop(listener);
+            let hosts = consumer.await.unwrap();
+            assert_eq!(hosts, (0..4).map(test_node_id).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn listener_stops_without_consumer() {
+        paused_runtime().block_on(async {
+            let (listener, rx) = Listener::new(1);
+            drop(rx);
+            assert!(!listener.deliver(AnnounceBuilder::new(1).sign()).await);
+        });
+    }
+
+    #[test]
+    fn largest_announce_fits_into_a_datagram() {
+        let metadata = AnnounceMetadata {
+            mime_type: Some("x".repeat(MAX_MIME_TYPE_LEN)),
+        };
+        let announce = AnnounceBuilder::new(1)
+            .metadata(metadata)
+            .namespace(Some(Namespace::from_name("test")))
+            .committed_until(Some(AbsoluteTime::now()))
+            .sign();
+        let mut buf = [0u8; MAX_MSG_SIZE];
+        encode_datagram(&Request::Announce(announce), &mut buf).unwrap();
+    }
+
+    #[test]
+    fn oversized_announce_is_an_error() {
+        // SignedAnnounce::new would reject the mime type, so set it after signing
+        let mut announce = AnnounceBuilder::new(1).sign();
+        announce.announce.metadata.mime_type = Some("x".repeat(MAX_MSG_SIZE));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let discovery = UdpDiscovery::new("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            assert!(discovery.announce_once(announce).await.is_err());
+            // the actor survived
+            let announce = AnnounceBuilder::new(1).sign();
+            assert!(discovery.announce_once(announce).await.is_ok());
+        });
+    }
+}
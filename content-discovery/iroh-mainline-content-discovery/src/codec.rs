@@ -0,0 +1,201 @@
+//! Wire encoding of the tracker protocol messages.
+//!
+//! By default, requests and responses are encoded with postcard. Embedders that
+//! need a different wire format can implement [Codec] and use the `_with_codec`
+//! variants of the client functions. Both sides must agree on the codec. It is not
+//! negotiated, so a tracker network with a different codec should also use its own
+//! ALPN, see [crate::protocol::validate_alpn]. Then a client with the wrong codec
+//! fails to connect instead of misinterpreting messages.
+//!
+//! Note that this only affects the wire format. Signatures are always computed over
+//! the postcard encoding, so signed messages can be passed between codecs.
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::protocol::{Request, UnsupportedRequest};
+
+/// An encoding for the tracker protocol messages.
+pub trait Codec: std::fmt::Debug + Send + Sync {
+    /// Encode a message.
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>>;
+
+    /// Decode a message.
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T>;
+
+    /// The index of the variant of an encoded [Request], without decoding it.
+    ///
+    /// This is used to tell requests the tracker does not know from malformed ones,
+    /// see [UnsupportedRequest]. Codecs that can not tell return `None`, then unknown
+    /// requests are just decoding errors.
+    fn request_variant(&self, _data: &[u8]) -> Option<u32> {
+        None
+    }
+}
+
+/// The default codec, using postcard.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+impl Codec for Postcard {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(postcard::from_bytes(data)?)
+    }
+
+    fn request_variant(&self, data: &[u8]) -> Option<u32> {
+        // postcard encodes the variant index as a varint before the fields
+        postcard::take_from_bytes::<u32>(data)
+            .ok()
+            .map(|(variant, _)| variant)
+    }
+}
+
+/// Encode a message as a frame, prefixed with its length as a big endian u32.
+///
+/// Frames are used where a stream carries multiple messages, such as the responses
+/// to a [Request::Subscribe].
+pub fn encode_frame<T: Serialize>(codec: &impl Codec, value: &T) -> anyhow::Result<Vec<u8>> {
+    let data = codec.encode(value)?;
+    let len = u32::try_from(data.len())?;
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+/// A request without any data.
+///
+/// This happens when a client finishes a stream without writing a request, e.g.
+/// because it gave up before sending. It is not a decoding error, so trackers can
+/// tell it apart from a malformed request and close the stream cleanly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmptyRequest;
+
+impl std::fmt::Display for EmptyRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "empty request")
+    }
+}
+
+impl std::error::Error for EmptyRequest {}
+
+/// Decode a request, failing with [EmptyRequest] if there is no data.
+///
+/// Fails with [UnsupportedRequest] if the variant is not known, as far as the codec
+/// can tell.
+pub fn decode_request(codec: &impl Codec, data: &[u8]) -> anyhow::Result<Request> {
+    decode_supported_request(codec, data, Request::VARIANTS)
+}
+
+/// Decode a request, only supporting the first `supported` variants of [Request].
+///
+/// See [decode_request]. This is for trackers that deliberately don't implement
+/// the newest requests.
+pub fn decode_supported_request(
+    codec: &impl Codec,
+    data: &[u8],
+    supported: u32,
+) -> anyhow::Result<Request> {
+    if data.is_empty() {
+        return Err(EmptyRequest.into());
+    }
+    match codec.request_variant(data) {
+        Some(variant) if variant >= supported => Err(UnsupportedRequest { variant }.into()),
+        _ => codec.decode(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_blobs::HashAndFormat;
+
+    use super::*;
+    use crate::{
+        protocol::{AbsoluteTime, AnnounceSet, QueryBatch, Refresh, SignedRefresh},
+        testing::{test_hash, test_secret_key, QueryBuilder},
+    };
+
+    fn subscribe_request() -> Request {
+        Request::Subscribe(QueryBuilder::new(HashAndFormat::raw(test_hash(1))).build())
+    }
+
+    /// The number of variants before [Request::Subscribe] was added.
+    const BEFORE_SUBSCRIBE: u32 = 6;
+
+    #[test]
+    fn frame_has_length_prefix() {
+        let frame = encode_frame(&Postcard, &Request::TopContent(7)).unwrap();
+        let data = Postcard.encode(&Request::TopContent(7)).unwrap();
+        assert_eq!(frame[..4], (data.len() as u32).to_be_bytes());
+        assert_eq!(frame[4..], data);
+    }
+
+    #[test]
+    fn empty_request() {
+        let err = decode_request(&Postcard, &[]).unwrap_err();
+        assert_eq!(err.downcast_ref::<EmptyRequest>(), Some(&EmptyRequest));
+    }
+
+    #[test]
+    fn variants_are_counted() {
+        let data = Postcard.encode(&Request::TopContent(0)).unwrap();
+        assert_eq!(Postcard.request_variant(&data), Some(5));
+        let data = Postcard.encode(&subscribe_request()).unwrap();
+        assert_eq!(Postcard.request_variant(&data), Some(BEFORE_SUBSCRIBE));
+        let batch = QueryBatch {
+            content: Vec::new(),
+            flags: QueryBuilder::new(HashAndFormat::raw(test_hash(1)))
+                .build()
+                .flags,
+            namespace: None,
+        };
+        let data = Postcard.encode(&Request::QueryBatch(batch)).unwrap();
+        assert_eq!(Postcard.request_variant(&data), Some(8));
+        let set = AnnounceSet {
+            announces: Vec::new(),
+            atomic: true,
+        };
+        let data = Postcard.encode(&Request::AnnounceSet(set)).unwrap();
+        assert_eq!(Postcard.request_variant(&data), Some(9));
+        // the last variant
+        let admin = test_secret_key(1);
+        let refresh = Refresh {
+            admin: admin.public(),
+            timestamp: AbsoluteTime::now(),
+            content: HashAndFormat::raw(test_hash(1)),
+            namespace: None,
+        };
+        let refresh = SignedRefresh::new(refresh, &admin).unwrap();
+        let data = Postcard.encode(&Request::Refresh(refresh)).unwrap();
+        assert_eq!(Postcard.request_variant(&data), Some(Request::VARIANTS - 1));
+    }
+
+    #[test]
+    fn unsupported_request() {
+        // a new client talking to a tracker that predates subscriptions
+        let old = BEFORE_SUBSCRIBE;
+        let data = Postcard.encode(&subscribe_request()).unwrap();
+        let err = decode_supported_request(&Postcard, &data, old).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsupportedRequest>(),
+            Some(&UnsupportedRequest {
+                variant: BEFORE_SUBSCRIBE
+            })
+        );
+        // requests the old tracker knows still work
+        let data = Postcard.encode(&Request::TopContent(3)).unwrap();
+        let request = decode_supported_request(&Postcard, &data, old).unwrap();
+        assert!(matches!(request, Request::TopContent(3)));
+        // a variant no tracker knows yet
+        let err = decode_request(&Postcard, &[Request::VARIANTS as u8]).unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedRequest>().is_some());
+    }
+
+    #[test]
+    fn malformed_request() {
+        let err = decode_request(&Postcard, &[0xff]).unwrap_err();
+        assert!(err.downcast_ref::<EmptyRequest>().is_none());
+    }
+}
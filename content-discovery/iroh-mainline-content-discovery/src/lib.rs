@@ -5,6 +5,15 @@
 //! connections.
 #[cfg(feature = "client")]
 mod client;
+#[cfg(feature = "client")]
+pub mod codec;
+pub mod mime;
 pub mod protocol;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+#[cfg(feature = "client")]
+mod tracker_client;
 #[cfg(feature = "client")]
 pub use client::*;
+#[cfg(feature = "client")]
+pub use tracker_client::*;
@@ -0,0 +1,142 @@
+//! Best effort MIME type inference for announced content.
+//!
+//! This is not meant to be exhaustive. It covers common formats so that content
+//! browsers can show icons or filter by type. If the type can not be inferred,
+//! the announce simply carries no MIME type.
+use std::{io::Read, path::Path};
+
+/// Magic byte prefixes and the MIME types they identify.
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"\x7fELF", "application/x-elf"),
+    (b"\x00asm", "application/wasm"),
+];
+
+/// File extensions and the MIME types they identify.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("toml", "application/toml"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("wasm", "application/wasm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+];
+
+/// The number of bytes we need to look at to detect all formats in [MAGIC].
+const MAGIC_LEN: usize = 16;
+
+/// Infer the MIME type from the first bytes of some data.
+pub fn mime_type_from_magic(data: &[u8]) -> Option<&'static str> {
+    MAGIC
+        .iter()
+        .find(|(magic, _)| data.starts_with(magic))
+        .map(|(_, mime_type)| *mime_type)
+}
+
+/// Infer the MIME type from the extension of a path.
+pub fn mime_type_from_extension(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+/// Infer the MIME type of a local file.
+///
+/// This looks at the magic bytes first, and falls back to the extension.
+/// Returns `None` if neither gives a result.
+pub fn infer_mime_type(path: impl AsRef<Path>) -> std::io::Result<Option<String>> {
+    let path = path.as_ref();
+    let mut data = Vec::with_capacity(MAGIC_LEN);
+    std::fs::File::open(path)?
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut data)?;
+    let mime_type = mime_type_from_magic(&data).or_else(|| mime_type_from_extension(path));
+    Ok(mime_type.map(ToOwned::to_owned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_bytes() {
+        assert_eq!(
+            mime_type_from_magic(b"\x89PNG\r\n\x1a\n rest of the image"),
+            Some("image/png")
+        );
+        assert_eq!(mime_type_from_magic(b"%PDF-1.7"), Some("application/pdf"));
+        assert_eq!(mime_type_from_magic(b"GIF89a"), Some("image/gif"));
+        // a prefix of a magic is not enough
+        assert_eq!(mime_type_from_magic(b"\x89PN"), None);
+        assert_eq!(mime_type_from_magic(b"hello world"), None);
+        assert_eq!(mime_type_from_magic(b""), None);
+    }
+
+    #[test]
+    fn extension() {
+        let mime = |path: &str| mime_type_from_extension(Path::new(path));
+        assert_eq!(mime("notes.md"), Some("text/markdown"));
+        assert_eq!(mime("dir/archive.tar.gz"), Some("application/gzip"));
+        // extensions are case insensitive
+        assert_eq!(mime("PHOTO.JPG"), Some("image/jpeg"));
+        assert_eq!(mime("unknown.xyz"), None);
+        assert_eq!(mime("no_extension"), None);
+    }
+
+    #[test]
+    fn magic_takes_precedence_over_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        // a png with the wrong extension
+        let png = dir.path().join("image.txt");
+        std::fs::write(&png, b"\x89PNG\r\n\x1a\n").unwrap();
+        assert_eq!(infer_mime_type(&png).unwrap().as_deref(), Some("image/png"));
+        // no magic, so the extension decides
+        let text = dir.path().join("notes.txt");
+        std::fs::write(&text, b"just some text").unwrap();
+        assert_eq!(
+            infer_mime_type(&text).unwrap().as_deref(),
+            Some("text/plain")
+        );
+        let unknown = dir.path().join("data.bin");
+        std::fs::write(&unknown, b"\x00\x01\x02").unwrap();
+        assert_eq!(infer_mime_type(&unknown).unwrap(), None);
+    }
+}
@@ -1,19 +1,48 @@
 //! The protocol for communicating with the tracker.
 use std::{
-    ops::{Deref, Sub},
+    collections::{BTreeMap, BTreeSet},
+    ops::{Add, Deref, Sub},
     time::{Duration, SystemTime},
 };
 
-use iroh_blobs::HashAndFormat;
-use iroh_net::NodeId;
+use iroh_base::ticket::BlobTicket;
+use iroh_blobs::{store::EntryStatus, BlobFormat, HashAndFormat};
+use iroh_net::{relay::RelayUrl, NodeAddr, NodeId};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
 /// The ALPN string for this protocol
 pub const ALPN: &[u8] = b"n0/tracker/1";
+
+/// Check that an ALPN can be used for the tracker protocol instead of [ALPN].
+///
+/// A separate ALPN keeps a private tracker network apart from the public one on the
+/// same infrastructure. It must be 1 to 255 bytes of printable ASCII, e.g.
+/// `myorg/tracker/1`.
+pub fn validate_alpn(alpn: &[u8]) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (1..=255).contains(&alpn.len()),
+        "alpn must be 1 to 255 bytes, got {}",
+        alpn.len()
+    );
+    anyhow::ensure!(
+        alpn.iter().all(|b| b.is_ascii_graphic()),
+        "alpn must be printable ascii"
+    );
+    Ok(())
+}
 /// Maximum size of a request
 pub const REQUEST_SIZE_LIMIT: usize = 1024 * 16;
 
+/// Maximum size of a response, or of a frame of a response.
+///
+/// This is larger than [REQUEST_SIZE_LIMIT], since responses can contain many hosts
+/// and up to [MAX_PROOFS_PER_RESPONSE] proofs.
+pub const RESPONSE_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// Maximum number of [VerificationProof]s in a single [QueryResponse].
+pub const MAX_PROOFS_PER_RESPONSE: usize = 16;
+
 /// Announce kind
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AnnounceKind {
@@ -31,6 +60,119 @@ impl AnnounceKind {
             Self::Partial
         }
     }
+
+    /// The announce kind for a blob with the given status in a local store.
+    ///
+    /// Returns `None` if the store does not have the blob, so there is nothing to announce.
+    pub fn from_store_state(status: EntryStatus) -> Option<Self> {
+        match status {
+            EntryStatus::Complete => Some(Self::Complete),
+            EntryStatus::Partial => Some(Self::Partial),
+            EntryStatus::NotFound => None,
+        }
+    }
+
+    /// The store status that corresponds to this announce kind.
+    pub fn to_store_state(self) -> EntryStatus {
+        match self {
+            Self::Complete => EntryStatus::Complete,
+            Self::Partial => EntryStatus::Partial,
+        }
+    }
+}
+
+/// How available a host intends to keep the content, as declared by the host itself.
+///
+/// This is advisory. A host can claim to be a mirror without being one, so combine
+/// it with verification before trusting it. Classes are ordered from least to most
+/// available, so queries can ask for a minimum class.
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum SeedClass {
+    /// A peer that will likely go away soon, e.g. after finishing a download.
+    Transient = 0,
+    /// A regular peer.
+    #[default]
+    Peer,
+    /// A dedicated host that intends to keep the content available.
+    Mirror,
+}
+
+impl std::fmt::Display for SeedClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transient => write!(f, "transient"),
+            Self::Peer => write!(f, "peer"),
+            Self::Mirror => write!(f, "mirror"),
+        }
+    }
+}
+
+impl std::str::FromStr for SeedClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transient" => Ok(Self::Transient),
+            "peer" => Ok(Self::Peer),
+            "mirror" => Ok(Self::Mirror),
+            _ => anyhow::bail!(
+                "invalid seed class {}, expected transient, peer or mirror",
+                s
+            ),
+        }
+    }
+}
+
+/// How the tracker verified that a host has the content.
+///
+/// Methods are ordered from weakest to strongest, so queries can ask for a minimum
+/// method with [QueryFlags::require_method].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VerificationMethod {
+    /// The host returned a plausible size for the data.
+    ///
+    /// This is how hosts that announced partial data are verified.
+    Size = 0,
+    /// The host returned a random range of the data, which was validated.
+    ///
+    /// This is how hosts that announced complete data are verified.
+    RandomRange,
+}
+
+impl VerificationMethod {
+    /// The method the tracker uses to verify an announce of the given kind.
+    pub fn for_kind(kind: AnnounceKind) -> Self {
+        match kind {
+            AnnounceKind::Partial => Self::Size,
+            AnnounceKind::Complete => Self::RandomRange,
+        }
+    }
+}
+
+impl std::fmt::Display for VerificationMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Size => write!(f, "size"),
+            Self::RandomRange => write!(f, "random-range"),
+        }
+    }
+}
+
+impl std::str::FromStr for VerificationMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(Self::Size),
+            "random-range" => Ok(Self::RandomRange),
+            _ => anyhow::bail!(
+                "invalid verification method {}, expected size or random-range",
+                s
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord)]
@@ -50,11 +192,25 @@ impl AbsoluteTime {
     }
 }
 
+/// The time a duration after `self`, saturating at the end of time.
+impl Add<Duration> for AbsoluteTime {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        let micros = u64::try_from(rhs.as_micros()).unwrap_or(u64::MAX);
+        Self(self.0.saturating_add(micros))
+    }
+}
+
+/// The duration from `rhs` to `self`, or zero if `rhs` is later.
+///
+/// Times from different clocks can be in either order, so this saturates instead of
+/// panicking.
 impl Sub for AbsoluteTime {
     type Output = Duration;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        Duration::from_micros(self.0 - rhs.0)
+        Duration::from_micros(self.0.saturating_sub(rhs.0))
     }
 }
 
@@ -79,11 +235,48 @@ impl From<AbsoluteTime> for SystemTime {
     }
 }
 
+/// A namespace to partition content on a shared tracker.
+///
+/// Applications that share a tracker, but should not see each other's hosts, use
+/// different namespaces. See [Namespace::from_name] to derive one from a name.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Namespace(pub [u8; 32]);
+
+impl Namespace {
+    /// Derive a namespace from a name, by hashing it.
+    pub fn from_name(name: &str) -> Self {
+        Self(*iroh_blobs::Hash::new(name).as_bytes())
+    }
+}
+
+impl std::fmt::Display for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// The maximum length of [AnnounceMetadata::mime_type], in bytes.
+///
+/// This keeps a signed announce small enough for a single UDP datagram.
+pub const MAX_MIME_TYPE_LEN: usize = 255;
+
+/// Additional information about announced content.
+///
+/// This is provided by the host and not checked by the tracker, so treat it
+/// as a hint.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AnnounceMetadata {
+    /// The MIME type of the content, if known.
+    ///
+    /// At most [MAX_MIME_TYPE_LEN] bytes.
+    pub mime_type: Option<String>,
+}
+
 /// Announce that a peer claims to have some blobs or set of blobs.
 ///
 /// A peer can announce having some data, but it should also be able to announce
 /// that another peer has the data. This is why the peer is included.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Announce {
     /// The peer that supposedly has the data.
     pub host: NodeId,
@@ -93,10 +286,100 @@ pub struct Announce {
     pub kind: AnnounceKind,
     /// The timestamp of the announce.
     pub timestamp: AbsoluteTime,
+    /// Metadata about the content.
+    pub metadata: AnnounceMetadata,
+    /// The namespace of the announce.
+    ///
+    /// Announces are only returned for queries in the same namespace. `None` is the
+    /// default namespace.
+    pub namespace: Option<Namespace>,
+    /// How available the host intends to keep the content.
+    ///
+    /// This is self-reported by the host, see [SeedClass].
+    pub class: SeedClass,
+    /// The host commits to serving the content until this time.
+    ///
+    /// The commitment is signed with the announce, so it proves that the host made
+    /// the promise. Keeping it is up to the host, nothing enforces it. See
+    /// [SignedAnnounce::is_committed] and [QueryFlags::prefer_committed].
+    pub committed_until: Option<AbsoluteTime>,
+}
+
+/// Check a set of announces that are sent together, and remove exact duplicates.
+///
+/// Content is identified by hash *and* format, so announcing a hash both as a raw
+/// blob and as a hash seq is two distinct claims, and both are kept. Announces for
+/// the same content and kind are duplicates, only the first one is kept.
+///
+/// It is an error to announce the same content as both complete and partial, or to
+/// mix announces for different hosts in one set.
+pub fn dedup_announces(announces: Vec<Announce>) -> anyhow::Result<Vec<Announce>> {
+    let mut kinds = std::collections::BTreeMap::new();
+    let mut res: Vec<Announce> = Vec::with_capacity(announces.len());
+    for announce in announces {
+        if let Some(first) = res.first() {
+            anyhow::ensure!(
+                first.host == announce.host,
+                "announces for different hosts {} and {}",
+                first.host,
+                announce.host
+            );
+        }
+        match kinds.get(&announce.content) {
+            None => {
+                kinds.insert(announce.content, announce.kind);
+                res.push(announce);
+            }
+            Some(kind) if *kind == announce.kind => {}
+            Some(kind) => anyhow::bail!(
+                "contradictory announces for {}: both {:?} and {:?}",
+                announce.content,
+                kind,
+                announce.kind
+            ),
+        }
+    }
+    Ok(res)
+}
+
+/// The key under which content is announced and queried.
+///
+/// Two ways to refer to content are equivalent if they have the same hash and the
+/// same format. Everything else is addressing and not part of the key, e.g. the node
+/// addr of a ticket. A bare hash refers to a raw blob.
+///
+/// Announces and queries carry only a [HashAndFormat] on the wire, so the tracker
+/// matches them by that alone. Clients should build both from the input the user
+/// gave with this trait, so a host that announced via a ticket can be found via the
+/// hash, and vice versa.
+pub trait ContentKey {
+    /// The hash and format of the content, without any addressing.
+    fn content_key(&self) -> HashAndFormat;
+}
+
+impl ContentKey for HashAndFormat {
+    fn content_key(&self) -> HashAndFormat {
+        *self
+    }
+}
+
+impl ContentKey for iroh_blobs::Hash {
+    fn content_key(&self) -> HashAndFormat {
+        HashAndFormat::raw(*self)
+    }
+}
+
+impl ContentKey for BlobTicket {
+    fn content_key(&self) -> HashAndFormat {
+        HashAndFormat {
+            hash: self.hash(),
+            format: self.format(),
+        }
+    }
 }
 
 /// A signed announce.
-#[derive(derive_more::Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(derive_more::Debug, Clone, Serialize, Deserialize)]
 pub struct SignedAnnounce {
     /// Announce.
     pub announce: Announce,
@@ -118,7 +401,17 @@ impl Deref for SignedAnnounce {
 
 impl SignedAnnounce {
     /// Create a new signed announce.
+    ///
+    /// Fails if the MIME type is longer than [MAX_MIME_TYPE_LEN].
     pub fn new(announce: Announce, secret_key: &iroh_net::key::SecretKey) -> anyhow::Result<Self> {
+        if let Some(mime_type) = &announce.metadata.mime_type {
+            anyhow::ensure!(
+                mime_type.len() <= MAX_MIME_TYPE_LEN,
+                "mime type is {} bytes, the maximum is {}",
+                mime_type.len(),
+                MAX_MIME_TYPE_LEN
+            );
+        }
         let announce_bytes = postcard::to_allocvec(&announce)?;
         let signature = secret_key.sign(&announce_bytes).to_bytes();
         Ok(Self {
@@ -128,14 +421,225 @@ impl SignedAnnounce {
     }
 
     /// Verify the announce, and return the announce if it's valid.
+    ///
+    /// Besides the signature, this checks that a commitment does not end before the
+    /// announce was made.
     pub fn verify(&self) -> anyhow::Result<()> {
         let announce_bytes = postcard::to_allocvec(&self.announce)?;
         let signature = iroh_net::key::Signature::from_bytes(&self.signature);
         self.announce.host.verify(&announce_bytes, &signature)?;
+        if let Some(until) = self.announce.committed_until {
+            anyhow::ensure!(
+                until > self.announce.timestamp,
+                "commitment ends before the announce"
+            );
+        }
+        Ok(())
+    }
+
+    /// True if the host has committed to serving the content at the given time.
+    pub fn is_committed(&self, now: AbsoluteTime) -> bool {
+        self.announce
+            .committed_until
+            .is_some_and(|until| until > now)
+    }
+}
+
+/// A pre-signed announce that can be submitted by a third party.
+///
+/// A host that is going to be offline can issue tokens for its content while
+/// it is still online, so that e.g. an archival or indexer service can keep
+/// the content discoverable. The tracker will consider the announce fresh until
+/// `valid_until`, even if the host itself does not announce again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnounceToken {
+    /// The signed announce of the host.
+    pub announce: SignedAnnounce,
+    /// The time until which the announce should be considered fresh.
+    pub valid_until: AbsoluteTime,
+}
+
+/// A signed announce token.
+#[derive(derive_more::Debug, Clone, Serialize, Deserialize)]
+pub struct SignedAnnounceToken {
+    /// Announce token.
+    pub token: AnnounceToken,
+    /// Signature of the token, signed by the host of the contained announce.
+    ///
+    /// The signature is over the token, serialized with postcard.
+    #[serde(with = "BigArray")]
+    #[debug("{}", hex::encode(self.signature))]
+    pub signature: [u8; 64],
+}
+
+impl Deref for SignedAnnounceToken {
+    type Target = AnnounceToken;
+
+    fn deref(&self) -> &Self::Target {
+        &self.token
+    }
+}
+
+impl SignedAnnounceToken {
+    /// Create a new signed announce token.
+    ///
+    /// The secret key must be the key of the host of the announce.
+    pub fn new(
+        announce: SignedAnnounce,
+        valid_until: AbsoluteTime,
+        secret_key: &iroh_net::key::SecretKey,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            announce.host == secret_key.public(),
+            "token must be signed by the host of the announce"
+        );
+        let token = AnnounceToken {
+            announce,
+            valid_until,
+        };
+        let token_bytes = postcard::to_allocvec(&token)?;
+        let signature = secret_key.sign(&token_bytes).to_bytes();
+        Ok(Self { token, signature })
+    }
+
+    /// Verify the token, including the contained announce.
+    ///
+    /// Fails if the token is no longer valid at `now`.
+    pub fn verify(&self, now: AbsoluteTime) -> anyhow::Result<()> {
+        self.token.announce.verify()?;
+        anyhow::ensure!(now <= self.token.valid_until, "announce token expired");
+        let token_bytes = postcard::to_allocvec(&self.token)?;
+        let signature = iroh_net::key::Signature::from_bytes(&self.signature);
+        self.token.announce.host.verify(&token_bytes, &signature)?;
+        Ok(())
+    }
+}
+
+/// Retract all announces of a host.
+///
+/// This is used by a host that shuts down, so it does not have to enumerate
+/// everything it has announced. Only announces that are older than the
+/// unannounce are retracted, so an old unannounce can not be replayed to
+/// remove newer announces.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Unannounce {
+    /// The host that retracts its announces.
+    pub host: NodeId,
+    /// The timestamp of the unannounce.
+    pub timestamp: AbsoluteTime,
+}
+
+/// A signed unannounce.
+#[derive(derive_more::Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SignedUnannounce {
+    /// Unannounce.
+    pub unannounce: Unannounce,
+    /// Signature of the unannounce, signed by the host.
+    ///
+    /// The signature is over the unannounce, serialized with postcard.
+    #[serde(with = "BigArray")]
+    #[debug("{}", hex::encode(self.signature))]
+    pub signature: [u8; 64],
+}
+
+impl Deref for SignedUnannounce {
+    type Target = Unannounce;
+
+    fn deref(&self) -> &Self::Target {
+        &self.unannounce
+    }
+}
+
+impl SignedUnannounce {
+    /// Create a new signed unannounce.
+    pub fn new(
+        unannounce: Unannounce,
+        secret_key: &iroh_net::key::SecretKey,
+    ) -> anyhow::Result<Self> {
+        let unannounce_bytes = postcard::to_allocvec(&unannounce)?;
+        let signature = secret_key.sign(&unannounce_bytes).to_bytes();
+        Ok(Self {
+            unannounce,
+            signature,
+        })
+    }
+
+    /// Verify the unannounce.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let unannounce_bytes = postcard::to_allocvec(&self.unannounce)?;
+        let signature = iroh_net::key::Signature::from_bytes(&self.signature);
+        self.unannounce.host.verify(&unannounce_bytes, &signature)?;
         Ok(())
     }
 }
 
+/// Ask a tracker to probe all hosts of some content now.
+///
+/// This is an admin request, the tracker only accepts it if it is signed with the
+/// secret key of the tracker itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Refresh {
+    /// The node that asks for the refresh, which must be the tracker.
+    pub admin: NodeId,
+    /// The timestamp of the refresh.
+    pub timestamp: AbsoluteTime,
+    /// The content to refresh.
+    pub content: HashAndFormat,
+    /// The namespace of the announces to refresh.
+    pub namespace: Option<Namespace>,
+}
+
+/// A signed refresh.
+#[derive(derive_more::Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SignedRefresh {
+    /// Refresh.
+    pub refresh: Refresh,
+    /// Signature of the refresh, signed by the admin.
+    ///
+    /// The signature is over the refresh, serialized with postcard.
+    #[serde(with = "BigArray")]
+    #[debug("{}", hex::encode(self.signature))]
+    pub signature: [u8; 64],
+}
+
+impl Deref for SignedRefresh {
+    type Target = Refresh;
+
+    fn deref(&self) -> &Self::Target {
+        &self.refresh
+    }
+}
+
+impl SignedRefresh {
+    /// Create a new signed refresh.
+    pub fn new(refresh: Refresh, secret_key: &iroh_net::key::SecretKey) -> anyhow::Result<Self> {
+        let refresh_bytes = postcard::to_allocvec(&refresh)?;
+        let signature = secret_key.sign(&refresh_bytes).to_bytes();
+        Ok(Self { refresh, signature })
+    }
+
+    /// Verify the refresh.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let refresh_bytes = postcard::to_allocvec(&self.refresh)?;
+        let signature = iroh_net::key::Signature::from_bytes(&self.signature);
+        self.refresh.admin.verify(&refresh_bytes, &signature)?;
+        Ok(())
+    }
+}
+
+/// The result of a [Request::Refresh].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefreshSummary {
+    /// Hosts that passed all probes, and are now verified.
+    pub verified: Vec<NodeId>,
+    /// Hosts that could be dialed, but failed at least one probe.
+    ///
+    /// Hosts that passed some probes are verified for the announces they passed.
+    pub failing: Vec<NodeId>,
+    /// Hosts that could not be dialed at all.
+    pub unreachable: Vec<NodeId>,
+}
+
 /// Flags for a query.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct QueryFlags {
@@ -152,6 +656,99 @@ pub struct QueryFlags {
     /// In case of a complete query, verification means that the host has been randomly
     /// probed for the data.
     pub verified: bool,
+
+    /// Only return a random sample of at most this many qualifying hosts.
+    ///
+    /// The sample is drawn independently for each request, so different requesters
+    /// get different hosts, which spreads the load for popular content. Sampling is
+    /// without replacement within a response, so a host appears at most once.
+    pub sample: Option<u32>,
+
+    /// Also return hosts whose announce expired at most this long ago.
+    ///
+    /// This is a last resort for content that has no live hosts. Such hosts are
+    /// listed in [QueryResponse::stale]. By default, expired hosts are not returned.
+    pub include_expired: Option<Duration>,
+
+    /// Only return hosts that announced at least this [SeedClass].
+    ///
+    /// The class is self-reported, so this is a preference, not a guarantee.
+    pub min_class: Option<SeedClass>,
+
+    /// Only return hosts that were recently verified by at least this method.
+    ///
+    /// This implies [Self::verified], whatever its value. Since hosts that announced
+    /// partial data are only verified by [VerificationMethod::Size], requiring
+    /// [VerificationMethod::RandomRange] only returns hosts with the complete data,
+    /// even if [Self::complete] is false.
+    pub require_method: Option<VerificationMethod>,
+
+    /// Return at most this many hosts per response, see [Query::cursor].
+    ///
+    /// Pagination does not apply to a [Self::sample], which is drawn from all
+    /// qualifying hosts. It is also not available via UDP, which only gets the first
+    /// page. A page size of 0 is the same as `None`.
+    pub page_size: Option<u32>,
+
+    /// Return hosts with an active seeding commitment first.
+    ///
+    /// See [Announce::committed_until]. Within committed and other hosts, the order is
+    /// kept. With [Self::page_size], only the hosts within each page are reordered.
+    pub prefer_committed: bool,
+
+    /// How much to include per host, see [DetailLevel].
+    pub detail: DetailLevel,
+
+    /// Also return the [VerificationProof]s the tracker got when verifying hosts.
+    ///
+    /// Proofs contain up to 16 KiB of data each, so only ask for them if needed. The
+    /// tracker returns at most [MAX_PROOFS_PER_RESPONSE] proofs per response.
+    pub include_proofs: bool,
+}
+
+/// How much the tracker includes per host in a [QueryResponse].
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum DetailLevel {
+    /// Just the node ids, in [QueryResponse::host_ids].
+    ///
+    /// This is the least data, but the tracker could make up hosts, since there are
+    /// no signed announces to verify.
+    Minimal = 0,
+    /// The signed announces in [QueryResponse::hosts], and the addrs of the hosts as
+    /// far as the tracker knows them.
+    #[default]
+    Standard,
+    /// Like [DetailLevel::Standard], plus the size of the content as verified by the
+    /// tracker in [QueryResponse::content_size].
+    Full,
+}
+
+impl std::fmt::Display for DetailLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Minimal => write!(f, "minimal"),
+            Self::Standard => write!(f, "standard"),
+            Self::Full => write!(f, "full"),
+        }
+    }
+}
+
+impl std::str::FromStr for DetailLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minimal" => Ok(Self::Minimal),
+            "standard" => Ok(Self::Standard),
+            "full" => Ok(Self::Full),
+            _ => anyhow::bail!(
+                "invalid detail level {}, expected minimal, standard or full",
+                s
+            ),
+        }
+    }
 }
 
 /// Query a peer for a blob or set of blobs.
@@ -160,9 +757,122 @@ pub struct Query {
     /// The content we want to find.
     ///
     /// It's a difference if a peer has a blob or a hash seq and all of its children.
+    /// Announces match if they have the same [ContentKey].
     pub content: HashAndFormat,
     /// The mode of the query.
     pub flags: QueryFlags,
+    /// The namespace to query in.
+    ///
+    /// Only announces in the same namespace match. `None` is the default namespace.
+    pub namespace: Option<Namespace>,
+    /// Continue after the host where the last page ended.
+    ///
+    /// This is the [QueryResponse::next_cursor] of the previous page. `None` starts
+    /// with the first page.
+    pub cursor: Option<HostCursor>,
+}
+
+/// Query for multiple items at once, see [Request::QueryBatch].
+///
+/// This is meant for collections, where a downloader wants hosts for every child.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QueryBatch {
+    /// The content we want to find.
+    pub content: Vec<HashAndFormat>,
+    /// The mode of the query, for all items.
+    ///
+    /// [QueryFlags::verified] makes the tracker probe the hosts of all items before
+    /// responding, instead of relying on the results of earlier probes. Each host is
+    /// dialed once, and probed for all items it announced over the same connection.
+    pub flags: QueryFlags,
+    /// The namespace to query in.
+    pub namespace: Option<Namespace>,
+}
+
+/// The hosts for a single item of a [QueryBatch].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BatchQueryItem {
+    /// The content of the item.
+    pub content: HashAndFormat,
+    /// The hosts that announced the content.
+    ///
+    /// For a verified batch, these are only the hosts that passed the probe.
+    pub hosts: Vec<NodeId>,
+    /// The hosts that were probed for the content, and failed.
+    ///
+    /// This is only filled for a verified batch. Hosts that could not be dialed are
+    /// neither in `hosts` nor in `failed`.
+    pub failed: Vec<NodeId>,
+}
+
+/// The position of a host in a paginated query response.
+///
+/// Hosts are returned in a stable order, so a cursor stays valid when hosts are added
+/// or removed between pages. Hosts added before the cursor are not returned.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HostCursor {
+    /// The format of the content of the last announce.
+    pub format: BlobFormat,
+    /// The kind of the last announce.
+    pub kind: AnnounceKind,
+    /// The host of the last announce.
+    pub host: NodeId,
+}
+
+/// A query that is attributed to the node sending it.
+///
+/// Queries over QUIC are already authenticated by the connection. Signing a query
+/// additionally makes it attributable after the fact, e.g. for audit logs of a
+/// tracker operated by a consortium.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AttributedQuery {
+    /// The node that sends the query.
+    pub querier: NodeId,
+    /// The timestamp of the query.
+    pub timestamp: AbsoluteTime,
+    /// The query.
+    pub query: Query,
+}
+
+/// A signed query.
+#[derive(derive_more::Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SignedQuery {
+    /// Attributed query.
+    pub query: AttributedQuery,
+    /// Signature of the query, signed by the querier.
+    ///
+    /// The signature is over the attributed query, serialized with postcard.
+    #[serde(with = "BigArray")]
+    #[debug("{}", hex::encode(self.signature))]
+    pub signature: [u8; 64],
+}
+
+impl Deref for SignedQuery {
+    type Target = AttributedQuery;
+
+    fn deref(&self) -> &Self::Target {
+        &self.query
+    }
+}
+
+impl SignedQuery {
+    /// Create a new signed query.
+    pub fn new(
+        query: AttributedQuery,
+        secret_key: &iroh_net::key::SecretKey,
+    ) -> anyhow::Result<Self> {
+        let query_bytes = postcard::to_allocvec(&query)?;
+        let signature = secret_key.sign(&query_bytes).to_bytes();
+        Ok(Self { query, signature })
+    }
+
+    /// Verify the query.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let query_bytes = postcard::to_allocvec(&self.query)?;
+        let signature = iroh_net::key::Signature::from_bytes(&self.signature);
+        self.query.querier.verify(&query_bytes, &signature)?;
+        Ok(())
+    }
 }
 
 /// A response to a query.
@@ -170,9 +880,270 @@ pub struct Query {
 pub struct QueryResponse {
     /// The hosts that supposedly have the content.
     ///
-    /// If there are any addrs, they are as seen from the tracker,
-    /// so they might or might not be useful.
+    /// This is empty for a query with [DetailLevel::Minimal], see [Self::host_ids].
     pub hosts: Vec<SignedAnnounce>,
+
+    /// The node ids of the hosts, only for a query with [DetailLevel::Minimal].
+    pub host_ids: Vec<NodeId>,
+
+    /// Addresses for some of the hosts.
+    ///
+    /// The addrs are as seen from the tracker, so they might or might not be useful.
+    /// They are not signed by the host, so treat them as a hint. Hosts the tracker
+    /// has no addrs for have no entry.
+    pub node_addrs: Vec<NodeAddr>,
+
+    /// The hosts that have recently been verified by the tracker.
+    ///
+    /// This is a subset of the hosts in `hosts`. For a verified query, it contains
+    /// all of them.
+    pub verified: Vec<NodeId>,
+
+    /// The hosts whose announce has expired.
+    ///
+    /// This is a subset of the hosts in `hosts`, and only filled if the query set
+    /// [QueryFlags::include_expired].
+    pub stale: Vec<NodeId>,
+
+    /// Why there are no hosts.
+    ///
+    /// This is filled by the tracker when `hosts` is empty, unless the query asked
+    /// for a sample of zero hosts.
+    pub empty_reason: Option<EmptyReason>,
+
+    /// How long the response can be cached, as advised by the tracker.
+    ///
+    /// This is the time until the first of the hosts in the response would be dropped
+    /// from the response, because its announce expires. A client that caches the
+    /// response for this long will not hand out hosts the tracker considers stale, but
+    /// might miss new hosts. `None` means no advice, e.g. for an empty response.
+    ///
+    /// There is no conditional query yet, like an `if_changed_since` flag, so a client
+    /// can only refresh a cached response by querying again in full. If one is added,
+    /// this stays the time until the response changes by expiry alone, while new
+    /// announces would be detected by the conditional query.
+    pub cache_for: Option<Duration>,
+
+    /// The time of the tracker when it created the response.
+    ///
+    /// The tracker expires announces by its own clock, starting when it received the
+    /// announce. Comparing this to the local time gives the clock skew to the tracker.
+    pub server_time: AbsoluteTime,
+
+    /// Where to continue for the next page, if the query set [QueryFlags::page_size]
+    /// and there are more hosts.
+    pub next_cursor: Option<HostCursor>,
+
+    /// The size of the content in bytes, as verified by the tracker.
+    ///
+    /// Only filled for a query with [DetailLevel::Full], and if the tracker has
+    /// verified the size. For a hash seq, this is the total size of the children.
+    pub content_size: Option<u64>,
+
+    /// The proofs the tracker got when verifying hosts, if the query set
+    /// [QueryFlags::include_proofs].
+    ///
+    /// There is at most one proof per host, and only for raw blobs. Hosts that were
+    /// not verified since the tracker started have no proof.
+    pub proofs: Vec<(NodeId, VerificationProof)>,
+}
+
+/// The hashes and data a tracker got from a host when verifying it.
+///
+/// This is the part of the bao encoding of a blob that covers a single chunk. It
+/// can be checked against the hash of the blob, see `verify_proof`, so a client does
+/// not have to trust the tracker that the host served valid data.
+#[derive(derive_more::Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerificationProof {
+    /// The size of the blob, as reported by the host.
+    pub size: u64,
+    /// The chunk the tracker asked for.
+    pub chunk: u64,
+    /// The hash pairs of the parents, from the root down to the chunk.
+    pub parents: Vec<(iroh_blobs::Hash, iroh_blobs::Hash)>,
+    /// The data of the chunk.
+    #[debug("{} bytes", self.data.len())]
+    pub data: Vec<u8>,
+}
+
+impl HostCursor {
+    /// The cursor pointing after the given announce.
+    pub fn after(announce: &Announce) -> Self {
+        Self {
+            format: announce.content.format,
+            kind: announce.kind,
+            host: announce.host,
+        }
+    }
+}
+
+impl QueryResponse {
+    /// Compute what changed from this response to a later response.
+    ///
+    /// Hosts are compared by node id, so a host announcing a different kind does
+    /// not count as a change.
+    pub fn diff(&self, other: &Self) -> QueryDiff {
+        let before = self.hosts.iter().map(|sa| sa.host).collect::<BTreeSet<_>>();
+        let after = other
+            .hosts
+            .iter()
+            .map(|sa| sa.host)
+            .collect::<BTreeSet<_>>();
+        let verified_before = self.verified.iter().collect::<BTreeSet<_>>();
+        let verified_after = other.verified.iter().collect::<BTreeSet<_>>();
+        QueryDiff {
+            added: after.difference(&before).copied().collect(),
+            removed: before.difference(&after).copied().collect(),
+            verification_changed: before
+                .intersection(&after)
+                .filter(|host| verified_before.contains(host) != verified_after.contains(host))
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+impl QueryResponse {
+    /// Convert the response into a list of hosts to download from, best first.
+    ///
+    /// Verified hosts come before unverified hosts, and hosts with the complete data
+    /// before hosts with partial data. Otherwise the order of the response is kept.
+    /// Each host is only listed once, with the addrs from the response if there are any.
+    pub fn into_download_sources(self) -> Vec<DownloadSource> {
+        let verified = self.verified.into_iter().collect::<BTreeSet<_>>();
+        let mut seen = BTreeSet::new();
+        let mut sources = self
+            .hosts
+            .iter()
+            .filter(|sa| seen.insert(sa.host))
+            .map(|sa| DownloadSource {
+                node_addr: self
+                    .node_addrs
+                    .iter()
+                    .find(|addr| addr.node_id == sa.host)
+                    .cloned()
+                    .unwrap_or_else(|| NodeAddr::new(sa.host)),
+                kind: sa.kind,
+                verified: verified.contains(&sa.host),
+            })
+            .collect::<Vec<_>>();
+        // stable sort, so the order of the response is kept within each class
+        sources.sort_by_key(|source| (!source.verified, source.kind != AnnounceKind::Complete));
+        sources
+    }
+
+    /// Convert the response into download sources, grouped by the relay of each host.
+    ///
+    /// This is useful to plan many connections, e.g. to spread them over relays.
+    /// Within each group, sources are ordered as in [Self::into_download_sources].
+    pub fn into_sources_by_relay(self) -> SourcesByRelay {
+        let mut res = SourcesByRelay::default();
+        for source in self.into_download_sources() {
+            if let Some(relay_url) = source.node_addr.relay_url() {
+                res.relays
+                    .entry(relay_url.clone())
+                    .or_default()
+                    .push(source);
+            } else if source.node_addr.info.direct_addresses.is_empty() {
+                res.unknown.push(source);
+            } else {
+                res.direct.push(source);
+            }
+        }
+        res
+    }
+}
+
+impl From<QueryResponse> for Vec<NodeAddr> {
+    fn from(response: QueryResponse) -> Self {
+        response
+            .into_download_sources()
+            .into_iter()
+            .map(|source| source.node_addr)
+            .collect()
+    }
+}
+
+/// A host to download from, see [QueryResponse::into_download_sources].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadSource {
+    /// The addr of the host.
+    pub node_addr: NodeAddr,
+    /// Whether the host claims to have the complete data.
+    pub kind: AnnounceKind,
+    /// Whether the host was recently verified by the tracker.
+    pub verified: bool,
+}
+
+/// Download sources grouped by relay, see [QueryResponse::into_sources_by_relay].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourcesByRelay {
+    /// Hosts with a relay, by relay url.
+    pub relays: BTreeMap<RelayUrl, Vec<DownloadSource>>,
+    /// Hosts without a relay, but with direct addresses.
+    pub direct: Vec<DownloadSource>,
+    /// Hosts the tracker gave no addrs for.
+    pub unknown: Vec<DownloadSource>,
+}
+
+/// The difference between two [QueryResponse]s, see [QueryResponse::diff].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryDiff {
+    /// Hosts that are only in the later response.
+    pub added: BTreeSet<NodeId>,
+    /// Hosts that are only in the earlier response.
+    pub removed: BTreeSet<NodeId>,
+    /// Hosts that are in both responses, but were verified in only one of them.
+    pub verification_changed: BTreeSet<NodeId>,
+}
+
+impl QueryDiff {
+    /// True if nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.verification_changed.is_empty()
+    }
+}
+
+/// Why a query returned no hosts.
+///
+/// If the known hosts were dropped for different reasons, the reason of the hosts that
+/// came closest to qualifying is given. E.g. if some hosts only have partial data and
+/// others are stale, the reason is [EmptyReason::Expired].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum EmptyReason {
+    /// The tracker knows no hosts for the content.
+    NeverAnnounced,
+    /// All known hosts only have partial data, but the query asked for complete data.
+    Filtered {
+        /// The number of known hosts.
+        known: u64,
+    },
+    /// All known hosts that match the query flags have not announced recently.
+    Expired {
+        /// The number of stale hosts.
+        known: u64,
+    },
+    /// All hosts that match the query flags and are not stale have not been verified recently,
+    /// or not by the method required with [QueryFlags::require_method].
+    Unverified {
+        /// The number of unverified hosts.
+        known: u64,
+    },
+}
+
+impl std::fmt::Display for EmptyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmptyReason::NeverAnnounced => write!(f, "the content was never announced"),
+            EmptyReason::Filtered { known } => {
+                write!(f, "all {} known hosts only have partial data", known)
+            }
+            EmptyReason::Expired { known } => write!(f, "all {} known hosts are stale", known),
+            EmptyReason::Unverified { known } => {
+                write!(f, "all {} known hosts failed verification", known)
+            }
+        }
+    }
 }
 
 /// A request to the tracker.
@@ -182,6 +1153,52 @@ pub enum Request {
     Announce(SignedAnnounce),
     /// Query info
     Query(Query),
+    /// Announce info on behalf of a host, using a token the host has signed
+    AnnounceToken(SignedAnnounceToken),
+    /// Retract all announces of a host
+    Unannounce(SignedUnannounce),
+    /// Query info, signed by the querier
+    SignedQuery(SignedQuery),
+    /// Ask for the most queried content, at most the given number of items
+    ///
+    /// The tracker may return fewer items than asked for, up to its own limit.
+    TopContent(u32),
+    /// Subscribe to the hosts for a query
+    ///
+    /// The tracker sends a [Response::QueryResponse] right away, and an updated one
+    /// whenever hosts announce the content, until the client goes away. The
+    /// responses are sent as frames on the same stream, see
+    /// [crate::codec::encode_frame]. This is only supported on QUIC connections.
+    Subscribe(Query),
+    /// Announce multiple items at once
+    ///
+    /// The tracker sends a [Response::ItemResult] for each announce as soon as it
+    /// is processed, in order, as frames on the same stream. Like all requests, the
+    /// batch must fit in [REQUEST_SIZE_LIMIT]. This is only supported on QUIC
+    /// connections.
+    BatchAnnounce(Vec<SignedAnnounce>),
+    /// Query multiple items at once
+    ///
+    /// The tracker sends a single [Response::QueryBatch], with one item per content
+    /// in the batch, in order.
+    QueryBatch(QueryBatch),
+    /// Announce multiple items at once, optionally all or nothing
+    ///
+    /// See [AnnounceSet]. This is only supported on QUIC connections.
+    AnnounceSet(AnnounceSet),
+    /// Probe all hosts of some content now, see [Refresh]
+    ///
+    /// The tracker sends a [Response::Refresh] once all hosts are probed. This is
+    /// only supported on QUIC connections.
+    Refresh(SignedRefresh),
+}
+
+impl Request {
+    /// The number of request variants.
+    ///
+    /// This must be increased when adding a variant. Trackers answer requests with
+    /// a variant they don't know with [UnsupportedRequest].
+    pub const VARIANTS: u32 = 11;
 }
 
 /// A response from the tracker.
@@ -189,7 +1206,443 @@ pub enum Request {
 pub enum Response {
     /// Response to a query
     QueryResponse(QueryResponse),
+    /// Response to a top content request
+    ///
+    /// The content with the number of recent queries, most queried first. This is
+    /// empty if the tracker does not collect query statistics.
+    TopContent(Vec<(HashAndFormat, u64)>),
+    /// The tracker could not handle the request
+    Error(ErrorResponse),
+    /// Whether an item of a [Request::BatchAnnounce] was accepted
+    ItemResult(ItemResult),
+    /// Response to a [Request::QueryBatch]
+    QueryBatch(Vec<BatchQueryItem>),
+    /// Whether an atomic [Request::AnnounceSet] was accepted
+    AnnounceSet(AnnounceSetResult),
+    /// Response to a [Request::Refresh]
+    Refresh(RefreshSummary),
+}
+
+/// The result for a single announce of a [Request::BatchAnnounce].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemResult {
+    /// The announced content.
+    pub content: HashAndFormat,
+    /// True if the tracker accepted the announce.
+    ///
+    /// Announces are rejected if the signature is invalid, or if the tracker could
+    /// not store them.
+    pub accepted: bool,
 }
 
+/// Multiple announces, see [Request::AnnounceSet].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnounceSet {
+    /// The announces.
+    pub announces: Vec<SignedAnnounce>,
+    /// Accept either all announces or none of them.
+    ///
+    /// If this is false, the set is handled like a [Request::BatchAnnounce], and the
+    /// tracker sends a [Response::ItemResult] for each announce.
+    ///
+    /// If this is true, the tracker checks all announces before storing any of them,
+    /// and stores them in a single transaction. It sends a single
+    /// [Response::AnnounceSet] frame. This is for sets where a partial accept is worse
+    /// than a full reject, e.g. the children of a collection.
+    pub atomic: bool,
+}
+
+/// The result of an atomic [AnnounceSet].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnnounceSetResult {
+    /// All announces were stored.
+    Accepted,
+    /// No announce was stored, because one of them violated a constraint.
+    Rejected(AnnounceRejection),
+}
+
+/// The first announce of an atomic [AnnounceSet] that violated a constraint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnounceRejection {
+    /// The index of the announce in [AnnounceSet::announces].
+    pub index: u32,
+    /// The content of the announce.
+    pub content: HashAndFormat,
+    /// The constraint that was violated.
+    pub constraint: AnnounceConstraint,
+}
+
+/// A constraint a tracker checks before accepting an announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnnounceConstraint {
+    /// The signature must be valid.
+    Signature,
+    /// The timestamp must be recent, see the announce timeout of the tracker, and
+    /// not too far in the future.
+    Freshness,
+}
+
+impl std::fmt::Display for AnnounceConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnnounceConstraint::Signature => write!(f, "invalid signature"),
+            AnnounceConstraint::Freshness => {
+                write!(f, "announce is too old or too far in the future")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for AnnounceRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "announce set rejected, item {} ({}): {}",
+            self.index, self.content, self.constraint
+        )
+    }
+}
+
+impl std::error::Error for AnnounceRejection {}
+
+/// Why a tracker could not handle a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    /// The tracker does not know the request variant.
+    UnsupportedRequest(UnsupportedRequest),
+    /// The request has more items than the tracker accepts in one request.
+    TooManyItems(TooManyItems),
+}
+
+/// A request with a variant the tracker does not know, e.g. because the tracker is
+/// older than the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnsupportedRequest {
+    /// The index of the variant of [Request].
+    pub variant: u32,
+}
+
+impl std::fmt::Display for UnsupportedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "request variant {} not supported by tracker",
+            self.variant
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedRequest {}
+
+/// A batch request with more items than the tracker accepts in one request.
+///
+/// This applies to [Request::QueryBatch], [Request::BatchAnnounce] and
+/// [Request::AnnounceSet]. Clients can split the request into requests of at most
+/// `limit` items, unless it has to be handled as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TooManyItems {
+    /// The number of items in the request.
+    pub requested: u32,
+    /// The maximum number of items per request.
+    pub limit: u32,
+}
+
+impl std::fmt::Display for TooManyItems {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "request has {} items, but the tracker accepts at most {}",
+            self.requested, self.limit
+        )
+    }
+}
+
+impl std::error::Error for TooManyItems {}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{
+        test_hash, test_node_id, test_secret_key, AnnounceBuilder, QueryBuilder,
+        QueryResponseBuilder,
+    };
+
+    #[test]
+    fn ticket_and_hash_have_the_same_key() {
+        let addr = NodeAddr::new(test_node_id(1));
+        // a host announces via a ticket, a downloader queries via the hash
+        let ticket = BlobTicket::new(addr.clone(), test_hash(1), BlobFormat::Raw).unwrap();
+        let announce = AnnounceBuilder::new(1)
+            .content(ticket.content_key())
+            .build();
+        let query = QueryBuilder::new(test_hash(1).content_key()).build();
+        assert_eq!(announce.content, query.content);
+
+        // and the other way around, for a collection
+        let content = HashAndFormat::hash_seq(test_hash(2));
+        let announce = AnnounceBuilder::new(1)
+            .content(content.content_key())
+            .build();
+        let ticket = BlobTicket::new(addr, test_hash(2), BlobFormat::HashSeq).unwrap();
+        let query = QueryBuilder::new(ticket.content_key()).build();
+        assert_eq!(announce.content, query.content);
+        // a bare hash is a raw blob, not the collection
+        assert_ne!(test_hash(2).content_key(), query.content);
+    }
+
+    #[test]
+    fn mime_type_is_bounded() {
+        let announce = |len: usize| {
+            let metadata = AnnounceMetadata {
+                mime_type: Some("x".repeat(len)),
+            };
+            AnnounceBuilder::new(1).metadata(metadata).build()
+        };
+        assert!(SignedAnnounce::new(announce(MAX_MIME_TYPE_LEN), &test_secret_key(1)).is_ok());
+        assert!(SignedAnnounce::new(announce(MAX_MIME_TYPE_LEN + 1), &test_secret_key(1)).is_err());
+    }
+
+    #[test]
+    fn fixtures_are_deterministic() {
+        assert_eq!(test_node_id(1), test_node_id(1));
+        assert_ne!(test_node_id(1), test_node_id(2));
+        assert_eq!(test_hash(1), test_hash(1));
+        assert_ne!(test_hash(1), test_hash(2));
+        assert_eq!(test_secret_key(1).public(), test_node_id(1));
+    }
+
+    #[test]
+    fn announce_kind_store_state() {
+        for kind in [AnnounceKind::Partial, AnnounceKind::Complete] {
+            assert_eq!(
+                AnnounceKind::from_store_state(kind.to_store_state()),
+                Some(kind)
+            );
+        }
+        assert_eq!(AnnounceKind::from_store_state(EntryStatus::NotFound), None);
+    }
+
+    #[test]
+    fn signed_announce_verify() {
+        let signed = AnnounceBuilder::new(1).sign();
+        assert_eq!(signed.host, test_node_id(1));
+        signed.verify().unwrap();
+
+        let mut tampered = signed.clone();
+        tampered.announce.kind = AnnounceKind::Partial;
+        assert!(tampered.verify().is_err());
+
+        // the namespace is signed, so an announce can not be moved to another namespace
+        let mut tampered = signed.clone();
+        tampered.announce.namespace = Some(Namespace::from_name("other"));
+        assert!(tampered.verify().is_err());
+
+        // the seed class is signed, so nobody but the host can promote it to a mirror
+        let mut tampered = signed.clone();
+        tampered.announce.class = SeedClass::Mirror;
+        assert!(tampered.verify().is_err());
+    }
+
+    #[test]
+    fn seeding_commitment() {
+        let now = AbsoluteTime::now();
+        let until = AbsoluteTime::from_micros(now.as_micros() + 1_000_000);
+        let signed = AnnounceBuilder::new(1)
+            .timestamp(now)
+            .committed_until(Some(until))
+            .sign();
+        signed.verify().unwrap();
+        assert!(signed.is_committed(now));
+        assert!(!signed.is_committed(until));
+
+        // the commitment is signed, so nobody but the host can extend it
+        let mut tampered = signed.clone();
+        tampered.announce.committed_until = Some(AbsoluteTime::from_micros(u64::MAX));
+        assert!(tampered.verify().is_err());
+
+        // a commitment that ends before the announce is meaningless
+        let signed = AnnounceBuilder::new(1)
+            .timestamp(until)
+            .committed_until(Some(now))
+            .sign();
+        assert!(signed.verify().is_err());
+    }
+
+    #[test]
+    fn absolute_time_sub_saturates() {
+        let now = AbsoluteTime::now();
+        let later = AbsoluteTime::from_micros(now.as_micros() + 1_000_000);
+        assert_eq!(later - now, Duration::from_secs(1));
+        // a timestamp from a clock that is ahead does not panic
+        assert_eq!(now - later, Duration::ZERO);
+    }
+
+    #[test]
+    fn alpn_validation() {
+        validate_alpn(ALPN).unwrap();
+        validate_alpn(b"myorg/tracker/1").unwrap();
+        assert!(validate_alpn(b"").is_err());
+        assert!(validate_alpn(&[b'a'; 256]).is_err());
+        assert!(validate_alpn(b"my tracker").is_err());
+    }
+
+    #[test]
+    fn detail_level_default() {
+        assert_eq!(DetailLevel::default(), DetailLevel::Standard);
+        for level in [
+            DetailLevel::Minimal,
+            DetailLevel::Standard,
+            DetailLevel::Full,
+        ] {
+            assert_eq!(level.to_string().parse::<DetailLevel>().unwrap(), level);
+        }
+    }
+
+    #[test]
+    fn seed_class_order() {
+        assert!(SeedClass::Transient < SeedClass::Peer);
+        assert!(SeedClass::Peer < SeedClass::Mirror);
+        for class in [SeedClass::Transient, SeedClass::Peer, SeedClass::Mirror] {
+            assert_eq!(class.to_string().parse::<SeedClass>().unwrap(), class);
+        }
+    }
+
+    #[test]
+    fn verification_method_order() {
+        assert!(VerificationMethod::Size < VerificationMethod::RandomRange);
+        for method in [VerificationMethod::Size, VerificationMethod::RandomRange] {
+            assert_eq!(
+                method.to_string().parse::<VerificationMethod>().unwrap(),
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn signed_query_verify() {
+        let query = AttributedQuery {
+            querier: test_node_id(1),
+            timestamp: AbsoluteTime::now(),
+            query: QueryBuilder::new(HashAndFormat::raw(test_hash(1))).build(),
+        };
+        let signed = SignedQuery::new(query, &test_secret_key(1)).unwrap();
+        signed.verify().unwrap();
+
+        // a query can not be attributed to somebody else
+        let mut tampered = signed;
+        tampered.query.querier = test_node_id(2);
+        assert!(tampered.verify().is_err());
+    }
+
+    #[test]
+    fn announce_token_verify() {
+        let announce = AnnounceBuilder::new(1).sign();
+        let now = AbsoluteTime::now();
+        let valid_until = AbsoluteTime::from_micros(now.as_micros() + 1_000_000);
+        let token =
+            SignedAnnounceToken::new(announce.clone(), valid_until, &test_secret_key(1)).unwrap();
+        token.verify(now).unwrap();
+
+        let later = AbsoluteTime::from_micros(
+            valid_until.as_micros() + Duration::from_secs(1).as_micros() as u64,
+        );
+        assert!(token.verify(later).is_err());
+
+        // a token can only be issued by the host of the announce
+        assert!(SignedAnnounceToken::new(announce, valid_until, &test_secret_key(2)).is_err());
+    }
+
+    #[test]
+    fn query_response_diff() {
+        let before = QueryResponseBuilder::new()
+            .host(AnnounceBuilder::new(1).sign())
+            .host(AnnounceBuilder::new(2).sign())
+            .build();
+        let after = QueryResponseBuilder::new()
+            .host(AnnounceBuilder::new(2).sign())
+            .host(AnnounceBuilder::new(3).sign())
+            .verified(test_node_id(2))
+            .build();
+        let diff = before.diff(&after);
+        assert_eq!(diff.added, BTreeSet::from([test_node_id(3)]));
+        assert_eq!(diff.removed, BTreeSet::from([test_node_id(1)]));
+        assert_eq!(diff.verification_changed, BTreeSet::from([test_node_id(2)]));
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn download_sources_order() {
+        let response = QueryResponseBuilder::new()
+            .host(AnnounceBuilder::new(1).kind(AnnounceKind::Partial).sign())
+            .host(AnnounceBuilder::new(2).sign())
+            .host(AnnounceBuilder::new(3).sign())
+            .host(AnnounceBuilder::new(3).sign())
+            .verified(test_node_id(3))
+            .build();
+        let addrs: Vec<NodeAddr> = response.into();
+        let ids = addrs.iter().map(|addr| addr.node_id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![test_node_id(3), test_node_id(2), test_node_id(1)]);
+    }
+
+    #[test]
+    fn sources_by_relay() {
+        let relay: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let direct = "127.0.0.1:4433".parse().unwrap();
+        let response = QueryResponseBuilder::new()
+            .host(AnnounceBuilder::new(1).sign())
+            .host(AnnounceBuilder::new(2).sign())
+            .host(AnnounceBuilder::new(3).sign())
+            .host(AnnounceBuilder::new(4).sign())
+            .node_addr(NodeAddr::new(test_node_id(1)).with_relay_url(relay.clone()))
+            .node_addr(
+                NodeAddr::new(test_node_id(2))
+                    .with_relay_url(relay.clone())
+                    .with_direct_addresses([direct]),
+            )
+            .node_addr(NodeAddr::new(test_node_id(3)).with_direct_addresses([direct]))
+            .build();
+        let grouped = response.into_sources_by_relay();
+        let ids = |sources: &[DownloadSource]| {
+            sources
+                .iter()
+                .map(|source| source.node_addr.node_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(grouped.relays.len(), 1);
+        assert_eq!(
+            ids(&grouped.relays[&relay]),
+            vec![test_node_id(1), test_node_id(2)]
+        );
+        assert_eq!(ids(&grouped.direct), vec![test_node_id(3)]);
+        assert_eq!(ids(&grouped.unknown), vec![test_node_id(4)]);
+    }
+
+    #[test]
+    fn dedup_announces_formats_are_distinct() {
+        let hash = test_hash(1);
+        let raw = AnnounceBuilder::new(1).content(HashAndFormat::raw(hash));
+        let seq = AnnounceBuilder::new(1).content(HashAndFormat::hash_seq(hash));
+        let res = dedup_announces(vec![raw.build(), seq.build()]).unwrap();
+        assert_eq!(res.len(), 2);
+    }
+
+    #[test]
+    fn dedup_announces_collapses_duplicates() {
+        let announce = AnnounceBuilder::new(1);
+        let res = dedup_announces(vec![announce.clone().build(), announce.build()]).unwrap();
+        assert_eq!(res.len(), 1);
+    }
+
+    #[test]
+    fn dedup_announces_rejects_contradictions() {
+        let complete = AnnounceBuilder::new(1).kind(AnnounceKind::Complete);
+        let partial = AnnounceBuilder::new(1).kind(AnnounceKind::Partial);
+        assert!(dedup_announces(vec![complete.build(), partial.build()]).is_err());
+
+        // the same content for different hosts can not be announced together
+        let other = AnnounceBuilder::new(2).content(HashAndFormat::raw(test_hash(1)));
+        assert!(dedup_announces(vec![AnnounceBuilder::new(1).build(), other.build()]).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Helpers for writing tests against the protocol.
+//!
+//! Node ids and hashes are derived from a seed, so tests get stable values
+//! without having to hardcode keys. This is available for the crate's own tests,
+//! and for downstream crates via the `test-utils` feature.
+use std::time::Duration;
+
+use iroh_blobs::{Hash, HashAndFormat};
+use iroh_net::{key::SecretKey, NodeAddr, NodeId};
+
+use crate::protocol::{
+    AbsoluteTime, Announce, AnnounceKind, AnnounceMetadata, DetailLevel, EmptyReason, HostCursor,
+    Namespace, Query, QueryFlags, QueryResponse, SeedClass, SignedAnnounce, VerificationMethod,
+};
+
+/// A secret key derived from a seed.
+pub fn test_secret_key(seed: u64) -> SecretKey {
+    let mut bytes = [0u8; 32];
+    bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    SecretKey::from_bytes(&bytes)
+}
+
+/// A node id derived from a seed.
+///
+/// This is the public key of [test_secret_key] for the same seed.
+pub fn test_node_id(seed: u64) -> NodeId {
+    test_secret_key(seed).public()
+}
+
+/// A hash derived from a seed.
+pub fn test_hash(seed: u64) -> Hash {
+    Hash::new(seed.to_le_bytes())
+}
+
+/// Builder for an [Announce].
+///
+/// By default, the host is derived from the seed, the content is the raw blob
+/// [test_hash] of the seed, the announce is complete and timestamped now.
+#[derive(Debug, Clone)]
+pub struct AnnounceBuilder {
+    seed: u64,
+    content: HashAndFormat,
+    kind: AnnounceKind,
+    timestamp: AbsoluteTime,
+    metadata: AnnounceMetadata,
+    namespace: Option<Namespace>,
+    class: SeedClass,
+    committed_until: Option<AbsoluteTime>,
+}
+
+impl AnnounceBuilder {
+    /// Create a new builder for the host with the given seed.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            content: HashAndFormat::raw(test_hash(seed)),
+            kind: AnnounceKind::Complete,
+            timestamp: AbsoluteTime::now(),
+            metadata: AnnounceMetadata::default(),
+            namespace: None,
+            class: SeedClass::default(),
+            committed_until: None,
+        }
+    }
+
+    /// Set the announced content.
+    pub fn content(mut self, content: HashAndFormat) -> Self {
+        self.content = content;
+        self
+    }
+
+    /// Set the announce kind.
+    pub fn kind(mut self, kind: AnnounceKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Set the timestamp.
+    pub fn timestamp(mut self, timestamp: AbsoluteTime) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Set the metadata.
+    pub fn metadata(mut self, metadata: AnnounceMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Set the namespace.
+    pub fn namespace(mut self, namespace: Option<Namespace>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// Set the seed class.
+    pub fn class(mut self, class: SeedClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    /// Set the end of the seeding commitment.
+    pub fn committed_until(mut self, until: Option<AbsoluteTime>) -> Self {
+        self.committed_until = until;
+        self
+    }
+
+    /// Build the unsigned announce.
+    pub fn build(self) -> Announce {
+        Announce {
+            host: test_node_id(self.seed),
+            content: self.content,
+            kind: self.kind,
+            timestamp: self.timestamp,
+            metadata: self.metadata,
+            namespace: self.namespace,
+            class: self.class,
+            committed_until: self.committed_until,
+        }
+    }
+
+    /// Build the announce and sign it with the [test_secret_key] of the seed.
+    pub fn sign(self) -> SignedAnnounce {
+        let secret_key = test_secret_key(self.seed);
+        SignedAnnounce::new(self.build(), &secret_key).expect("failed to sign announce")
+    }
+}
+
+/// Builder for a [Query].
+///
+/// By default, the query is for complete, but not necessarily verified, hosts.
+#[derive(Debug, Clone)]
+pub struct QueryBuilder {
+    content: HashAndFormat,
+    flags: QueryFlags,
+    namespace: Option<Namespace>,
+    cursor: Option<HostCursor>,
+}
+
+impl QueryBuilder {
+    /// Create a new builder for the given content.
+    pub fn new(content: HashAndFormat) -> Self {
+        Self {
+            content,
+            flags: QueryFlags {
+                complete: true,
+                verified: false,
+                sample: None,
+                include_expired: None,
+                min_class: None,
+                require_method: None,
+                page_size: None,
+                prefer_committed: false,
+                detail: DetailLevel::default(),
+                include_proofs: false,
+            },
+            namespace: None,
+            cursor: None,
+        }
+    }
+
+    /// Only ask for hosts that have the complete data.
+    pub fn complete(mut self, complete: bool) -> Self {
+        self.flags.complete = complete;
+        self
+    }
+
+    /// Only ask for verified hosts.
+    pub fn verified(mut self, verified: bool) -> Self {
+        self.flags.verified = verified;
+        self
+    }
+
+    /// Only ask for a random sample of at most `n` hosts.
+    pub fn sample(mut self, n: Option<u32>) -> Self {
+        self.flags.sample = n;
+        self
+    }
+
+    /// Also ask for hosts that expired at most this long ago.
+    pub fn include_expired(mut self, grace: Option<Duration>) -> Self {
+        self.flags.include_expired = grace;
+        self
+    }
+
+    /// Only ask for hosts of at least this class.
+    pub fn min_class(mut self, class: Option<SeedClass>) -> Self {
+        self.flags.min_class = class;
+        self
+    }
+
+    /// Only ask for hosts verified by at least this method.
+    pub fn require_method(mut self, method: Option<VerificationMethod>) -> Self {
+        self.flags.require_method = method;
+        self
+    }
+
+    /// Ask for at most `n` hosts per page.
+    pub fn page_size(mut self, n: Option<u32>) -> Self {
+        self.flags.page_size = n;
+        self
+    }
+
+    /// Ask for hosts with a seeding commitment first.
+    pub fn prefer_committed(mut self, prefer: bool) -> Self {
+        self.flags.prefer_committed = prefer;
+        self
+    }
+
+    /// Set how much to include per host.
+    pub fn detail(mut self, detail: DetailLevel) -> Self {
+        self.flags.detail = detail;
+        self
+    }
+
+    /// Also ask for verification proofs.
+    pub fn include_proofs(mut self, include: bool) -> Self {
+        self.flags.include_proofs = include;
+        self
+    }
+
+    /// Continue after the given cursor.
+    pub fn cursor(mut self, cursor: Option<HostCursor>) -> Self {
+        self.cursor = cursor;
+        self
+    }
+
+    /// Set the namespace to query in.
+    pub fn namespace(mut self, namespace: Option<Namespace>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// Build the query.
+    pub fn build(self) -> Query {
+        Query {
+            content: self.content,
+            flags: self.flags,
+            namespace: self.namespace,
+            cursor: self.cursor,
+        }
+    }
+}
+
+/// Builder for a [QueryResponse].
+///
+/// By default, the response contains no hosts and no reason for being empty.
+#[derive(Debug, Clone, Default)]
+pub struct QueryResponseBuilder {
+    hosts: Vec<SignedAnnounce>,
+    node_addrs: Vec<NodeAddr>,
+    verified: Vec<NodeId>,
+    stale: Vec<NodeId>,
+    empty_reason: Option<EmptyReason>,
+}
+
+impl QueryResponseBuilder {
+    /// Create a new builder for an empty response.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a host to the response.
+    pub fn host(mut self, host: SignedAnnounce) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    /// Add multiple hosts to the response.
+    pub fn hosts(mut self, hosts: impl IntoIterator<Item = SignedAnnounce>) -> Self {
+        self.hosts.extend(hosts);
+        self
+    }
+
+    /// Add the addr of a host.
+    pub fn node_addr(mut self, addr: NodeAddr) -> Self {
+        self.node_addrs.push(addr);
+        self
+    }
+
+    /// Mark a host as verified.
+    pub fn verified(mut self, host: NodeId) -> Self {
+        self.verified.push(host);
+        self
+    }
+
+    /// Mark a host as stale.
+    pub fn stale(mut self, host: NodeId) -> Self {
+        self.stale.push(host);
+        self
+    }
+
+    /// Set the reason for the response being empty.
+    pub fn empty_reason(mut self, reason: EmptyReason) -> Self {
+        self.empty_reason = Some(reason);
+        self
+    }
+
+    /// Build the response.
+    pub fn build(self) -> QueryResponse {
+        QueryResponse {
+            hosts: self.hosts,
+            host_ids: Vec::new(),
+            node_addrs: self.node_addrs,
+            verified: self.verified,
+            stale: self.stale,
+            empty_reason: self.empty_reason,
+            cache_for: None,
+            server_time: AbsoluteTime::now(),
+            next_cursor: None,
+            content_size: None,
+            proofs: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! A client for a single tracker, using one connection for all requests.
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use genawaiter::sync::Gen;
+use iroh_blobs::HashAndFormat;
+use iroh_net::{key::SecretKey, Endpoint, NodeAddr, NodeId};
+
+use crate::{
+    announce_all, create_quinn_client_with_options,
+    protocol::{
+        validate_alpn, AbsoluteTime, Announce, AnnounceKind, AnnounceMetadata, Query, QueryDiff,
+        QueryResponse, SeedClass, SignedAnnounce, UnsupportedRequest, ALPN,
+    },
+    ConnectionOptions,
+};
+
+/// Options for a [TrackerClient].
+#[derive(Debug, Clone)]
+pub struct TrackerClientOptions {
+    /// How long to collect buffered announces before sending them.
+    ///
+    /// See [TrackerClient::announce_buffered].
+    pub coalesce_window: Duration,
+    /// The maximum number of announces in flight when sending buffered announces.
+    pub max_in_flight: usize,
+    /// Timeouts for the connection, when connecting with [TrackerClient::connect].
+    ///
+    /// A client is usually kept around for a long time, so the default is
+    /// [ConnectionOptions::long_lived].
+    pub connection: ConnectionOptions,
+    /// Resolves the addr of a tracker given by node id, when connecting with
+    /// [TrackerClient::connect_node_id].
+    ///
+    /// Without a resolver, or if it does not know the tracker, the discovery of the
+    /// endpoint is used.
+    pub resolver: Option<Arc<dyn AddrResolver>>,
+    /// The ALPN to connect with, when connecting with [TrackerClient::connect] or
+    /// [TrackerClient::connect_node_id].
+    ///
+    /// This is [ALPN] by default. See [TrackerClientOptions::with_alpn].
+    pub alpn: Vec<u8>,
+    /// How often to query the tracker in [TrackerClient::watch], if the tracker does
+    /// not support subscriptions.
+    pub watch_interval: Duration,
+}
+
+impl Default for TrackerClientOptions {
+    fn default() -> Self {
+        Self {
+            coalesce_window: Duration::from_millis(500),
+            max_in_flight: 8,
+            connection: ConnectionOptions::long_lived(),
+            resolver: None,
+            alpn: ALPN.to_vec(),
+            watch_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl TrackerClientOptions {
+    /// Connect with a custom ALPN, for a tracker network that is separate from the
+    /// public one.
+    ///
+    /// The tracker must be configured with the same ALPN. Fails if the ALPN is not
+    /// valid, see [validate_alpn].
+    pub fn with_alpn(mut self, alpn: impl Into<Vec<u8>>) -> anyhow::Result<Self> {
+        let alpn = alpn.into();
+        validate_alpn(&alpn)?;
+        self.alpn = alpn;
+        Ok(self)
+    }
+}
+
+/// Provides the addr of a node before dialing it.
+///
+/// This can be a local cache, a DNS lookup or a fixed configuration, to avoid
+/// depending on node discovery in controlled environments.
+pub trait AddrResolver: std::fmt::Debug + Send + Sync {
+    /// Resolve the addr of a node, or `None` if it is not known.
+    fn resolve(&self, node_id: NodeId) -> BoxFuture<'_, anyhow::Result<Option<NodeAddr>>>;
+}
+
+/// A fixed map of addrs.
+impl AddrResolver for BTreeMap<NodeId, NodeAddr> {
+    fn resolve(&self, node_id: NodeId) -> BoxFuture<'_, anyhow::Result<Option<NodeAddr>>> {
+        let addr = self.get(&node_id).cloned();
+        async move { Ok(addr) }.boxed()
+    }
+}
+
+/// A client for a single tracker.
+///
+/// This announces content of the node with the given secret key. It is a cheaply
+/// cloneable handle, all clones share the connection and the announce buffer.
+///
+/// When the last clone is dropped, the connection is closed. Dropping can not wait,
+/// so buffered announces are lost and the close frame is sent on a best effort basis.
+/// Prefer calling [TrackerClient::close] when done:
+///
+/// ```ignore
+/// client.close().await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct TrackerClient(Arc<Inner>);
+
+#[derive(derive_more::Debug)]
+struct Inner {
+    connection: iroh_net::endpoint::Connection,
+    #[debug(skip)]
+    secret_key: SecretKey,
+    options: TrackerClientOptions,
+    buffer: Mutex<Buffer>,
+    /// Cached query responses, and when they expire.
+    cache: Mutex<BTreeMap<Query, (Instant, QueryResponse)>>,
+}
+
+/// The application error code for a regular close.
+const CLOSE_CODE: u32 = 0;
+/// The reason sent to the tracker when closing.
+const CLOSE_REASON: &[u8] = b"client closed";
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // closing an already closed connection has no effect
+        self.connection.close(CLOSE_CODE.into(), CLOSE_REASON);
+    }
+}
+
+/// A change of the hosts for some content, see [TrackerClient::watch].
+#[derive(Debug, Clone)]
+pub struct WatchUpdate {
+    /// The current response.
+    pub response: QueryResponse,
+    /// What changed since the previous update.
+    ///
+    /// For the first update, all hosts are added.
+    pub diff: QueryDiff,
+}
+
+impl WatchUpdate {
+    /// The update from the previous response, or `None` if nothing changed.
+    fn new(previous: Option<&QueryResponse>, response: QueryResponse) -> Option<Self> {
+        let diff = match previous {
+            Some(previous) => previous.diff(&response),
+            None => QueryDiff {
+                added: response.hosts.iter().map(|sa| sa.host).collect(),
+                ..Default::default()
+            },
+        };
+        if previous.is_some() && diff.is_empty() {
+            return None;
+        }
+        Some(Self { response, diff })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    /// Content to announce. If content is announced as both partial and complete,
+    /// complete wins.
+    items: BTreeMap<HashAndFormat, AnnounceKind>,
+    /// True if a task to flush the buffer is scheduled.
+    flush_scheduled: bool,
+}
+
+impl TrackerClient {
+    /// Create a new client using an existing connection to the tracker.
+    pub fn new(
+        connection: iroh_net::endpoint::Connection,
+        secret_key: SecretKey,
+        options: TrackerClientOptions,
+    ) -> Self {
+        Self(Arc::new(Inner {
+            connection,
+            secret_key,
+            options,
+            buffer: Default::default(),
+            cache: Default::default(),
+        }))
+    }
+
+    /// Connect to a tracker by address and create a new client.
+    ///
+    /// The connection uses [TrackerClientOptions::connection]. When using [Self::new]
+    /// with an iroh endpoint, configure the endpoint with
+    /// [ConnectionOptions::transport_config] instead.
+    pub async fn connect(
+        bind_addr: SocketAddr,
+        tracker: SocketAddr,
+        secret_key: SecretKey,
+        options: TrackerClientOptions,
+    ) -> anyhow::Result<Self> {
+        validate_alpn(&options.alpn)?;
+        let endpoint = create_quinn_client_with_options(
+            bind_addr,
+            vec![options.alpn.clone()],
+            false,
+            &options.connection,
+        )?;
+        let connection = endpoint.connect(tracker, "localhost")?.await?;
+        Ok(Self::new(connection, secret_key, options))
+    }
+
+    /// Connect to a tracker by node id via an iroh endpoint and create a new client.
+    ///
+    /// The addr of the tracker is taken from [TrackerClientOptions::resolver] if it
+    /// knows the tracker, otherwise the discovery of the endpoint is used. As with
+    /// [Self::new], timeouts are those of the endpoint.
+    pub async fn connect_node_id(
+        endpoint: &Endpoint,
+        tracker: NodeId,
+        secret_key: SecretKey,
+        options: TrackerClientOptions,
+    ) -> anyhow::Result<Self> {
+        validate_alpn(&options.alpn)?;
+        let addr = match &options.resolver {
+            Some(resolver) => resolver.resolve(tracker).await?,
+            None => None,
+        };
+        let connection = match addr {
+            Some(addr) => {
+                anyhow::ensure!(
+                    addr.node_id == tracker,
+                    "resolved addr is for {}, not {}",
+                    addr.node_id,
+                    tracker
+                );
+                endpoint.connect(addr, &options.alpn).await?
+            }
+            None => endpoint.connect_by_node_id(&tracker, &options.alpn).await?,
+        };
+        Ok(Self::new(connection, secret_key, options))
+    }
+
+    /// The connection to the tracker.
+    pub fn connection(&self) -> &iroh_net::endpoint::Connection {
+        &self.0.connection
+    }
+
+    /// Announce content immediately.
+    pub async fn announce(&self, content: HashAndFormat, kind: AnnounceKind) -> anyhow::Result<()> {
+        let signed_announce = self.sign(content, kind)?;
+        crate::announce(self.0.connection.clone(), signed_announce).await
+    }
+
+    /// Announce content after a short delay, together with other buffered content.
+    ///
+    /// Content is collected for [TrackerClientOptions::coalesce_window] after the first
+    /// buffered item, and then sent in one go. Errors when sending are logged. Use
+    /// [TrackerClient::flush] to send immediately and get the result.
+    pub fn announce_buffered(&self, content: HashAndFormat, kind: AnnounceKind) {
+        let mut buffer = self.0.buffer.lock().unwrap();
+        let entry = buffer.items.entry(content).or_insert(kind);
+        *entry = (*entry).max(kind);
+        if buffer.flush_scheduled {
+            return;
+        }
+        buffer.flush_scheduled = true;
+        let this = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(this.0.options.coalesce_window).await;
+            this.0.buffer.lock().unwrap().flush_scheduled = false;
+            if let Err(cause) = this.flush().await {
+                tracing::warn!("error sending buffered announces: {}", cause);
+            }
+        });
+    }
+
+    /// Send all buffered announces now.
+    ///
+    /// Returns the number of announces sent.
+    pub async fn flush(&self) -> anyhow::Result<usize> {
+        let items = std::mem::take(&mut self.0.buffer.lock().unwrap().items);
+        if items.is_empty() {
+            return Ok(0);
+        }
+        let announces = items
+            .into_iter()
+            .map(|(content, kind)| self.sign(content, kind))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        announce_all(
+            self.0.connection.clone(),
+            announces,
+            self.0.options.max_in_flight,
+        )
+        .await
+    }
+
+    /// Query the tracker.
+    pub async fn query(&self, query: Query) -> anyhow::Result<QueryResponse> {
+        crate::query(self.0.connection.clone(), query).await
+    }
+
+    /// Query the tracker, or return a cached response.
+    ///
+    /// Responses are cached for as long as the tracker advises with
+    /// [QueryResponse::cache_for]. Responses without advice are not cached.
+    pub async fn query_cached(&self, query: Query) -> anyhow::Result<QueryResponse> {
+        let now = Instant::now();
+        if let Some((until, response)) = self.0.cache.lock().unwrap().get(&query) {
+            if *until > now {
+                return Ok(response.clone());
+            }
+        }
+        let response = self.query(query).await?;
+        let mut cache = self.0.cache.lock().unwrap();
+        cache.retain(|_, (until, _)| *until > now);
+        if let Some(cache_for) = response.cache_for {
+            cache.insert(query, (now + cache_for, response.clone()));
+        }
+        Ok(response)
+    }
+
+    /// Watch the hosts for a query.
+    ///
+    /// This yields an update with the full response whenever the hosts change, starting
+    /// with the current hosts. Trackers that support subscriptions push changes, see
+    /// [crate::query_updates]. For older trackers, which reject the subscription as
+    /// unsupported, this falls back to querying every
+    /// [TrackerClientOptions::watch_interval] and only yields responses that differ from
+    /// the previous one. Either way, the stream ends after the first error.
+    pub fn watch(&self, query: Query) -> impl Stream<Item = anyhow::Result<WatchUpdate>> {
+        let this = self.clone();
+        Gen::new(move |co| async move {
+            let mut previous = None;
+            let mut updates = crate::query_updates(this.0.connection.clone(), query);
+            while let Some(res) = updates.next().await {
+                match res {
+                    Ok(response) => {
+                        if let Some(update) = WatchUpdate::new(previous.as_ref(), response) {
+                            previous = Some(update.response.clone());
+                            co.yield_(Ok(update)).await;
+                        }
+                    }
+                    Err(cause) if previous.is_none() && cause.is::<UnsupportedRequest>() => {
+                        tracing::debug!("tracker does not support subscriptions, polling");
+                        break;
+                    }
+                    Err(cause) => {
+                        co.yield_(Err(cause)).await;
+                        return;
+                    }
+                }
+            }
+            if previous.is_some() {
+                // the tracker closed the subscription
+                return;
+            }
+            loop {
+                match this.query(query).await {
+                    Ok(response) => {
+                        if let Some(update) = WatchUpdate::new(previous.as_ref(), response) {
+                            previous = Some(update.response.clone());
+                            co.yield_(Ok(update)).await;
+                        }
+                    }
+                    Err(cause) => {
+                        co.yield_(Err(cause)).await;
+                        return;
+                    }
+                }
+                tokio::time::sleep(this.0.options.watch_interval).await;
+            }
+        })
+    }
+
+    /// Send all buffered announces, then close the connection.
+    ///
+    /// The connection is closed for all clones of this client. To make sure the close
+    /// frame reaches the tracker, wait for the endpoint to be idle before exiting.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        let res = self.flush().await;
+        self.0.connection.close(CLOSE_CODE.into(), CLOSE_REASON);
+        self.0.connection.closed().await;
+        res.map(|_| ())
+    }
+
+    fn sign(&self, content: HashAndFormat, kind: AnnounceKind) -> anyhow::Result<SignedAnnounce> {
+        let announce = Announce {
+            host: self.0.secret_key.public(),
+            content,
+            kind,
+            timestamp: AbsoluteTime::now(),
+            metadata: AnnounceMetadata::default(),
+            namespace: None,
+            class: SeedClass::default(),
+            committed_until: None,
+        };
+        SignedAnnounce::new(announce, &self.0.secret_key)
+    }
+}
@@ -1,5 +1,6 @@
 //! Command line arguments.
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use iroh_blobs::HashAndFormat;
 
 #[derive(Parser, Debug)]
 pub struct Args {
@@ -20,4 +21,72 @@ pub struct Args {
 
     #[clap(long)]
     pub quiet: bool,
+
+    /// Run a maintenance command instead of the server.
+    #[clap(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Run a tracker for local testing, and print how to connect to it.
+    ///
+    /// Unlike the default server, this only listens on the iroh port and does not
+    /// publish the addr of the tracker. It runs until interrupted with Ctrl-C.
+    Serve(ServeArgs),
+    /// Maintenance commands for the operator.
+    ///
+    /// These use the database of the tracker, so the tracker must not be running.
+    #[clap(subcommand)]
+    Admin(AdminCommands),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AdminCommands {
+    /// Probe all hosts of some content now, and update their verification.
+    Refresh(RefreshArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct RefreshArgs {
+    /// The content to refresh, as hash and format.
+    pub content: HashAndFormat,
+
+    /// The namespace of the announces to refresh.
+    ///
+    /// The name is hashed to get the namespace id.
+    #[clap(long)]
+    pub namespace: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct ServeArgs {
+    /// The iroh port to listen on. Set to 0 to listen on a random port.
+    #[clap(long, default_value_t = 0)]
+    pub bind_port: u16,
+
+    /// Where to keep announces and probe results.
+    #[clap(long, value_enum, default_value_t = StoreKind::Memory)]
+    pub store: StoreKind,
+
+    /// Time after which an announce is considered stale, e.g. `10m`.
+    #[clap(long)]
+    pub announce_timeout: Option<humantime::Duration>,
+
+    /// Interval between probing hosts, e.g. `30s`.
+    #[clap(long)]
+    pub probe_interval: Option<humantime::Duration>,
+
+    /// The ALPN to accept connections on, instead of the public one.
+    #[clap(long)]
+    pub alpn: Option<String>,
+}
+
+/// The store to use for [ServeArgs].
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    /// A fresh database and node id that are deleted on shutdown.
+    Memory,
+    /// The persistent redb database and node id of the tracker.
+    Redb,
 }
@@ -1,7 +1,7 @@
 //! Utilities for advanced use of iroh::blobs.
 use std::sync::Arc;
 
-use bao_tree::{ChunkNum, ChunkRanges};
+use bao_tree::{io::BaoContentItem, ChunkNum, ChunkRanges};
 use bytes::Bytes;
 use iroh_blobs::{
     get::{
@@ -12,6 +12,7 @@ use iroh_blobs::{
     protocol::{GetRequest, RangeSpecSeq},
     Hash, HashAndFormat,
 };
+use iroh_mainline_content_discovery::protocol::VerificationProof;
 use rand::Rng;
 
 /// Get the claimed size of a blob from a peer.
@@ -134,11 +135,13 @@ pub async fn get_hash_seq_and_sizes(
 }
 
 /// Probe for a single chunk of a blob.
+///
+/// Returns the parents and data that were received, as a proof for the chunk.
 pub async fn chunk_probe(
     connection: &iroh_quinn::Connection,
     hash: &Hash,
     chunk: ChunkNum,
-) -> anyhow::Result<Stats> {
+) -> anyhow::Result<(Stats, VerificationProof)> {
     let ranges = ChunkRanges::from(chunk..chunk + 1);
     let ranges = RangeSpecSeq::from_ranges([ranges]);
     let request = GetRequest::new(*hash, ranges);
@@ -148,11 +151,22 @@ pub async fn chunk_probe(
         unreachable!("query includes root");
     };
     let header = start.next();
-    let (mut curr, _size) = header.next().await?;
+    let (mut curr, size) = header.next().await?;
+    let mut proof = VerificationProof {
+        size,
+        chunk: chunk.0,
+        parents: Vec::new(),
+        data: Vec::new(),
+    };
     let end = loop {
         match curr.next().await {
             BlobContentNext::More((next, res)) => {
-                res?;
+                match res? {
+                    BaoContentItem::Parent(parent) => proof
+                        .parents
+                        .push((parent.pair.0.into(), parent.pair.1.into())),
+                    BaoContentItem::Leaf(leaf) => proof.data.extend_from_slice(&leaf.data),
+                }
                 curr = next;
             }
             BlobContentNext::Done(end) => {
@@ -164,7 +178,7 @@ pub async fn chunk_probe(
         unreachable!("query contains only one blob");
     };
     let stats = closing.next().await?;
-    Ok(stats)
+    Ok((stats, proof))
 }
 
 /// Given a sequence of sizes of children, generate a range spec that selects a
@@ -11,7 +11,11 @@ use std::{
 
 use anyhow::Context;
 use clap::Parser;
-use iroh_mainline_content_discovery::protocol::ALPN;
+use iroh_base::ticket::NodeTicket;
+use iroh_mainline_content_discovery::{
+    protocol::{AbsoluteTime, Namespace, Refresh, SignedRefresh},
+    refresh_verification,
+};
 use iroh_mainline_tracker::{
     io::{
         self, load_from_file, setup_logging, tracker_home, tracker_path, CONFIG_DEBUG_FILE,
@@ -20,11 +24,11 @@ use iroh_mainline_tracker::{
     options::Options,
     tracker::Tracker,
 };
-use iroh_net::{endpoint::get_remote_node_id, Endpoint, NodeId};
+use iroh_net::{endpoint::get_remote_node_id, key::SecretKey, Endpoint, NodeAddr, NodeId};
 use iroh_pkarr_node_discovery::PkarrNodeDiscovery;
 use tokio::io::AsyncWriteExt;
 
-use crate::args::Args;
+use crate::args::{AdminCommands, Args, Commands, RefreshArgs, ServeArgs, StoreKind};
 
 use iroh_mainline_tracker::tracker::get_alpn;
 
@@ -67,6 +71,7 @@ async fn create_endpoint(
     key: iroh_net::key::SecretKey,
     port: u16,
     publish: bool,
+    alpn: &[u8],
 ) -> anyhow::Result<Endpoint> {
     let mainline_discovery = if publish {
         PkarrNodeDiscovery::builder()
@@ -78,7 +83,7 @@ async fn create_endpoint(
     iroh_net::Endpoint::builder()
         .secret_key(key)
         .discovery(Box::new(mainline_discovery))
-        .alpns(vec![ALPN.to_vec()])
+        .alpns(vec![alpn.to_vec()])
         .bind(port)
         .await
 }
@@ -107,8 +112,8 @@ fn write_defaults() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn server(args: Args) -> anyhow::Result<()> {
-    set_verbose(!args.quiet);
+/// Load the options from the config file, overridden by the args.
+async fn load_options(args: &Args) -> anyhow::Result<Options> {
     let home = tracker_home()?;
     tokio::fs::create_dir_all(&home).await?;
     let config_path = tracker_path(CONFIG_FILE)?;
@@ -127,10 +132,16 @@ async fn server(args: Args) -> anyhow::Result<()> {
     if let Some(udp_port) = args.udp_port {
         options.udp_port = udp_port;
     }
-    log!("tracker starting using {}", home.display());
+    Ok(options)
+}
+
+async fn server(args: Args) -> anyhow::Result<()> {
+    set_verbose(!args.quiet);
+    let mut options = load_options(&args).await?;
+    log!("tracker starting using {}", tracker_home()?.display());
     let key_path = tracker_path(SERVER_KEY_FILE)?;
     let key = load_secret_key(key_path).await?;
-    let server_config = configure_server(&key)?;
+    let server_config = configure_server(&key, options.alpn())?;
     let udp_bind_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, options.udp_port));
     let udp_socket = tokio::net::UdpSocket::bind(udp_bind_addr).await?;
     let quinn_bind_addr =
@@ -138,7 +149,8 @@ async fn server(args: Args) -> anyhow::Result<()> {
     let quinn_endpoint = iroh_quinn::Endpoint::server(server_config, quinn_bind_addr)?;
     // set the quinn port to the actual port we bound to so the DHT will announce it correctly
     options.quinn_port = quinn_endpoint.local_addr()?.port();
-    let iroh_endpoint = create_endpoint(key.clone(), options.iroh_port, true).await?;
+    let iroh_endpoint =
+        create_endpoint(key.clone(), options.iroh_port, true, options.alpn()).await?;
     let db = Tracker::new(options, iroh_endpoint.clone())?;
     await_relay_region(&iroh_endpoint).await?;
     let addr = iroh_endpoint.my_addr().await?;
@@ -181,19 +193,131 @@ async fn server(args: Args) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Run a tracker for local testing until interrupted.
+async fn serve(args: &ServeArgs) -> anyhow::Result<()> {
+    // keep the temp dir alive until shutdown, it is deleted on drop
+    let (mut options, key, _temp_dir) = match args.store {
+        StoreKind::Memory => {
+            let dir = tempfile::tempdir()?;
+            let options = Options {
+                announce_data_path: dir.path().join("announce.redb"),
+                ..Options::default()
+            };
+            (options, iroh_net::key::SecretKey::generate(), Some(dir))
+        }
+        StoreKind::Redb => {
+            let home = tracker_home()?;
+            tokio::fs::create_dir_all(&home).await?;
+            let mut options = load_from_file::<Options>(&tracker_path(CONFIG_FILE)?)?;
+            options.make_paths_relative(&home);
+            let key = load_secret_key(tracker_path(SERVER_KEY_FILE)?).await?;
+            (options, key, None)
+        }
+    };
+    options.iroh_port = args.bind_port;
+    if let Some(timeout) = args.announce_timeout {
+        options.announce_timeout = timeout.into();
+    }
+    if let Some(interval) = args.probe_interval {
+        options.probe_interval = interval.into();
+    }
+    if let Some(alpn) = &args.alpn {
+        options.alpn = Some(alpn.clone());
+    }
+    let iroh_endpoint = create_endpoint(key, options.iroh_port, false, options.alpn()).await?;
+    let db = Tracker::new(options, iroh_endpoint.clone())?;
+    let addr = iroh_endpoint.my_addr().await?;
+    println!("node id: {}", addr.node_id);
+    println!("ticket: {}", NodeTicket::new(addr)?);
+    let mut iroh_accept_task = tokio::spawn(db.clone().iroh_accept_loop(iroh_endpoint.clone()));
+    let mut gc_task = tokio::spawn(db.gc_loop());
+    let res = tokio::select! {
+        res = tokio::signal::ctrl_c() => res.map_err(anyhow::Error::from),
+        res = &mut iroh_accept_task => {
+            tracing::error!("iroh accept task exited");
+            res?.map_err(anyhow::Error::from)
+        }
+        res = &mut gc_task => {
+            tracing::error!("gc task exited");
+            res?
+        }
+    };
+    println!("shutting down");
+    iroh_accept_task.abort();
+    gc_task.abort();
+    // let connected clients know, instead of having them run into a timeout
+    iroh_endpoint
+        .close(0u32.into(), b"tracker shutting down")
+        .await?;
+    res
+}
+
+/// Ask the running tracker to probe all hosts of some content.
+///
+/// The database belongs to the running tracker, so this sends it a refresh signed
+/// with the key of the tracker instead of opening the database.
+async fn refresh(args: &Args, refresh: &RefreshArgs) -> anyhow::Result<()> {
+    let options = load_options(args).await?;
+    let key = load_secret_key(tracker_path(SERVER_KEY_FILE)?).await?;
+    let tracker = key.public();
+    // an endpoint can not connect to its own node id, so use a throwaway key
+    let iroh_endpoint = create_endpoint(SecretKey::generate(), 0, false, options.alpn()).await?;
+    let mut addr = NodeAddr::new(tracker);
+    if options.iroh_port != 0 {
+        // with a fixed port, there is no need to look up the tracker
+        let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, options.iroh_port);
+        addr = addr.with_direct_addresses([SocketAddr::V4(local)]);
+    }
+    let connection = iroh_endpoint
+        .connect(addr, options.alpn())
+        .await
+        .context("failed to connect to the tracker, is it running?")?;
+    let refresh_msg = Refresh {
+        admin: tracker,
+        timestamp: AbsoluteTime::now(),
+        content: refresh.content,
+        namespace: refresh.namespace.as_deref().map(Namespace::from_name),
+    };
+    let signed_refresh = SignedRefresh::new(refresh_msg, &key)?;
+    let summary = refresh_verification(connection, signed_refresh).await?;
+    for host in &summary.verified {
+        println!("{}: verified", host);
+    }
+    for host in &summary.failing {
+        println!("{}: failing", host);
+    }
+    for host in &summary.unreachable {
+        println!("{}: unreachable", host);
+    }
+    println!(
+        "{} verified, {} failing, {} unreachable",
+        summary.verified.len(),
+        summary.failing.len(),
+        summary.unreachable.len()
+    );
+    Ok(())
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
     setup_logging();
     let args = Args::parse();
-    server(args).await
+    match &args.command {
+        Some(Commands::Serve(serve_args)) => serve(serve_args).await,
+        Some(Commands::Admin(AdminCommands::Refresh(refresh_args))) => {
+            refresh(&args, refresh_args).await
+        }
+        None => server(args).await,
+    }
 }
 
 /// Returns default server configuration along with its certificate.
 #[allow(clippy::field_reassign_with_default)] // https://github.com/rust-lang/rust-clippy/issues/6527
 fn configure_server(
     secret_key: &iroh_net::key::SecretKey,
+    alpn: &[u8],
 ) -> anyhow::Result<iroh_quinn::ServerConfig> {
-    make_server_config(secret_key, 8, 1024, vec![ALPN.to_vec()])
+    make_server_config(secret_key, 8, 1024, vec![alpn.to_vec()])
 }
 
 /// Create a [`quinn::ServerConfig`] with the given secret key and limits.
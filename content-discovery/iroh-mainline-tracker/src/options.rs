@@ -4,6 +4,7 @@ use std::{
     time::Duration,
 };
 
+use iroh_mainline_content_discovery::protocol::ALPN;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,6 +64,44 @@ pub struct Options {
 
     /// The UDP port to listen on. Set to 0 to listen on a random port.
     pub udp_port: u16,
+
+    /// The window over which queries are counted per content, for top content requests.
+    ///
+    /// Only counts per content are kept, in memory, and nothing about the queriers.
+    /// Set to 0 to not count queries at all, which is the default.
+    #[serde(default, with = "serde_duration")]
+    pub query_stats_window: Duration,
+
+    /// The ALPN to accept connections on, for a tracker network that is separate from
+    /// the public one, e.g. `myorg/tracker/1`.
+    ///
+    /// Clients must connect with the same ALPN. The default is the public ALPN.
+    #[serde(default)]
+    pub alpn: Option<String>,
+
+    /// How long to collect changes to some content before updating subscribers.
+    ///
+    /// A longer window means fewer and larger updates when many hosts announce the
+    /// same content at once, at the cost of subscribers learning about new hosts
+    /// later. Set to 0 to update subscribers for every change.
+    #[serde(default = "default_subscribe_coalesce_window", with = "serde_duration")]
+    pub subscribe_coalesce_window: Duration,
+
+    /// The maximum number of items in a single batch request, i.e. the content of a
+    /// batch query, or the announces of a batch announce or an announce set.
+    ///
+    /// Larger requests are rejected with the limit, so clients can split them. A top
+    /// content request returns at most this many items.
+    #[serde(default = "default_max_request_items")]
+    pub max_request_items: usize,
+}
+
+fn default_subscribe_coalesce_window() -> Duration {
+    Duration::from_millis(200)
+}
+
+fn default_max_request_items() -> usize {
+    256
 }
 
 impl Default for Options {
@@ -83,11 +122,20 @@ impl Default for Options {
             quinn_port: 0,
             iroh_port: 0,
             udp_port: 0,
+            query_stats_window: Duration::ZERO,
+            alpn: None,
+            subscribe_coalesce_window: default_subscribe_coalesce_window(),
+            max_request_items: default_max_request_items(),
         }
     }
 }
 
 impl Options {
+    /// The ALPN to accept connections on.
+    pub fn alpn(&self) -> &[u8] {
+        self.alpn.as_deref().map(str::as_bytes).unwrap_or(ALPN)
+    }
+
     /// Debug options for testing. These will spam the DHT and all peers,
     /// so use with care.
     pub fn debug() -> Self {
@@ -107,6 +155,10 @@ impl Options {
             quinn_port: 0,
             iroh_port: 0,
             udp_port: 0,
+            query_stats_window: Duration::ZERO,
+            alpn: None,
+            subscribe_coalesce_window: default_subscribe_coalesce_window(),
+            max_request_items: default_max_request_items(),
         }
     }
 
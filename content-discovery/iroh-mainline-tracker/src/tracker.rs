@@ -1,11 +1,13 @@
 //! The tracker server
 use std::{
     collections::{BTreeMap, BTreeSet},
+    ops::Bound,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use bao_tree::ChunkNum;
+use futures::StreamExt;
 use iroh_blobs::{
     get::{fsm::EndBlobNext, Stats},
     hashseq::HashSeq,
@@ -13,19 +15,25 @@ use iroh_blobs::{
     BlobFormat, Hash, HashAndFormat,
 };
 use iroh_mainline_content_discovery::{
+    codec::{decode_request, encode_frame, Codec, EmptyRequest, Postcard},
     protocol::{
-        AbsoluteTime, Announce, AnnounceKind, Query, QueryResponse, Request, Response,
-        SignedAnnounce, REQUEST_SIZE_LIMIT,
+        validate_alpn, AbsoluteTime, Announce, AnnounceConstraint, AnnounceKind, AnnounceMetadata,
+        AnnounceRejection, AnnounceSetResult, BatchQueryItem, ContentKey, DetailLevel, EmptyReason,
+        ErrorResponse, HostCursor, ItemResult, Namespace, Query, QueryBatch, QueryFlags,
+        QueryResponse, RefreshSummary, Request, Response, SeedClass, SignedAnnounce,
+        SignedAnnounceToken, SignedRefresh, SignedUnannounce, TooManyItems, UnsupportedRequest,
+        VerificationMethod, VerificationProof, MAX_PROOFS_PER_RESPONSE, REQUEST_SIZE_LIMIT,
     },
     to_infohash,
 };
-use iroh_net::{endpoint::get_remote_node_id, Endpoint, NodeId};
-use rand::Rng;
+use iroh_net::{endpoint::get_remote_node_id, Endpoint, NodeAddr, NodeId};
+use rand::{seq::SliceRandom, Rng};
 use redb::{ReadableTable, RedbValue};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
 
+mod query_stats;
 mod tables;
 mod util;
 
@@ -39,18 +47,29 @@ use crate::{
 };
 
 use self::{
+    query_stats::QueryStats,
     tables::{ReadOnlyTables, ReadableTables, Tables},
     util::PeekableFlumeReceiver,
 };
 
+/// The number of hosts probed at the same time by [Tracker::refresh_verification],
+/// and for verified batch queries.
+const REFRESH_PARALLELISM: usize = 16;
+
 /// The tracker server.
 ///
 /// This is a cheaply cloneable handle to the state and the options.
 #[derive(Debug, Clone)]
 pub struct Tracker(Arc<Inner>);
 
+/// A hook that is called for every query, e.g. for audit logging.
+///
+/// The node id is the querier. For signed queries it is the signer, for other queries
+/// over QUIC the id authenticated by the connection. It is `None` for queries via UDP.
+pub type QueryHook = Arc<dyn Fn(Option<NodeId>, &Query) + Send + Sync>;
+
 /// The inner state of the tracker server. Options are immutable and don't need to be locked.
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 struct Inner {
     actor: flume::Sender<ActorMessage>,
     /// The options for the tracker server.
@@ -68,6 +87,17 @@ struct Inner {
     local_pool: tokio_util::task::LocalPoolHandle,
     /// The handle to the actor thread.
     handle: Option<std::thread::JoinHandle<()>>,
+    /// Called for every query.
+    #[debug(skip)]
+    on_query: std::sync::RwLock<Option<QueryHook>>,
+    /// Query counts per content, if enabled.
+    query_stats: Option<std::sync::Mutex<QueryStats>>,
+    /// The proof of the last successful chunk probe, per raw blob and host.
+    ///
+    /// This is only kept in memory, and replaced with every probe.
+    proofs: std::sync::Mutex<BTreeMap<(Hash, NodeId), VerificationProof>>,
+    /// Notifies subscribers of changes, per subscribed content.
+    subscriptions: std::sync::Mutex<BTreeMap<HashAndFormat, watch::Sender<()>>>,
 }
 
 impl Drop for Inner {
@@ -92,6 +122,24 @@ enum ActorMessage {
         #[debug(skip)]
         tx: oneshot::Sender<anyhow::Result<AnnounceResponse>>,
     },
+    AnnounceToken {
+        token: SignedAnnounceToken,
+        #[debug(skip)]
+        tx: oneshot::Sender<anyhow::Result<AnnounceResponse>>,
+    },
+    AnnounceSet {
+        announces: Vec<SignedAnnounce>,
+        #[debug(skip)]
+        #[allow(clippy::type_complexity)]
+        tx: oneshot::Sender<
+            anyhow::Result<std::result::Result<Vec<AnnounceResponse>, AnnounceRejection>>,
+        >,
+    },
+    Unannounce {
+        unannounce: SignedUnannounce,
+        #[debug(skip)]
+        tx: oneshot::Sender<anyhow::Result<()>>,
+    },
     Query {
         query: Query,
         #[debug(skip)]
@@ -147,6 +195,9 @@ impl ActorMessage {
     fn category(&self) -> MessageCategory {
         match self {
             Self::Announce { .. }
+            | Self::AnnounceToken { .. }
+            | Self::AnnounceSet { .. }
+            | Self::Unannounce { .. }
             | Self::SetSize { .. }
             | Self::SetSizes { .. }
             | Self::StoreProbeResult { .. }
@@ -261,6 +312,20 @@ impl Actor {
                 let response = self.handle_announce(tables, announce);
                 tx.send(response).ok();
             }
+            ActorMessage::AnnounceToken { token, tx } => {
+                let response = self.handle_announce_token(tables, token);
+                tx.send(response).ok();
+            }
+            ActorMessage::AnnounceSet { announces, tx } => {
+                // a storage error would leave a partial set in the transaction, so it
+                // must abort the transaction instead of being sent to the caller
+                let response = self.handle_announce_set(tables, announces)?;
+                tx.send(Ok(response)).ok();
+            }
+            ActorMessage::Unannounce { unannounce, tx } => {
+                let response = self.handle_unannounce(tables, unannounce);
+                tx.send(response).ok();
+            }
             ActorMessage::SetSize { hash, size } => {
                 self.state.sizes.insert(hash, size);
             }
@@ -308,33 +373,99 @@ impl Actor {
         &mut self,
         tables: &mut Tables,
         signed_announce: SignedAnnounce,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let now = AbsoluteTime::now();
+        // an old announce is a replay, or the clock of the host is way off
+        anyhow::ensure!(
+            is_fresh(
+                now,
+                signed_announce.timestamp,
+                self.options.announce_timeout
+            ),
+            "announce is too old or too far in the future, check the clock of the host"
+        );
+        self.store_announce(tables, signed_announce, now)
+    }
+
+    /// Store a set of announces, either all of them or none.
+    ///
+    /// All constraints are checked before anything is written, so a rejected set
+    /// leaves the tables unchanged.
+    fn handle_announce_set(
+        &mut self,
+        tables: &mut Tables,
+        announces: Vec<SignedAnnounce>,
+    ) -> anyhow::Result<std::result::Result<Vec<AnnounceResponse>, AnnounceRejection>> {
+        let now = AbsoluteTime::now();
+        for (index, announce) in announces.iter().enumerate() {
+            let constraint = if announce.verify().is_err() {
+                AnnounceConstraint::Signature
+            } else if !is_fresh(now, announce.timestamp, self.options.announce_timeout) {
+                AnnounceConstraint::Freshness
+            } else {
+                continue;
+            };
+            return Ok(Err(AnnounceRejection {
+                index: index as u32,
+                content: announce.content,
+                constraint,
+            }));
+        }
+        let mut responses = Vec::with_capacity(announces.len());
+        for announce in announces {
+            responses.push(self.store_announce(tables, announce, now)?);
+        }
+        Ok(Ok(responses))
+    }
+
+    /// Store an announce, if it is newer than the one we have.
+    ///
+    /// The announce is stored with the time it was received, so expiry does not
+    /// depend on the clock of the host. An announce that is not newer, like a
+    /// repeated datagram or one from a host that set its clock back, still refreshes
+    /// the time it was received.
+    fn store_announce(
+        &mut self,
+        tables: &mut Tables,
+        signed_announce: SignedAnnounce,
+        received: AbsoluteTime,
     ) -> anyhow::Result<AnnounceResponse> {
         tracing::info!("got announce");
         signed_announce.verify()?;
         tracing::info!("verified announce: {:?}", signed_announce);
         let content = signed_announce.content;
-        let (path, value1) = split_signed_announce(signed_announce);
+        let namespace = signed_announce.namespace;
+        let (path, value1, metadata) = split_signed_announce(signed_announce, received);
         // true if this is entirely new content, false if it is just a new host for existing content
         // if this is true we need to start announcing it to the DHT
         let new_content = tables
             .announces
-            .range(AnnouncePath::content_min(content)..=AnnouncePath::content_max(content))?
+            .range(
+                AnnouncePath::content_min(namespace, content)
+                    ..=AnnouncePath::content_max(namespace, content),
+            )?
             .next()
             .transpose()?
             .is_none();
         let prev = tables.announces.get(path)?.map(|x| x.value());
         // new_host_for_content is true if this is a new host for this path (content, kind, node), false if it is just an update
         // if this is true we need to start probing it
-        //
-        // update is true if the new announce is newer than the previous one
-        // if this is true we need to store the new announce
-        let (new_host_for_content, update) = if let Some(prev) = prev {
-            (false, prev.timestamp < value1.timestamp)
-        } else {
-            (true, true)
-        };
-        if update {
-            tables.announces.insert(path, value1)?;
+        let new_host_for_content = prev.is_none();
+        match prev {
+            Some(prev) if prev.timestamp >= value1.timestamp => {
+                // keep the newer signed announce, but the host is still alive
+                tables
+                    .announces
+                    .insert(path, AnnounceValue { received, ..prev })?;
+            }
+            _ => {
+                tables.announces.insert(path, value1)?;
+                if metadata == MetadataValue::default() {
+                    tables.metadata.remove(path)?;
+                } else {
+                    tables.metadata.insert(path, metadata)?;
+                }
+            }
         }
         Ok(AnnounceResponse {
             new_content,
@@ -342,47 +473,224 @@ impl Actor {
         })
     }
 
+    fn handle_announce_token(
+        &mut self,
+        tables: &mut Tables,
+        token: SignedAnnounceToken,
+    ) -> anyhow::Result<AnnounceResponse> {
+        tracing::info!("got announce token");
+        let now = AbsoluteTime::now();
+        token.verify(now)?;
+        tracing::info!("verified announce token: {:?}", token);
+        let announce = &token.announce;
+        let path = AnnouncePath::new(
+            announce.namespace,
+            announce.content,
+            announce.kind,
+            announce.host,
+        );
+        // the announce in a token can be old, the token keeps it alive
+        let response = self.store_announce(tables, announce.clone(), now)?;
+        let prev = tables.tokens.get(path)?.map(|x| x.value().valid_until);
+        // only extend the validity, a token can not be used to shorten it
+        if prev.map(|t| t < token.valid_until).unwrap_or(true) {
+            tables
+                .tokens
+                .insert(path, TokenValue::from(token.valid_until))?;
+        }
+        Ok(response)
+    }
+
+    /// Remove all announces of a host that are older than the unannounce.
+    ///
+    /// Todo: this is a full table scan, could be optimized.
+    fn handle_unannounce(
+        &mut self,
+        tables: &mut Tables,
+        unannounce: SignedUnannounce,
+    ) -> anyhow::Result<()> {
+        tracing::info!("got unannounce");
+        unannounce.verify()?;
+        tracing::info!("verified unannounce: {:?}", unannounce);
+        let mut to_remove = Vec::new();
+        for entry in tables.announces.iter()? {
+            let (path, value) = entry?;
+            let path = path.value();
+            if path.node() != unannounce.host {
+                continue;
+            }
+            if value.value().timestamp <= unannounce.timestamp {
+                to_remove.push(path);
+            }
+        }
+        for path in to_remove {
+            tracing::trace!("removing announce {}", path.format_short());
+            remove_announce(tables, &path)?;
+        }
+        Ok(())
+    }
+
     fn handle_query(
         &self,
         query: Query,
         tables: &impl ReadableTables,
     ) -> anyhow::Result<QueryResponse> {
-        let iter = tables.announces().range(
-            AnnouncePath::content_min(query.content)..=AnnouncePath::content_max(query.content),
-        )?;
+        let start = match query.cursor {
+            Some(cursor) => Bound::Excluded(AnnouncePath::new(
+                query.namespace,
+                query.content,
+                cursor.kind,
+                cursor.host,
+            )),
+            None => Bound::Included(AnnouncePath::content_min(query.namespace, query.content)),
+        };
+        let end = Bound::Included(AnnouncePath::content_max(query.namespace, query.content));
+        let iter = tables.announces().range((start, end))?;
+        // a sample is drawn from all hosts, so it is never paginated. An empty page
+        // would never get to a next page, so it means no pagination.
+        let page_size = query
+            .flags
+            .page_size
+            .filter(|n| *n > 0 && query.flags.sample.is_none());
+        let mut next_cursor = None;
         let options = &self.options;
         let now = AbsoluteTime::now();
         let kind: AnnounceKind = AnnounceKind::from_complete(query.flags.complete);
-        let mut announces = Vec::new();
+        let mut announces: Vec<SignedAnnounce> = Vec::new();
+        // count the hosts dropped at each stage, to explain an empty response
+        let mut filtered = 0;
+        let mut expired = 0;
+        let mut unverified = 0;
+        let mut verified = BTreeSet::new();
+        let mut stale = BTreeSet::new();
+        let mut cache_for = None;
         for entry in iter {
             let (path, value) = entry?;
             let path = path.value();
             let value = value.value();
             if kind == AnnounceKind::Complete && path.announce_kind() == AnnounceKind::Partial {
                 // we only want complete announces
+                filtered += 1;
                 continue;
             }
-            let recently_announced = now - value.timestamp <= options.announce_timeout;
-            if !recently_announced {
-                // announce is too old
-                tracing::error!("announce is too old");
+            let metadata = tables
+                .metadata()
+                .get(&path)?
+                .map(|x| x.value())
+                .unwrap_or_default();
+            if query
+                .flags
+                .min_class
+                .is_some_and(|min| metadata.class < min)
+            {
+                // the host does not claim to be available enough
+                filtered += 1;
                 continue;
             }
-            if query.flags.verified {
-                let last_probed = tables.probes().get(&path)?.map(|x| x.value().timestamp);
-                let recently_probed = last_probed
-                    .map(|t| now - t <= options.probe_timeout)
+            let recently_announced = now - value.received <= options.announce_timeout;
+            let token_valid_until = tables.tokens().get(&path)?.map(|x| x.value().valid_until);
+            let valid_token = token_valid_until.map(|t| t >= now).unwrap_or_default();
+            // an expired announce is still returned as stale within the grace window
+            let mut is_stale = false;
+            if !recently_announced && !valid_token {
+                let within_grace = query
+                    .flags
+                    .include_expired
+                    .map(|grace| now - value.received <= options.announce_timeout + grace)
                     .unwrap_or_default();
-                if !recently_probed {
-                    // query asks for verificated hosts, but the last successful probe is too old
-                    tracing::error!("verification of complete data is too old");
+                if !within_grace {
+                    // announce is too old, and there is no token to keep it alive
+                    tracing::error!("announce is too old");
+                    expired += 1;
                     continue;
                 }
+                is_stale = true;
+            }
+            let last_probed = tables
+                .probes()
+                .get(&path.without_namespace())?
+                .map(|x| x.value().timestamp);
+            let recently_probed = last_probed
+                .map(|t| now - t <= options.probe_timeout)
+                .unwrap_or_default();
+            let require_verified = query.flags.verified || query.flags.require_method.is_some();
+            if require_verified && !recently_probed {
+                // query asks for verificated hosts, but the last successful probe is too old
+                tracing::error!("verification of complete data is too old");
+                unverified += 1;
+                continue;
+            }
+            let method = VerificationMethod::for_kind(path.announce_kind());
+            if query.flags.require_method.is_some_and(|min| method < min) {
+                // the host was verified, but not thoroughly enough
+                unverified += 1;
+                continue;
+            }
+            if page_size.is_some_and(|n| announces.len() >= n as usize) {
+                // there is at least one more host, so there is a next page
+                next_cursor = announces.last().map(|sa| HostCursor::after(&sa.announce));
+                break;
+            }
+            if recently_probed {
+                verified.insert(path.node());
+            }
+            // the host stays in the response until the announce and the token expire,
+            // or, for a verified query, until the probe is too old
+            let mut ttl = time_until(now, value.received, options.announce_timeout);
+            if let Some(valid_until) = token_valid_until {
+                ttl = ttl.max(time_until(now, valid_until, Duration::ZERO));
+            }
+            if is_stale {
+                // a stale host stays until the grace window ends
+                let grace = query.flags.include_expired.unwrap_or_default();
+                ttl = time_until(now, value.received, options.announce_timeout + grace);
+                stale.insert(path.node());
             }
-            let signed_announce = join_signed_announce(path, value);
+            if let (true, Some(last_probed)) = (require_verified, last_probed) {
+                ttl = ttl.min(time_until(now, last_probed, options.probe_timeout));
+            }
+            cache_for = Some(cache_for.map_or(ttl, |c: Duration| c.min(ttl)));
+            let signed_announce = join_signed_announce(path, value, metadata);
             announces.push(signed_announce);
         }
-        Ok(QueryResponse { hosts: announces })
+        if let Some(n) = query.flags.sample {
+            // shuffle everything so the sample is uniform and in random order
+            announces.shuffle(&mut rand::thread_rng());
+            announces.truncate(n as usize);
+        }
+        if query.flags.prefer_committed {
+            // stable sort, so the order is kept within committed and other hosts
+            announces.sort_by_key(|sa| !sa.is_committed(now));
+        }
+        // only report verification and staleness for hosts that made it into the response
+        let hosts = announces.iter().map(|sa| sa.host).collect::<BTreeSet<_>>();
+        let verified = verified.intersection(&hosts).copied().collect::<Vec<_>>();
+        let stale = stale.intersection(&hosts).copied().collect::<Vec<_>>();
+        let empty_reason = if !announces.is_empty() || query.flags.sample == Some(0) {
+            None
+        } else if unverified > 0 {
+            Some(EmptyReason::Unverified { known: unverified })
+        } else if expired > 0 {
+            Some(EmptyReason::Expired { known: expired })
+        } else if filtered > 0 {
+            Some(EmptyReason::Filtered { known: filtered })
+        } else {
+            Some(EmptyReason::NeverAnnounced)
+        };
+        Ok(QueryResponse {
+            hosts: announces,
+            host_ids: Vec::new(),
+            // filled in by the tracker, the actor does not know about connections
+            node_addrs: Vec::new(),
+            verified,
+            stale,
+            empty_reason,
+            cache_for,
+            server_time: now,
+            next_cursor,
+            content_size: None,
+            proofs: Vec::new(),
+        })
     }
 
     fn store_probe_result(
@@ -394,7 +702,8 @@ impl Actor {
     ) -> anyhow::Result<()> {
         for (content, announce_kind, result) in &results {
             if result.is_ok() {
-                let path = AnnouncePath::new(*content, *announce_kind, node);
+                // probes are stored without namespace
+                let path = AnnouncePath::new(None, *content, *announce_kind, node);
                 tables.probes.insert(path, ProbeValue::from(now))?;
             }
         }
@@ -436,11 +745,11 @@ impl Actor {
         let now = AbsoluteTime::now();
         let options = &self.options;
         let mut to_remove = Vec::new();
-        for item in tables.probes.iter()? {
+        for item in tables.announces.iter()? {
             let (path, value) = item?;
             let path = path.value();
             let value = value.value();
-            let announce_age = now - value.timestamp;
+            let announce_age = now - value.received;
             if announce_age <= options.announce_expiry {
                 tracing::debug!(
                     "keeping announce {} because it was announced {}s ago",
@@ -450,7 +759,21 @@ impl Actor {
                 // announce is recent, keep it
                 continue;
             }
-            if let Some(last_probe) = tables.probes.get(&path)?.map(|x| x.value().timestamp) {
+            if let Some(valid_until) = tables.tokens.get(&path)?.map(|x| x.value().valid_until) {
+                // announce is expired, but there is a token that keeps it alive
+                if valid_until >= now {
+                    tracing::trace!(
+                        "keeping expired announce {} because of a token",
+                        path.format_short(),
+                    );
+                    continue;
+                }
+            }
+            let last_probe = tables
+                .probes
+                .get(&path.without_namespace())?
+                .map(|x| x.value().timestamp);
+            if let Some(last_probe) = last_probe {
                 // announce is expired, but we have probed it recently, keep it
                 let age = now - last_probe;
                 if age <= options.probe_expiry {
@@ -466,36 +789,95 @@ impl Actor {
             to_remove.push(path);
         }
         for path in to_remove {
-            tables.announces.remove(&path)?;
-            tables.probes.remove(&path)?;
+            remove_announce(tables, &path)?;
         }
         Ok(())
     }
 }
 
-fn split_signed_announce(announce: SignedAnnounce) -> (AnnouncePath, AnnounceValue) {
-    let path = AnnouncePath::new(announce.content, announce.kind, announce.host);
+/// How far the timestamp of an announce may be ahead of the clock of the tracker.
+///
+/// Only a newer announce replaces the stored one, so without a bound, a host with a
+/// clock that is far ahead could not update its announce after fixing its clock.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(10 * 60);
+
+/// True if an announce with the given timestamp is recent by the clock of the tracker.
+///
+/// A timestamp in the future, from a host with a clock that is ahead, counts as
+/// recent, up to [MAX_CLOCK_SKEW]. Expiry is based on the time the announce was
+/// received, so this does not make the announce live longer.
+fn is_fresh(now: AbsoluteTime, timestamp: AbsoluteTime, announce_timeout: Duration) -> bool {
+    now - timestamp <= announce_timeout && timestamp - now <= MAX_CLOCK_SKEW
+}
+
+/// The time from `now` until `t + offset`, or zero if that is in the past.
+fn time_until(now: AbsoluteTime, t: AbsoluteTime, offset: Duration) -> Duration {
+    let until = t.as_micros().saturating_add(offset.as_micros() as u64);
+    Duration::from_micros(until.saturating_sub(now.as_micros()))
+}
+
+/// Remove an announce and everything we know about it.
+fn remove_announce(tables: &mut Tables, path: &AnnouncePath) -> anyhow::Result<()> {
+    tables.announces.remove(path)?;
+    tables.probes.remove(path)?;
+    tables.tokens.remove(path)?;
+    tables.metadata.remove(path)?;
+    Ok(())
+}
+
+fn split_signed_announce(
+    announce: SignedAnnounce,
+    received: AbsoluteTime,
+) -> (AnnouncePath, AnnounceValue, MetadataValue) {
+    let path = AnnouncePath::new(
+        announce.namespace,
+        announce.content,
+        announce.kind,
+        announce.host,
+    );
     let value = AnnounceValue {
         timestamp: announce.timestamp,
+        received,
         signature: announce.signature,
     };
-    (path, value)
+    let metadata = MetadataValue {
+        metadata: announce.announce.metadata,
+        class: announce.announce.class,
+        committed_until: announce.announce.committed_until,
+    };
+    (path, value, metadata)
 }
 
-fn join_signed_announce(path: AnnouncePath, value: AnnounceValue) -> SignedAnnounce {
+fn join_signed_announce(
+    path: AnnouncePath,
+    value: AnnounceValue,
+    metadata: MetadataValue,
+) -> SignedAnnounce {
     SignedAnnounce {
         announce: Announce {
             content: path.content(),
             kind: path.announce_kind(),
             host: path.node(),
             timestamp: value.timestamp,
+            metadata: metadata.metadata,
+            namespace: path.namespace(),
+            class: metadata.class,
+            committed_until: metadata.committed_until,
         },
         signature: value.signature,
     }
 }
 
+/// The key for all tables.
+///
+/// The namespace comes first, so the announces for some content in a namespace
+/// are a contiguous range.
 #[derive(derive_more::Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct AnnouncePath {
+    #[debug(skip)]
+    has_namespace: u8,
+    #[debug("{:?}", self.namespace())]
+    namespace: [u8; 32],
     #[debug("{}", self.content().hash)]
     hash: [u8; 32],
     #[debug("{:?}", self.content().format)]
@@ -507,8 +889,20 @@ struct AnnouncePath {
 }
 
 impl AnnouncePath {
-    fn new(content: HashAndFormat, kind: AnnounceKind, node: NodeId) -> Self {
+    /// The path of an announce.
+    ///
+    /// Content is stored under its [ContentKey], so all ways of referring to the same
+    /// content end up at the same path.
+    fn new(
+        namespace: Option<Namespace>,
+        content: impl ContentKey,
+        kind: AnnounceKind,
+        node: NodeId,
+    ) -> Self {
+        let content = content.content_key();
         Self {
+            has_namespace: namespace.is_some() as u8,
+            namespace: namespace.map(|ns| ns.0).unwrap_or_default(),
             hash: *content.hash.as_bytes(),
             format: content.format as u8,
             kind: kind as u8,
@@ -516,6 +910,26 @@ impl AnnouncePath {
         }
     }
 
+    fn namespace(&self) -> Option<Namespace> {
+        if self.has_namespace == 0 {
+            None
+        } else {
+            Some(Namespace(self.namespace))
+        }
+    }
+
+    /// The same path in the default namespace.
+    ///
+    /// Probes are stored without namespace, since whether a node has some content
+    /// does not depend on the namespace it was announced in.
+    fn without_namespace(&self) -> Self {
+        Self {
+            has_namespace: 0,
+            namespace: [0; 32],
+            ..*self
+        }
+    }
+
     fn content(&self) -> HashAndFormat {
         HashAndFormat {
             hash: Hash::from_bytes(self.hash),
@@ -539,27 +953,44 @@ impl AnnouncePath {
         NodeId::from_bytes(&self.node).unwrap()
     }
 
-    fn content_min(content: HashAndFormat) -> Self {
+    /// The first path of the announces for some content.
+    ///
+    /// The range of paths for some content covers all kinds and hosts, but only the
+    /// format of its [ContentKey]. A raw blob and a hash seq with the same hash are
+    /// different content.
+    fn content_min(namespace: Option<Namespace>, content: impl ContentKey) -> Self {
+        let content = content.content_key();
         Self {
+            has_namespace: namespace.is_some() as u8,
+            namespace: namespace.map(|ns| ns.0).unwrap_or_default(),
             hash: *content.hash.as_bytes(),
-            format: 0,
+            format: content.format as u8,
             kind: 0,
             node: [0; 32],
         }
     }
 
-    fn content_max(content: HashAndFormat) -> Self {
+    /// The last path of the announces for some content, see [Self::content_min].
+    fn content_max(namespace: Option<Namespace>, content: impl ContentKey) -> Self {
+        let content = content.content_key();
         Self {
+            has_namespace: namespace.is_some() as u8,
+            namespace: namespace.map(|ns| ns.0).unwrap_or_default(),
             hash: *content.hash.as_bytes(),
-            format: 255,
+            format: content.format as u8,
             kind: 255,
             node: [255; 32],
         }
     }
 
     fn format_short(&self) -> String {
+        let namespace = self
+            .namespace()
+            .map(|ns| ns.to_string()[..8].to_owned())
+            .unwrap_or_else(|| "-".to_owned());
         format!(
-            "({}, {:?}, {:?}, {})",
+            "({}, {}, {:?}, {:?}, {})",
+            namespace,
             self.content().hash,
             self.content().format,
             self.announce_kind(),
@@ -571,23 +1002,28 @@ impl AnnouncePath {
 impl redb::RedbValue for AnnouncePath {
     type SelfType<'a> = Self;
 
-    type AsBytes<'a> = [u8; 66];
+    type AsBytes<'a> = [u8; 99];
 
     fn fixed_width() -> Option<usize> {
-        Some(66)
+        Some(99)
     }
 
     fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
     where
         Self: 'a,
     {
+        let has_namespace = data[0];
+        let mut namespace = [0; 32];
+        namespace.copy_from_slice(&data[1..33]);
         let mut hash = [0; 32];
-        hash.copy_from_slice(&data[0..32]);
-        let format = data[32];
-        let kind = data[33];
+        hash.copy_from_slice(&data[33..65]);
+        let format = data[65];
+        let kind = data[66];
         let mut node = [0; 32];
-        node.copy_from_slice(&data[34..66]);
+        node.copy_from_slice(&data[67..99]);
         Self {
+            has_namespace,
+            namespace,
             hash,
             format,
             kind,
@@ -600,11 +1036,13 @@ impl redb::RedbValue for AnnouncePath {
         Self: 'a,
         Self: 'b,
     {
-        let mut res = [0; 66];
-        res[0..32].copy_from_slice(&value.hash);
-        res[32] = value.format;
-        res[33] = value.kind;
-        res[34..66].copy_from_slice(&value.node);
+        let mut res = [0; 99];
+        res[0] = value.has_namespace;
+        res[1..33].copy_from_slice(&value.namespace);
+        res[33..65].copy_from_slice(&value.hash);
+        res[65] = value.format;
+        res[66] = value.kind;
+        res[67..99].copy_from_slice(&value.node);
         res
     }
 
@@ -621,7 +1059,10 @@ impl redb::RedbKey for AnnouncePath {
 
 #[derive(derive_more::Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 struct AnnounceValue {
+    /// The timestamp signed by the host, by the clock of the host.
     timestamp: AbsoluteTime,
+    /// When the announce was received, by the clock of the tracker.
+    received: AbsoluteTime,
     #[debug("{}", hex::encode(self.signature))]
     #[serde(with = "BigArray")]
     signature: [u8; 64],
@@ -630,23 +1071,26 @@ struct AnnounceValue {
 impl RedbValue for AnnounceValue {
     type SelfType<'a> = Self;
 
-    type AsBytes<'a> = [u8; 72];
+    type AsBytes<'a> = [u8; 80];
 
     fn fixed_width() -> Option<usize> {
-        Some(8 + 64)
+        Some(8 + 8 + 64)
     }
 
     fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
     where
         Self: 'a,
     {
-        assert!(data.len() == 72);
+        assert!(data.len() == 80);
         let mut timestamp = [0; 8];
         timestamp.copy_from_slice(&data[0..8]);
+        let mut received = [0; 8];
+        received.copy_from_slice(&data[8..16]);
         let mut signature = [0; 64];
-        signature.copy_from_slice(&data[8..72]);
+        signature.copy_from_slice(&data[16..80]);
         Self {
             timestamp: AbsoluteTime::from_micros(u64::from_le_bytes(timestamp)),
+            received: AbsoluteTime::from_micros(u64::from_le_bytes(received)),
             signature,
         }
     }
@@ -656,9 +1100,10 @@ impl RedbValue for AnnounceValue {
         Self: 'a,
         Self: 'b,
     {
-        let mut res = [0; 72];
+        let mut res = [0; 80];
         res[0..8].copy_from_slice(&value.timestamp.as_micros().to_le_bytes());
-        res[8..72].copy_from_slice(&value.signature);
+        res[8..16].copy_from_slice(&value.received.as_micros().to_le_bytes());
+        res[16..80].copy_from_slice(&value.signature);
         res
     }
 
@@ -703,6 +1148,80 @@ impl redb::RedbValue for ProbeValue {
     }
 }
 
+#[derive(Debug, derive_more::From)]
+struct TokenValue {
+    valid_until: AbsoluteTime,
+}
+
+impl redb::RedbValue for TokenValue {
+    type SelfType<'a> = Self;
+
+    type AsBytes<'a> = [u8; 8];
+
+    fn fixed_width() -> Option<usize> {
+        Some(8)
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        Self {
+            valid_until: AbsoluteTime::from_micros(u64::from_le_bytes(data.try_into().unwrap())),
+        }
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        value.valid_until.as_micros().to_le_bytes()
+    }
+
+    fn type_name() -> redb::TypeName {
+        redb::TypeName::new("token-value")
+    }
+}
+
+/// Announce metadata, seed class and commitment, stored separately since they are
+/// optional and variable size.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct MetadataValue {
+    metadata: AnnounceMetadata,
+    class: SeedClass,
+    committed_until: Option<AbsoluteTime>,
+}
+
+impl redb::RedbValue for MetadataValue {
+    type SelfType<'a> = Self;
+
+    type AsBytes<'a> = Vec<u8>;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        postcard::from_bytes(data).unwrap_or_default()
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        postcard::to_stdvec(value).unwrap()
+    }
+
+    fn type_name() -> redb::TypeName {
+        redb::TypeName::new("metadata-value")
+    }
+}
+
 /// The mutable state of the tracker server.
 #[derive(Debug, Clone, Default)]
 struct State {
@@ -741,6 +1260,7 @@ impl Tracker {
     ///
     /// You will have to drive the tracker server yourself, using `handle_connection` and `probe_loop`.
     pub fn new(options: Options, endpoint: Endpoint) -> anyhow::Result<Self> {
+        validate_alpn(options.alpn())?;
         tracing::info!(
             "creating tracker using database at {}",
             options.announce_data_path.display()
@@ -766,6 +1286,8 @@ impl Tracker {
             }
         });
         tx.send(ActorMessage::Dump).ok();
+        let query_stats = (!options.query_stats_window.is_zero())
+            .then(|| std::sync::Mutex::new(QueryStats::new(options.query_stats_window)));
         let res = Self(Arc::new(Inner {
             actor: tx,
             options,
@@ -775,6 +1297,10 @@ impl Tracker {
             local_pool: tpc,
             dht,
             handle: Some(handle),
+            on_query: Default::default(),
+            query_stats,
+            proofs: Default::default(),
+            subscriptions: Default::default(),
         }));
         // spawn independent announce tasks for each content item
         for content in dc.content {
@@ -928,20 +1454,81 @@ impl Tracker {
         addr: std::net::SocketAddr,
     ) -> anyhow::Result<()> {
         tracing::trace!("got UDP packet from {}, {} bytes", addr, data.len());
-        let request = postcard::from_bytes::<Request>(data)?;
+        let request = match decode_request(&Postcard, data) {
+            Err(cause) if cause.is::<EmptyRequest>() => {
+                tracing::debug!("ignoring empty UDP packet from {}", addr);
+                return Ok(());
+            }
+            Err(cause) if cause.is::<UnsupportedRequest>() => {
+                // there is no way to reply to requests without a response via udp
+                tracing::debug!("ignoring UDP packet from {}: {}", addr, cause);
+                return Ok(());
+            }
+            res => res?,
+        };
+        if let Some(too_many) = self.too_many_items(&request) {
+            // there is no way to reply with the limit via udp
+            tracing::debug!("ignoring UDP packet from {}: {}", addr, too_many);
+            return Ok(());
+        }
         match request {
             Request::Announce(announce) => {
                 tracing::debug!("got announce: {:?}", announce);
                 self.handle_announce(announce).await?;
             }
 
+            Request::AnnounceToken(token) => {
+                tracing::debug!("got announce token: {:?}", token);
+                self.handle_announce_token(token).await?;
+            }
+
+            Request::Unannounce(unannounce) => {
+                tracing::debug!("got unannounce: {:?}", unannounce);
+                self.handle_unannounce(unannounce).await?;
+            }
+
             Request::Query(query) => {
-                let mut buf = [0u8; 1200];
                 tracing::debug!("handle query: {:?}", query);
-                let response = self.handle_query(query).await?;
-                let response = Response::QueryResponse(response);
-                let response = postcard::to_slice(&response, &mut buf)?;
-                socket.send_to(response, addr).await?;
+                let response = self.handle_query(query, None).await?;
+                send_udp_query_response(socket, addr, response).await?;
+            }
+
+            Request::SignedQuery(signed_query) => {
+                tracing::debug!("handle signed query: {:?}", signed_query);
+                signed_query.verify()?;
+                let querier = Some(signed_query.querier);
+                let response = self.handle_query(signed_query.query.query, querier).await?;
+                send_udp_query_response(socket, addr, response).await?;
+            }
+
+            Request::TopContent(_) => {
+                // the response would rarely fit in a datagram
+                tracing::debug!("ignoring top content request via udp");
+            }
+
+            Request::Subscribe(_) => {
+                // there is no stream to send updates on
+                tracing::debug!("ignoring subscribe request via udp");
+            }
+
+            Request::BatchAnnounce(_) => {
+                // there is no stream to send results on, and batches rarely fit
+                tracing::debug!("ignoring batch announce via udp");
+            }
+
+            Request::QueryBatch(_) => {
+                // the response would rarely fit in a datagram
+                tracing::debug!("ignoring batch query via udp");
+            }
+
+            Request::AnnounceSet(_) => {
+                // there is no way to send the result, and sets rarely fit
+                tracing::debug!("ignoring announce set via udp");
+            }
+
+            Request::Refresh(_) => {
+                // probing takes far longer than a client waits for a datagram
+                tracing::debug!("ignoring refresh via udp");
             }
         }
         Ok(())
@@ -952,11 +1539,81 @@ impl Tracker {
         &self,
         connection: iroh_quinn::Connection,
     ) -> anyhow::Result<()> {
-        tracing::debug!("calling accept_bi");
-        let (mut send, mut recv) = connection.accept_bi().await?;
-        tracing::debug!("got bi stream");
+        self.handle_connection_with_codec(connection, Postcard)
+            .await
+    }
+
+    /// Handle a single incoming connection, using the given codec.
+    ///
+    /// The connection must have been accepted on an ALPN for this codec. Every request
+    /// is sent on its own bi stream, and each stream is handled in its own task, so a
+    /// client can send any number of requests on one connection, also concurrently.
+    /// The number of concurrent streams is bounded by the transport config. This
+    /// returns when the client closes the connection.
+    pub async fn handle_connection_with_codec<C: Codec + Clone + 'static>(
+        &self,
+        connection: iroh_quinn::Connection,
+        codec: C,
+    ) -> anyhow::Result<()> {
+        loop {
+            tracing::debug!("calling accept_bi");
+            let (send, recv) = match connection.accept_bi().await {
+                Ok(streams) => streams,
+                Err(iroh_quinn::ConnectionError::ApplicationClosed(_))
+                | Err(iroh_quinn::ConnectionError::LocallyClosed) => return Ok(()),
+                Err(cause) => return Err(cause.into()),
+            };
+            tracing::debug!("got bi stream");
+            let tracker = self.clone();
+            let connection = connection.clone();
+            let codec = codec.clone();
+            tokio::spawn(async move {
+                if let Err(cause) = tracker.handle_stream(&connection, send, recv, &codec).await {
+                    tracing::error!("error handling request: {}", cause);
+                }
+            });
+        }
+    }
+
+    /// Handle a single request on a bi stream of a connection.
+    async fn handle_stream(
+        &self,
+        connection: &iroh_quinn::Connection,
+        mut send: iroh_quinn::SendStream,
+        mut recv: iroh_quinn::RecvStream,
+        codec: &impl Codec,
+    ) -> anyhow::Result<()> {
         let request = recv.read_to_end(REQUEST_SIZE_LIMIT).await?;
-        let request = postcard::from_bytes::<Request>(&request)?;
+        let request = match decode_request(codec, &request) {
+            Err(cause) if cause.is::<EmptyRequest>() => {
+                // nothing to respond to, just close our side
+                tracing::debug!("got empty request");
+                send.finish().await?;
+                return Ok(());
+            }
+            Err(cause) if cause.is::<UnsupportedRequest>() => {
+                let unsupported = *cause.downcast_ref::<UnsupportedRequest>().unwrap();
+                tracing::debug!("{}", unsupported);
+                let response = Response::Error(ErrorResponse::UnsupportedRequest(unsupported));
+                let response = codec.encode(&response)?;
+                send.write_all(&response).await?;
+                send.finish().await?;
+                return Ok(());
+            }
+            res => res?,
+        };
+        if let Some(too_many) = self.too_many_items(&request) {
+            tracing::debug!("{}", too_many);
+            let response = Response::Error(ErrorResponse::TooManyItems(too_many));
+            // batch announces and announce sets are answered with frames
+            let response = match request {
+                Request::QueryBatch(_) => codec.encode(&response)?,
+                _ => encode_frame(codec, &response)?,
+            };
+            send.write_all(&response).await?;
+            send.finish().await?;
+            return Ok(());
+        }
         match request {
             Request::Announce(announce) => {
                 tracing::debug!("got announce: {:?}", announce);
@@ -964,11 +1621,94 @@ impl Tracker {
                 send.finish().await?;
             }
 
+            Request::AnnounceToken(token) => {
+                tracing::debug!("got announce token: {:?}", token);
+                self.handle_announce_token(token).await?;
+                send.finish().await?;
+            }
+
+            Request::Unannounce(unannounce) => {
+                tracing::debug!("got unannounce: {:?}", unannounce);
+                self.handle_unannounce(unannounce).await?;
+                send.finish().await?;
+            }
+
             Request::Query(query) => {
                 tracing::debug!("handle query: {:?}", query);
-                let response = self.handle_query(query).await?;
+                // the connection authenticates the querier
+                let querier = get_remote_node_id(connection).ok();
+                let response = self.handle_query(query, querier).await?;
+                let response = Response::QueryResponse(response);
+                let response = codec.encode(&response)?;
+                send.write_all(&response).await?;
+                send.finish().await?;
+            }
+
+            Request::SignedQuery(signed_query) => {
+                tracing::debug!("handle signed query: {:?}", signed_query);
+                signed_query.verify()?;
+                let querier = Some(signed_query.querier);
+                let response = self.handle_query(signed_query.query.query, querier).await?;
                 let response = Response::QueryResponse(response);
-                let response = postcard::to_stdvec(&response)?;
+                let response = codec.encode(&response)?;
+                send.write_all(&response).await?;
+                send.finish().await?;
+            }
+
+            Request::TopContent(limit) => {
+                tracing::debug!("handle top content request: {}", limit);
+                // the limit comes from the client, so it does not size the response alone
+                let limit = (limit as usize).min(self.0.options.max_request_items);
+                let response = Response::TopContent(self.top_content(limit));
+                let response = codec.encode(&response)?;
+                send.write_all(&response).await?;
+                send.finish().await?;
+            }
+
+            Request::Subscribe(query) => {
+                tracing::debug!("handle subscribe: {:?}", query);
+                let querier = get_remote_node_id(connection).ok();
+                self.handle_subscribe(query, querier, &mut send, codec)
+                    .await?;
+            }
+
+            Request::BatchAnnounce(announces) => {
+                tracing::debug!("got batch announce of {} items", announces.len());
+                self.handle_batch_announce(announces, &mut send, codec)
+                    .await?;
+                send.finish().await?;
+            }
+
+            Request::QueryBatch(batch) => {
+                tracing::debug!("handle batch query of {} items", batch.content.len());
+                let querier = get_remote_node_id(connection).ok();
+                let items = self.handle_query_batch(batch, querier).await?;
+                let response = codec.encode(&Response::QueryBatch(items))?;
+                send.write_all(&response).await?;
+                send.finish().await?;
+            }
+
+            Request::AnnounceSet(set) => {
+                tracing::debug!(
+                    "got announce set of {} items, atomic: {}",
+                    set.announces.len(),
+                    set.atomic
+                );
+                if set.atomic {
+                    let result = self.handle_atomic_announce(set.announces).await?;
+                    let result = Response::AnnounceSet(result);
+                    send.write_all(&encode_frame(codec, &result)?).await?;
+                } else {
+                    self.handle_batch_announce(set.announces, &mut send, codec)
+                        .await?;
+                }
+                send.finish().await?;
+            }
+
+            Request::Refresh(refresh) => {
+                tracing::debug!("handle refresh: {:?}", refresh);
+                let summary = self.handle_refresh(refresh).await?;
+                let response = codec.encode(&Response::Refresh(summary))?;
                 send.write_all(&response).await?;
                 send.finish().await?;
             }
@@ -976,6 +1716,106 @@ impl Tracker {
         Ok(())
     }
 
+    /// The error for a batch request with more than [Options::max_request_items] items.
+    fn too_many_items(&self, request: &Request) -> Option<TooManyItems> {
+        let requested = match request {
+            Request::BatchAnnounce(announces) => announces.len(),
+            Request::QueryBatch(batch) => batch.content.len(),
+            Request::AnnounceSet(set) => set.announces.len(),
+            _ => return None,
+        };
+        let limit = self.0.options.max_request_items;
+        (requested > limit).then(|| TooManyItems {
+            requested: u32::try_from(requested).unwrap_or(u32::MAX),
+            limit: u32::try_from(limit).unwrap_or(u32::MAX),
+        })
+    }
+
+    /// Handle announces one by one, and send the result for each as a frame.
+    async fn handle_batch_announce(
+        &self,
+        announces: Vec<SignedAnnounce>,
+        send: &mut iroh_quinn::SendStream,
+        codec: &impl Codec,
+    ) -> anyhow::Result<()> {
+        for announce in announces {
+            let content = announce.content;
+            let accepted = match self.handle_announce(announce).await {
+                Ok(()) => true,
+                Err(cause) => {
+                    tracing::debug!("rejected announce for {}: {}", content, cause);
+                    false
+                }
+            };
+            let result = Response::ItemResult(ItemResult { content, accepted });
+            send.write_all(&encode_frame(codec, &result)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the response to a query, and an updated response after every change of
+    /// the queried content, until the subscriber goes away.
+    ///
+    /// Changes are collected for [Options::subscribe_coalesce_window] before sending
+    /// an update, so a burst of announces results in a single update.
+    async fn handle_subscribe(
+        &self,
+        query: Query,
+        querier: Option<NodeId>,
+        send: &mut iroh_quinn::SendStream,
+        codec: &impl Codec,
+    ) -> anyhow::Result<()> {
+        let mut changes = self.subscribe_changes(query.content);
+        loop {
+            let response = self.handle_query(query, querier).await?;
+            let frame = encode_frame(codec, &Response::QueryResponse(response))?;
+            if let Err(cause) = send.write_all(&frame).await {
+                tracing::debug!("subscriber went away: {}", cause);
+                return Ok(());
+            }
+            tokio::select! {
+                res = changes.changed() => res?,
+                _ = send.stopped() => {
+                    tracing::debug!("subscriber went away");
+                    return Ok(());
+                }
+            }
+            tokio::time::sleep(self.0.options.subscribe_coalesce_window).await;
+            // changes during the window are covered by the next response
+            changes.borrow_and_update();
+        }
+    }
+
+    fn subscribe_changes(&self, content: HashAndFormat) -> watch::Receiver<()> {
+        let mut subscriptions = self.0.subscriptions.lock().unwrap();
+        subscriptions
+            .entry(content)
+            .or_insert_with(|| watch::channel(()).0)
+            .subscribe()
+    }
+
+    /// Notify the subscribers of some content of a change.
+    fn notify_subscribers(&self, content: HashAndFormat) {
+        let mut subscriptions = self.0.subscriptions.lock().unwrap();
+        if let Some(sender) = subscriptions.get(&content) {
+            if sender.send(()).is_err() {
+                // no subscribers left
+                subscriptions.remove(&content);
+            }
+        }
+    }
+
+    /// The size of some content, if it is known from probing.
+    async fn content_size(&self, content: HashAndFormat) -> anyhow::Result<Option<u64>> {
+        Ok(match content.format {
+            BlobFormat::Raw => self.get_size(content.hash).await?,
+            BlobFormat::HashSeq => self
+                .get_sizes(content.hash)
+                .await?
+                .map(|(_, sizes)| sizes.iter().sum()),
+        })
+    }
+
     async fn get_size(&self, hash: Hash) -> anyhow::Result<Option<u64>> {
         let (tx, rx) = oneshot::channel();
         self.0
@@ -1060,7 +1900,6 @@ impl Tracker {
     ) -> anyhow::Result<Stats> {
         let cap = format!("{} at {}", content, host);
         let HashAndFormat { hash, format } = content;
-        let mut rng = rand::thread_rng();
         let stats = if probe_kind == ProbeKind::Incomplete {
             tracing::debug!("Size probing {}...", cap);
             let (size, stats) = unverified_size(connection, hash).await?;
@@ -1075,9 +1914,12 @@ impl Tracker {
             match format {
                 BlobFormat::Raw => {
                     let size = self.get_or_insert_size(connection, hash).await?;
-                    let random_chunk = rng.gen_range(0..ChunkNum::chunks(size).0);
+                    // the rng is not Send, so it must not be held across an await
+                    let random_chunk = rand::thread_rng().gen_range(0..ChunkNum::chunks(size).0);
                     tracing::debug!("Chunk probing {}, chunk {}", cap, random_chunk);
-                    let stats = chunk_probe(connection, hash, ChunkNum(random_chunk)).await?;
+                    let (stats, proof) =
+                        chunk_probe(connection, hash, ChunkNum(random_chunk)).await?;
+                    self.0.proofs.lock().unwrap().insert((*hash, *host), proof);
                     tracing::debug!(
                         "Chunk probed {}, chunk {}, {:.6}s",
                         cap,
@@ -1135,12 +1977,102 @@ impl Tracker {
         let (tx, rx) = oneshot::channel();
         self.0
             .actor
-            .send_async(ActorMessage::Announce { announce, tx })
+            .send_async(ActorMessage::Announce {
+                announce: announce.clone(),
+                tx,
+            })
+            .await?;
+        let response = rx.await??;
+        self.setup_tasks_for_announce(&announce, response);
+        self.notify_subscribers(announce.content);
+        Ok(())
+    }
+
+    async fn handle_atomic_announce(
+        &self,
+        announces: Vec<SignedAnnounce>,
+    ) -> anyhow::Result<AnnounceSetResult> {
+        tracing::info!("got atomic announce set");
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .actor
+            .send_async(ActorMessage::AnnounceSet {
+                announces: announces.clone(),
+                tx,
+            })
+            .await?;
+        let responses = match rx.await?? {
+            Ok(responses) => responses,
+            Err(rejection) => {
+                tracing::debug!("{}", rejection);
+                return Ok(AnnounceSetResult::Rejected(rejection));
+            }
+        };
+        for (announce, response) in announces.iter().zip(responses) {
+            self.setup_tasks_for_announce(announce, response);
+            self.notify_subscribers(announce.content);
+        }
+        Ok(AnnounceSetResult::Accepted)
+    }
+
+    async fn handle_announce_token(&self, token: SignedAnnounceToken) -> anyhow::Result<()> {
+        tracing::info!("got announce token");
+        token.verify(AbsoluteTime::now())?;
+        tracing::info!("verified announce token: {:?}", token);
+
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .actor
+            .send_async(ActorMessage::AnnounceToken {
+                token: token.clone(),
+                tx,
+            })
+            .await?;
+        let response = rx.await??;
+        self.setup_tasks_for_announce(&token.announce, response);
+        self.notify_subscribers(token.announce.content);
+        Ok(())
+    }
+
+    async fn handle_unannounce(&self, unannounce: SignedUnannounce) -> anyhow::Result<()> {
+        tracing::info!("got unannounce");
+        unannounce.verify()?;
+        tracing::info!("verified unannounce: {:?}", unannounce);
+
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .actor
+            .send_async(ActorMessage::Unannounce { unannounce, tx })
             .await?;
+        rx.await??;
+        // no need to probe the host anymore. DHT announce tasks for content that
+        // no longer has any hosts will be removed in the next gc run.
+        self.0.probe_tasks.retain(|node| node != &unannounce.host);
+        self.0
+            .proofs
+            .lock()
+            .unwrap()
+            .retain(|(_, host), _| host != &unannounce.host);
+        // we don't know which content the host had, so update all subscribers
+        let subscribed = self
+            .0
+            .subscriptions
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        for content in subscribed {
+            self.notify_subscribers(content);
+        }
+        Ok(())
+    }
+
+    fn setup_tasks_for_announce(&self, announce: &SignedAnnounce, response: AnnounceResponse) {
         let AnnounceResponse {
             new_content,
             new_host_for_content,
-        } = rx.await??;
+        } = response;
         if new_content {
             // if this is a new content, start announcing it to the DHT
             self.setup_dht_announce_task(announce.content);
@@ -1149,16 +2081,264 @@ impl Tracker {
             // if this is a new host for this content, start probing it
             self.setup_probe_task(announce.host);
         }
-        Ok(())
     }
 
-    async fn handle_query(&self, query: Query) -> anyhow::Result<QueryResponse> {
+    /// Set a hook that is called for every query, see [QueryHook].
+    ///
+    /// This replaces any previously set hook.
+    pub fn on_query(&self, hook: impl Fn(Option<NodeId>, &Query) + Send + Sync + 'static) {
+        *self.0.on_query.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    async fn handle_query(
+        &self,
+        query: Query,
+        querier: Option<NodeId>,
+    ) -> anyhow::Result<QueryResponse> {
+        let hook = self.0.on_query.read().unwrap().clone();
+        if let Some(hook) = hook {
+            hook(querier, &query);
+        }
+        if let Some(stats) = &self.0.query_stats {
+            stats.lock().unwrap().record(query.content, Instant::now());
+        }
+        let (content, flags) = (query.content, query.flags);
         let (tx, rx) = oneshot::channel();
         self.0
             .actor
             .send_async(ActorMessage::Query { query, tx })
             .await?;
-        rx.await?
+        let mut response = rx.await??;
+        if flags.detail == DetailLevel::Minimal {
+            // just the ids, without signatures or addrs
+            let mut seen = BTreeSet::new();
+            response.host_ids = std::mem::take(&mut response.hosts)
+                .into_iter()
+                .map(|sa| sa.host)
+                .filter(|host| seen.insert(*host))
+                .collect();
+            return Ok(response);
+        }
+        let hosts = response
+            .hosts
+            .iter()
+            .map(|sa| sa.host)
+            .collect::<BTreeSet<_>>();
+        response.node_addrs = hosts
+            .iter()
+            .filter_map(|host| self.node_addr(*host))
+            .collect();
+        if flags.detail == DetailLevel::Full {
+            response.content_size = self.content_size(content).await?;
+        }
+        if flags.include_proofs && content.format == BlobFormat::Raw {
+            let proofs = self.0.proofs.lock().unwrap();
+            response.proofs = hosts
+                .iter()
+                .filter_map(|host| {
+                    let proof = proofs.get(&(content.hash, *host))?;
+                    Some((*host, proof.clone()))
+                })
+                .take(MAX_PROOFS_PER_RESPONSE)
+                .collect();
+        }
+        Ok(response)
+    }
+
+    /// Answer a batch query.
+    ///
+    /// For a verified batch, the hosts of all items are probed now instead of relying
+    /// on earlier probes. Each host is dialed once, and probed for all items of the
+    /// batch it announced over the same connection. The results are stored like
+    /// those of regular probes.
+    async fn handle_query_batch(
+        &self,
+        batch: QueryBatch,
+        querier: Option<NodeId>,
+    ) -> anyhow::Result<Vec<BatchQueryItem>> {
+        let flags = QueryFlags {
+            // all candidates, the probes below decide
+            verified: false,
+            page_size: None,
+            detail: DetailLevel::Standard,
+            include_proofs: false,
+            ..batch.flags
+        };
+        let mut candidates = Vec::with_capacity(batch.content.len());
+        for content in batch.content {
+            let query = Query {
+                content,
+                flags,
+                namespace: batch.namespace,
+                cursor: None,
+            };
+            let response = self.handle_query(query, querier).await?;
+            let hosts = response
+                .hosts
+                .iter()
+                .map(|sa| (sa.host, sa.kind))
+                .collect::<BTreeSet<_>>();
+            candidates.push((content, hosts));
+        }
+        if !batch.flags.verified {
+            return Ok(candidates
+                .into_iter()
+                .map(|(content, hosts)| BatchQueryItem {
+                    content,
+                    hosts: host_ids(hosts),
+                    failed: Vec::new(),
+                })
+                .collect());
+        }
+        let mut by_host = BTreeMap::<NodeId, BTreeSet<(AnnounceKind, HashAndFormat)>>::new();
+        for (content, hosts) in &candidates {
+            for (host, kind) in hosts {
+                by_host.entry(*host).or_default().insert((*kind, *content));
+            }
+        }
+        let mut results = futures::stream::iter(by_host)
+            .map(|(host, content)| async move { (host, self.probe_one(host, content).await) })
+            .buffer_unordered(REFRESH_PARALLELISM);
+        // whether a host passed all probes for an item
+        let mut passed = BTreeMap::<(HashAndFormat, NodeId), bool>::new();
+        while let Some((host, res)) = results.next().await {
+            let Ok(results) = res else {
+                // unreachable hosts are neither passed nor failed
+                continue;
+            };
+            for (content, _, res) in &results {
+                let entry = passed.entry((*content, host)).or_insert(true);
+                *entry &= res.is_ok();
+            }
+            self.apply_result(host, results, AbsoluteTime::now())
+                .await?;
+        }
+        Ok(candidates
+            .into_iter()
+            .map(|(content, hosts)| {
+                let mut item = BatchQueryItem {
+                    content,
+                    hosts: Vec::new(),
+                    failed: Vec::new(),
+                };
+                for host in host_ids(hosts) {
+                    match passed.get(&(content, host)) {
+                        Some(true) => item.hosts.push(host),
+                        Some(false) => item.failed.push(host),
+                        None => {}
+                    }
+                }
+                item
+            })
+            .collect())
+    }
+
+    /// The most queried content, most queried first.
+    ///
+    /// This is empty unless [Options::query_stats_window] is set.
+    pub fn top_content(&self, limit: usize) -> Vec<(HashAndFormat, u64)> {
+        match &self.0.query_stats {
+            Some(stats) => stats.lock().unwrap().top(limit, Instant::now()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Probe all hosts of some content now, and update their verification.
+    ///
+    /// This is the operator triggered counterpart to the regular probing in the
+    /// background, e.g. for maintenance. Hosts are probed concurrently, for both
+    /// complete and partial announces in the given namespace.
+    pub async fn refresh_verification(
+        &self,
+        content: HashAndFormat,
+        namespace: Option<Namespace>,
+    ) -> anyhow::Result<RefreshSummary> {
+        let query = Query {
+            content,
+            flags: QueryFlags {
+                complete: false,
+                verified: false,
+                sample: None,
+                include_expired: None,
+                min_class: None,
+                require_method: None,
+                page_size: None,
+                prefer_committed: false,
+                detail: DetailLevel::default(),
+                include_proofs: false,
+            },
+            namespace,
+            cursor: None,
+        };
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .actor
+            .send_async(ActorMessage::Query { query, tx })
+            .await?;
+        let response = rx.await??;
+        let mut by_host = BTreeMap::<NodeId, BTreeMap<AnnounceKind, HashAndFormat>>::new();
+        for sa in response.hosts {
+            by_host
+                .entry(sa.host)
+                .or_default()
+                .insert(sa.kind, sa.content);
+        }
+        let mut results = futures::stream::iter(by_host)
+            .map(|(host, content)| async move { (host, self.probe_one(host, content).await) })
+            .buffer_unordered(REFRESH_PARALLELISM);
+        let mut summary = RefreshSummary::default();
+        while let Some((host, res)) = results.next().await {
+            let results = match res {
+                Ok(results) => results,
+                Err(_) => {
+                    summary.unreachable.push(host);
+                    continue;
+                }
+            };
+            if results.iter().all(|(_, _, res)| res.is_ok()) {
+                summary.verified.push(host);
+            } else {
+                summary.failing.push(host);
+            }
+            self.apply_result(host, results, AbsoluteTime::now())
+                .await?;
+        }
+        Ok(summary)
+    }
+
+    /// Handle a refresh from the admin of the tracker.
+    ///
+    /// Only the tracker itself may ask for a refresh, since probing all hosts of some
+    /// content is expensive. A fresh timestamp keeps a captured refresh from being
+    /// replayed much later.
+    async fn handle_refresh(&self, refresh: SignedRefresh) -> anyhow::Result<RefreshSummary> {
+        refresh.verify()?;
+        anyhow::ensure!(
+            refresh.admin == self.0.endpoint.node_id(),
+            "refresh is not signed by the tracker"
+        );
+        anyhow::ensure!(
+            is_fresh(AbsoluteTime::now(), refresh.timestamp, MAX_CLOCK_SKEW),
+            "refresh is too old or too far in the future"
+        );
+        self.refresh_verification(refresh.content, refresh.namespace)
+            .await
+    }
+
+    /// The addrs of a node, as currently known to the endpoint.
+    ///
+    /// This is only known for nodes we are or were recently connected to, e.g. because
+    /// they announced via iroh or because we probed them.
+    fn node_addr(&self, node: NodeId) -> Option<NodeAddr> {
+        let info = self.0.endpoint.connection_info(node)?;
+        let mut addr = NodeAddr::new(node).with_direct_addresses(info.addrs.iter().map(|a| a.addr));
+        if let Some(relay) = info.relay_url {
+            addr = addr.with_relay_url(relay.relay_url);
+        }
+        if addr.info.is_empty() {
+            return None;
+        }
+        Some(addr)
     }
 
     /// Get the content that is supposedly available, grouped by peers
@@ -1196,7 +2376,7 @@ impl Tracker {
     async fn probe_one(
         &self,
         host: NodeId,
-        by_kind_and_content: BTreeMap<AnnounceKind, HashAndFormat>,
+        by_kind_and_content: impl IntoIterator<Item = (AnnounceKind, HashAndFormat)>,
     ) -> anyhow::Result<Vec<(HashAndFormat, AnnounceKind, anyhow::Result<Stats>)>> {
         let t0 = Instant::now();
         let res = self
@@ -1227,6 +2407,8 @@ impl Tracker {
             )?;
             if let Err(cause) = &res {
                 tracing::debug!("error probing host {}: {}", host, cause);
+                // the proof is no longer current
+                self.0.proofs.lock().unwrap().remove(&(content.hash, host));
             }
             results.push((content, announce_kind, res));
         }
@@ -1240,6 +2422,29 @@ impl Tracker {
     }
 }
 
+/// The distinct hosts of a set of candidates, in order.
+fn host_ids(hosts: BTreeSet<(NodeId, AnnounceKind)>) -> Vec<NodeId> {
+    let mut ids = hosts.into_iter().map(|(host, _)| host).collect::<Vec<_>>();
+    ids.dedup();
+    ids
+}
+
+/// Send a query response via UDP.
+async fn send_udp_query_response(
+    socket: &tokio::net::UdpSocket,
+    addr: std::net::SocketAddr,
+    mut response: QueryResponse,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; 1200];
+    // addrs and proofs would rarely fit in a datagram, so only send them via quic
+    response.node_addrs.clear();
+    response.proofs.clear();
+    let response = Response::QueryResponse(response);
+    let response = postcard::to_slice(&response, &mut buf)?;
+    socket.send_to(response, addr).await?;
+    Ok(())
+}
+
 /// Accept an incoming connection and extract the client-provided [`NodeId`] and ALPN protocol.
 async fn accept_conn(
     mut conn: iroh_quinn::Connecting,
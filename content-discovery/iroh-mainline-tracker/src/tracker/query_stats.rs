@@ -0,0 +1,134 @@
+//! Counting queries per content, for top content requests.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use iroh_blobs::HashAndFormat;
+
+/// The number of buckets per window.
+const BUCKETS: u32 = 10;
+
+/// Query counts per content over a rolling window.
+///
+/// Counts are kept in buckets that each cover a part of the window, so old counts
+/// can be dropped without remembering the time of each query.
+#[derive(Debug)]
+pub(super) struct QueryStats {
+    window: Duration,
+    /// Start time and counts of each bucket, oldest first.
+    buckets: VecDeque<(Instant, BTreeMap<HashAndFormat, u64>)>,
+}
+
+impl QueryStats {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    /// Count a query for the given content.
+    pub fn record(&mut self, content: HashAndFormat, now: Instant) {
+        self.expire(now);
+        let bucket_len = self.window / BUCKETS;
+        match self.buckets.back_mut() {
+            Some((start, counts)) if now.duration_since(*start) < bucket_len => {
+                *counts.entry(content).or_default() += 1;
+            }
+            _ => {
+                self.buckets
+                    .push_back((now, BTreeMap::from([(content, 1)])));
+            }
+        }
+    }
+
+    /// The most queried content within the window, most queried first.
+    pub fn top(&mut self, limit: usize, now: Instant) -> Vec<(HashAndFormat, u64)> {
+        self.expire(now);
+        let mut totals = BTreeMap::<HashAndFormat, u64>::new();
+        for (_, counts) in &self.buckets {
+            for (content, count) in counts {
+                *totals.entry(*content).or_default() += count;
+            }
+        }
+        let mut top = totals.into_iter().collect::<Vec<_>>();
+        // stable sort, so ties are ordered by content
+        top.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        top.truncate(limit);
+        top
+    }
+
+    /// Drop buckets that are entirely outside the window.
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front() {
+            if now.duration_since(*start) < self.window {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_mainline_content_discovery::testing::test_hash;
+
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn content(seed: u64) -> HashAndFormat {
+        HashAndFormat::raw(test_hash(seed))
+    }
+
+    #[test]
+    fn queries_are_counted_in_buckets() {
+        let start = Instant::now();
+        let mut stats = QueryStats::new(WINDOW);
+        stats.record(content(1), start);
+        stats.record(content(1), start + Duration::from_millis(500));
+        stats.record(content(2), start + Duration::from_millis(900));
+        // within the first bucket, which covers a tenth of the window
+        assert_eq!(stats.buckets.len(), 1);
+        stats.record(content(1), start + Duration::from_secs(1));
+        stats.record(content(1), start + Duration::from_secs(5));
+        assert_eq!(stats.buckets.len(), 3);
+        let top = stats.top(10, start + Duration::from_secs(5));
+        assert_eq!(top, vec![(content(1), 4), (content(2), 1)]);
+    }
+
+    #[test]
+    fn old_queries_expire() {
+        let start = Instant::now();
+        let mut stats = QueryStats::new(WINDOW);
+        stats.record(content(1), start);
+        stats.record(content(2), start + Duration::from_secs(5));
+        let top = stats.top(10, start + WINDOW - Duration::from_millis(1));
+        assert_eq!(top, vec![(content(1), 1), (content(2), 1)]);
+        // the first bucket is outside the window now, the second one is not
+        assert_eq!(stats.top(10, start + WINDOW), vec![(content(2), 1)]);
+        assert_eq!(stats.buckets.len(), 1);
+        assert!(stats.top(10, start + WINDOW * 2).is_empty());
+        assert!(stats.buckets.is_empty());
+    }
+
+    #[test]
+    fn ties_are_ordered_by_content() {
+        let start = Instant::now();
+        let mut stats = QueryStats::new(WINDOW);
+        // recorded in reverse order, to show that it does not matter
+        for seed in (0..4).rev() {
+            stats.record(content(seed), start);
+        }
+        stats.record(content(0), start);
+        let mut ties = (1..4).map(content).collect::<Vec<_>>();
+        ties.sort();
+        let expected = std::iter::once((content(0), 2))
+            .chain(ties.into_iter().map(|content| (content, 1)))
+            .collect::<Vec<_>>();
+        assert_eq!(stats.top(10, start), expected);
+        // the limit cuts off the tail
+        assert_eq!(stats.top(2, start), expected[..2]);
+    }
+}
@@ -1,21 +1,35 @@
 //! Table definitions and accessors for the redb database.
 use redb::{ReadableTable, TableDefinition, TableError};
 
-use super::{AnnouncePath, AnnounceValue, ProbeValue};
+use super::{AnnouncePath, AnnounceValue, MetadataValue, ProbeValue, TokenValue};
+
+// Version 1 added the namespace to the key. Version 2 of the announces and the
+// metadata added the seed class to the signed announce. Version 3 of the announces
+// added the time the announce was received. Version 4 of the announces and version
+// 3 of the metadata added the seeding commitment to the signed announce. Announces
+// are refreshed regularly, so the old tables are just left alone.
 
 pub(super) const ANNOUNCES_TABLE: TableDefinition<AnnouncePath, AnnounceValue> =
-    TableDefinition::new("announces-0");
+    TableDefinition::new("announces-4");
 pub(super) const PROBES_TABLE: TableDefinition<AnnouncePath, ProbeValue> =
-    TableDefinition::new("probes-0");
+    TableDefinition::new("probes-1");
+pub(super) const TOKENS_TABLE: TableDefinition<AnnouncePath, TokenValue> =
+    TableDefinition::new("tokens-1");
+pub(super) const METADATA_TABLE: TableDefinition<AnnouncePath, MetadataValue> =
+    TableDefinition::new("metadata-3");
 
 pub(super) trait ReadableTables {
     fn announces(&self) -> &impl ReadableTable<AnnouncePath, AnnounceValue>;
     fn probes(&self) -> &impl ReadableTable<AnnouncePath, ProbeValue>;
+    fn tokens(&self) -> &impl ReadableTable<AnnouncePath, TokenValue>;
+    fn metadata(&self) -> &impl ReadableTable<AnnouncePath, MetadataValue>;
 }
 
 pub(super) struct Tables<'a, 'b> {
     pub announces: redb::Table<'a, 'b, AnnouncePath, AnnounceValue>,
     pub probes: redb::Table<'a, 'b, AnnouncePath, ProbeValue>,
+    pub tokens: redb::Table<'a, 'b, AnnouncePath, TokenValue>,
+    pub metadata: redb::Table<'a, 'b, AnnouncePath, MetadataValue>,
 }
 
 impl<'db, 'txn> Tables<'db, 'txn> {
@@ -23,6 +37,8 @@ impl<'db, 'txn> Tables<'db, 'txn> {
         Ok(Self {
             announces: tx.open_table(ANNOUNCES_TABLE)?,
             probes: tx.open_table(PROBES_TABLE)?,
+            tokens: tx.open_table(TOKENS_TABLE)?,
+            metadata: tx.open_table(METADATA_TABLE)?,
         })
     }
 }
@@ -34,6 +50,12 @@ impl ReadableTables for Tables<'_, '_> {
     fn probes(&self) -> &impl ReadableTable<AnnouncePath, ProbeValue> {
         &self.probes
     }
+    fn tokens(&self) -> &impl ReadableTable<AnnouncePath, TokenValue> {
+        &self.tokens
+    }
+    fn metadata(&self) -> &impl ReadableTable<AnnouncePath, MetadataValue> {
+        &self.metadata
+    }
 }
 
 /// A struct similar to [`redb::ReadOnlyTable`] but for all tables that make up
@@ -41,6 +63,8 @@ impl ReadableTables for Tables<'_, '_> {
 pub(super) struct ReadOnlyTables<'txn> {
     pub announces: redb::ReadOnlyTable<'txn, AnnouncePath, AnnounceValue>,
     pub probes: redb::ReadOnlyTable<'txn, AnnouncePath, ProbeValue>,
+    pub tokens: redb::ReadOnlyTable<'txn, AnnouncePath, TokenValue>,
+    pub metadata: redb::ReadOnlyTable<'txn, AnnouncePath, MetadataValue>,
 }
 
 impl<'txn> ReadOnlyTables<'txn> {
@@ -48,6 +72,8 @@ impl<'txn> ReadOnlyTables<'txn> {
         Ok(Self {
             announces: tx.open_table(ANNOUNCES_TABLE)?,
             probes: tx.open_table(PROBES_TABLE)?,
+            tokens: tx.open_table(TOKENS_TABLE)?,
+            metadata: tx.open_table(METADATA_TABLE)?,
         })
     }
 }
@@ -59,4 +85,10 @@ impl ReadableTables for ReadOnlyTables<'_> {
     fn probes(&self) -> &impl ReadableTable<AnnouncePath, ProbeValue> {
         &self.probes
     }
+    fn tokens(&self) -> &impl ReadableTable<AnnouncePath, TokenValue> {
+        &self.tokens
+    }
+    fn metadata(&self) -> &impl ReadableTable<AnnouncePath, MetadataValue> {
+        &self.metadata
+    }
 }
@@ -66,7 +66,7 @@ impl<T> PeekableFlumeReceiver<T> {
 
     /// Create an iterator that pulls messages from the receiver for at most
     /// `count` messages or `max_duration` time.
-    pub fn batch_iter(&mut self, count: usize, max_duration: Duration) -> BatchIter<T> {
+    pub fn batch_iter(&mut self, count: usize, max_duration: Duration) -> BatchIter<'_, T> {
         BatchIter::new(self, count, max_duration)
     }
 
@@ -0,0 +1,135 @@
+//! Announces from hosts with a clock that is ahead of or behind the tracker.
+//!
+//! The tracker expires announces by its own clock, so the skew of the host only
+//! matters for whether the announce is accepted at all.
+use std::time::Duration;
+
+use common::{with_timeout, TestTracker};
+use iroh_blobs::HashAndFormat;
+use iroh_mainline_content_discovery::{
+    announce,
+    protocol::{AbsoluteTime, QueryResponse, SignedAnnounce},
+    query,
+    testing::{test_hash, AnnounceBuilder, QueryBuilder},
+};
+use iroh_mainline_tracker::options::Options;
+
+mod common;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Less than the maximum clock skew of the tracker.
+const AHEAD: Duration = Duration::from_secs(5 * 60);
+
+/// The time between two announces, long enough to tell a refresh from a query delay.
+const GAP: Duration = Duration::from_millis(200);
+
+async fn test_tracker() -> anyhow::Result<TestTracker> {
+    TestTracker::with_options(Options {
+        announce_timeout: TIMEOUT,
+        ..Options::default()
+    })
+    .await
+}
+
+/// A timestamp that is `skew` ahead of now, or behind if `ahead` is false.
+fn skewed(ahead: bool, skew: Duration) -> AbsoluteTime {
+    let now = AbsoluteTime::now().as_micros();
+    let skew = skew.as_micros() as u64;
+    AbsoluteTime::from_micros(if ahead { now + skew } else { now - skew })
+}
+
+fn announce_at(timestamp: AbsoluteTime) -> SignedAnnounce {
+    AnnounceBuilder::new(1).timestamp(timestamp).sign()
+}
+
+async fn announce_and_query(
+    tracker: &TestTracker,
+    signed_announce: SignedAnnounce,
+) -> anyhow::Result<QueryResponse> {
+    let connection = tracker.connect().await?;
+    announce(connection.clone(), signed_announce).await?;
+    let q = QueryBuilder::new(AnnounceBuilder::new(1).build().content).build();
+    query(connection, q).await
+}
+
+async fn clock_ahead() -> anyhow::Result<()> {
+    let tracker = test_tracker().await?;
+    let response = announce_and_query(&tracker, announce_at(skewed(true, AHEAD))).await?;
+    assert_eq!(response.hosts.len(), 1);
+    // the announce expires by the clock of the tracker, not later
+    assert!(response.cache_for.unwrap() <= TIMEOUT);
+
+    // too far ahead, so it is rejected
+    let signed_announce = AnnounceBuilder::new(1)
+        .content(HashAndFormat::raw(test_hash(2)))
+        .timestamp(skewed(true, AHEAD * 12))
+        .sign();
+    let connection = tracker.connect().await?;
+    announce(connection.clone(), signed_announce.clone())
+        .await
+        .ok();
+    let q = QueryBuilder::new(signed_announce.content).build();
+    let response = query(connection, q).await?;
+    assert!(response.hosts.is_empty());
+    Ok(())
+}
+
+async fn clock_behind() -> anyhow::Result<()> {
+    let tracker = test_tracker().await?;
+    let response = announce_and_query(&tracker, announce_at(skewed(false, TIMEOUT / 2))).await?;
+    assert_eq!(response.hosts.len(), 1);
+    // the announce does not expire early
+    assert!(response.cache_for.unwrap() > TIMEOUT / 2);
+
+    // too far behind, so it is rejected
+    let tracker = test_tracker().await?;
+    let response = announce_and_query(&tracker, announce_at(skewed(false, TIMEOUT * 2))).await?;
+    assert!(response.hosts.is_empty());
+    Ok(())
+}
+
+async fn corrected_clock_refreshes() -> anyhow::Result<()> {
+    let tracker = test_tracker().await?;
+    let ahead = announce_at(skewed(true, AHEAD));
+    announce_and_query(&tracker, ahead.clone()).await?;
+    tokio::time::sleep(GAP).await;
+    // the host fixed its clock, so the next announce is older than the stored one
+    let response = announce_and_query(&tracker, announce_at(AbsoluteTime::now())).await?;
+    // the newer signed announce is kept
+    assert_eq!(response.hosts[0].timestamp, ahead.timestamp);
+    // but it lives for the timeout after the last announce
+    assert!(response.cache_for.unwrap() > TIMEOUT - GAP / 2);
+    Ok(())
+}
+
+async fn repeated_announce_refreshes() -> anyhow::Result<()> {
+    let tracker = test_tracker().await?;
+    let signed_announce = announce_at(AbsoluteTime::now());
+    announce_and_query(&tracker, signed_announce.clone()).await?;
+    tokio::time::sleep(GAP).await;
+    // the same announce again, like a repeated datagram
+    let response = announce_and_query(&tracker, signed_announce).await?;
+    assert!(response.cache_for.unwrap() > TIMEOUT - GAP / 2);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clock_ahead_against_tracker() -> anyhow::Result<()> {
+    with_timeout(clock_ahead()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clock_behind_against_tracker() -> anyhow::Result<()> {
+    with_timeout(clock_behind()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrected_clock_refreshes_against_tracker() -> anyhow::Result<()> {
+    with_timeout(corrected_clock_refreshes()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn repeated_announce_refreshes_against_tracker() -> anyhow::Result<()> {
+    with_timeout(repeated_announce_refreshes()).await
+}
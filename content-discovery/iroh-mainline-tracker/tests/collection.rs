@@ -0,0 +1,93 @@
+//! Checking the availability of a whole collection on trackers.
+use std::collections::BTreeSet;
+
+use common::{with_timeout, TestTracker};
+use iroh_blobs::{hashseq::HashSeq, HashAndFormat};
+use iroh_mainline_content_discovery::{
+    announce_all,
+    protocol::ALPN,
+    testing::{test_hash, test_node_id, AnnounceBuilder, QueryBuilder},
+    verify_collection,
+};
+
+mod common;
+
+async fn collection_availability() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let children = (0..3).map(test_hash).collect::<HashSeq>();
+    let root = test_hash(100);
+    // host 1 has the first two children, nobody has the last one
+    let announces = (0..2)
+        .map(|seed| {
+            AnnounceBuilder::new(1)
+                .content(HashAndFormat::raw(test_hash(seed)))
+                .sign()
+        })
+        .collect::<Vec<_>>();
+    announce_all(tracker.connect().await?, announces, 4).await?;
+
+    let flags = QueryBuilder::new(HashAndFormat::raw(root)).build().flags;
+    let client = tracker.client.clone();
+    client.add_node_addr(tracker.addr.clone())?;
+    let tracker_id = tracker.addr.node_id;
+    let availability = verify_collection(
+        client.clone(),
+        [tracker_id],
+        ALPN,
+        root,
+        &children,
+        flags,
+        None,
+        4,
+    )
+    .await;
+    assert!(!availability.is_available());
+    assert!(availability.complete.is_empty());
+    let available = availability
+        .available
+        .keys()
+        .copied()
+        .collect::<BTreeSet<_>>();
+    assert_eq!(available, BTreeSet::from([test_hash(0), test_hash(1)]));
+    assert_eq!(
+        availability.available[&test_hash(0)],
+        BTreeSet::from([test_node_id(1)])
+    );
+    assert_eq!(availability.missing, BTreeSet::from([test_hash(2)]));
+    assert!(availability.failed.is_empty());
+
+    // a tracker that can not be reached does not fail the check, but a child without
+    // hosts on the other trackers might be on it
+    let unreachable = test_node_id(99);
+    let availability = verify_collection(
+        client.clone(),
+        [tracker_id, unreachable],
+        ALPN,
+        root,
+        &children,
+        flags,
+        None,
+        4,
+    )
+    .await;
+    assert_eq!(availability.available.len(), 2);
+    assert!(availability.missing.is_empty());
+    assert_eq!(availability.failed, BTreeSet::from([test_hash(2)]));
+
+    // a host of the whole collection is a host of every child
+    let signed_announce = AnnounceBuilder::new(2)
+        .content(HashAndFormat::hash_seq(root))
+        .sign();
+    iroh_mainline_content_discovery::announce(tracker.connect().await?, signed_announce).await?;
+    let availability =
+        verify_collection(client, [tracker_id], ALPN, root, &children, flags, None, 4).await;
+    assert!(availability.is_available());
+    assert_eq!(availability.complete, BTreeSet::from([test_node_id(2)]));
+    assert_eq!(availability.available.len(), 3);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verify_collection_against_tracker() -> anyhow::Result<()> {
+    with_timeout(collection_availability()).await
+}
@@ -0,0 +1,151 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use std::{future::Future, time::Duration};
+
+use futures::{future::BoxFuture, FutureExt};
+use iroh_blobs::{
+    provider::{Event, EventSender},
+    store::mem::Store,
+};
+use iroh_mainline_content_discovery::protocol::ALPN;
+use iroh_mainline_tracker::{options::Options, tracker::Tracker};
+use iroh_net::{endpoint::Connection, key::SecretKey, relay::RelayMode, Endpoint, NodeAddr};
+use tempfile::TempDir;
+use tokio::task::JoinHandle;
+
+/// Run a test against a tracker, and fail it if it takes longer than 30 seconds.
+pub async fn with_timeout(test: impl Future<Output = anyhow::Result<()>>) -> anyhow::Result<()> {
+    tokio::time::timeout(Duration::from_secs(30), test).await?
+}
+
+/// A provider that does not care about events.
+#[derive(Debug, Clone)]
+pub struct NoEvents;
+
+impl EventSender for NoEvents {
+    fn send(&self, _event: Event) -> BoxFuture<'static, ()> {
+        async {}.boxed()
+    }
+}
+
+/// An endpoint that only uses local addrs.
+pub async fn local_endpoint(
+    secret_key: SecretKey,
+    alpns: Vec<Vec<u8>>,
+) -> anyhow::Result<Endpoint> {
+    Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(alpns)
+        .relay_mode(RelayMode::Disabled)
+        .bind(0)
+        .await
+}
+
+/// A tracker on a local endpoint, with its database in a temp dir.
+///
+/// The tracker is stopped when this is dropped.
+pub struct TestTracker {
+    /// The tracker, to inspect its state directly.
+    pub tracker: Tracker,
+    /// The addr to connect to the tracker.
+    pub addr: NodeAddr,
+    /// The secret key of the tracker, to sign admin requests.
+    pub secret_key: SecretKey,
+    /// An endpoint to connect to the tracker from.
+    pub client: Endpoint,
+    task: JoinHandle<std::io::Result<()>>,
+    _dir: TempDir,
+}
+
+impl TestTracker {
+    /// Start a tracker with the default options.
+    pub async fn new() -> anyhow::Result<Self> {
+        Self::with_options(Options::default()).await
+    }
+
+    /// Start a tracker with the given options.
+    ///
+    /// The data path of the options is replaced with a path in a temp dir.
+    pub async fn with_options(options: Options) -> anyhow::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let options = Options {
+            announce_data_path: dir.path().join("announce.redb"),
+            ..options
+        };
+        let secret_key = SecretKey::generate();
+        let endpoint = local_endpoint(secret_key.clone(), vec![ALPN.to_vec()]).await?;
+        let addr = endpoint.my_addr().await?;
+        let tracker = Tracker::new(options, endpoint.clone())?;
+        let task = tokio::spawn(tracker.clone().iroh_accept_loop(endpoint));
+        let client = local_endpoint(SecretKey::generate(), vec![]).await?;
+        Ok(Self {
+            tracker,
+            addr,
+            secret_key,
+            client,
+            task,
+            _dir: dir,
+        })
+    }
+
+    /// Open a new connection to the tracker.
+    pub async fn connect(&self) -> anyhow::Result<Connection> {
+        self.client.connect(self.addr.clone(), ALPN).await
+    }
+}
+
+impl Drop for TestTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A provider serving the blobs of a store on a local endpoint.
+///
+/// The provider is stopped when this is dropped.
+pub struct TestProvider {
+    /// The secret key of the provider, to sign announces.
+    pub secret_key: SecretKey,
+    /// The endpoint of the provider, to announce from.
+    pub endpoint: Endpoint,
+    task: JoinHandle<()>,
+}
+
+impl TestProvider {
+    /// Start a provider for the given store.
+    pub async fn new(store: Store) -> anyhow::Result<Self> {
+        let secret_key = SecretKey::generate();
+        let endpoint = local_endpoint(
+            secret_key.clone(),
+            vec![iroh_blobs::protocol::ALPN.to_vec()],
+        )
+        .await?;
+        let rt = tokio_util::task::LocalPoolHandle::new(1);
+        let task = tokio::spawn({
+            let endpoint = endpoint.clone();
+            async move {
+                while let Some(connecting) = endpoint.accept().await {
+                    let Ok(connection) = connecting.await else {
+                        continue;
+                    };
+                    let (store, rt) = (store.clone(), rt.clone());
+                    tokio::spawn(iroh_blobs::provider::handle_connection(
+                        connection, store, NoEvents, rt,
+                    ));
+                }
+            }
+        });
+        Ok(Self {
+            secret_key,
+            endpoint,
+            task,
+        })
+    }
+}
+
+impl Drop for TestProvider {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
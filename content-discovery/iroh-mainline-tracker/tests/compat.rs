@@ -0,0 +1,138 @@
+//! New clients against trackers that don't know the newest requests.
+use std::collections::BTreeSet;
+
+use common::{local_endpoint, with_timeout, TestTracker};
+use futures::StreamExt;
+use iroh_blobs::HashAndFormat;
+use iroh_mainline_content_discovery::{
+    codec::{decode_supported_request, Codec, Postcard},
+    protocol::{
+        AbsoluteTime, AttributedQuery, ErrorResponse, QueryBatch, Request, Response, SignedQuery,
+        UnsupportedRequest, ALPN, REQUEST_SIZE_LIMIT,
+    },
+    query_batch, query_signed,
+    testing::{test_hash, test_secret_key, AnnounceBuilder, QueryBuilder, QueryResponseBuilder},
+    TrackerClient,
+};
+use iroh_net::{key::SecretKey, Endpoint};
+
+mod common;
+
+/// The number of request variants known to the old tracker, up to and excluding
+/// [Request::SignedQuery].
+const OLD_VARIANTS: u32 = 4;
+
+/// A tracker that only knows plain queries, and answers them with a single host.
+async fn old_tracker(endpoint: Endpoint) -> anyhow::Result<()> {
+    while let Some(connecting) = endpoint.accept().await {
+        let connection = connecting.await?;
+        tokio::spawn(async move {
+            while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                let request = recv.read_to_end(REQUEST_SIZE_LIMIT).await?;
+                let response = match decode_supported_request(&Postcard, &request, OLD_VARIANTS) {
+                    Ok(Request::Query(query)) => {
+                        let host = AnnounceBuilder::new(1).content(query.content).sign();
+                        Response::QueryResponse(QueryResponseBuilder::new().host(host).build())
+                    }
+                    Ok(other) => anyhow::bail!("unexpected request {:?}", other),
+                    Err(cause) => match cause.downcast::<UnsupportedRequest>() {
+                        Ok(unsupported) => {
+                            Response::Error(ErrorResponse::UnsupportedRequest(unsupported))
+                        }
+                        Err(cause) => return Err(cause),
+                    },
+                };
+                send.write_all(&Postcard.encode(&response)?).await?;
+                send.finish().await?;
+            }
+            anyhow::Ok(())
+        });
+    }
+    Ok(())
+}
+
+fn signed_query(content: HashAndFormat) -> anyhow::Result<SignedQuery> {
+    let querier = test_secret_key(2);
+    let query = AttributedQuery {
+        querier: querier.public(),
+        timestamp: AbsoluteTime::now(),
+        query: QueryBuilder::new(content).build(),
+    };
+    SignedQuery::new(query, &querier)
+}
+
+async fn signed_query_fallback() -> anyhow::Result<()> {
+    let endpoint = local_endpoint(SecretKey::generate(), vec![ALPN.to_vec()]).await?;
+    let addr = endpoint.my_addr().await?;
+    let server = tokio::spawn(old_tracker(endpoint));
+    let client = local_endpoint(SecretKey::generate(), vec![]).await?;
+    let connection = client.connect(addr, ALPN).await?;
+    let content = HashAndFormat::raw(test_hash(0));
+
+    // the tracker rejects the signed query, so it is sent again unsigned
+    let response = query_signed(connection.clone(), signed_query(content)?).await?;
+    let hosts = response.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>();
+    assert_eq!(hosts, vec![test_secret_key(1).public()]);
+    // and the connection is still usable afterwards
+    query_signed(connection, signed_query(content)?).await?;
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn signed_query_against_old_tracker() -> anyhow::Result<()> {
+    with_timeout(signed_query_fallback()).await
+}
+
+async fn signed_query_current() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let connection = tracker.connect().await?;
+    let content = HashAndFormat::raw(test_hash(0));
+    let signed_announce = AnnounceBuilder::new(1).content(content).sign();
+    iroh_mainline_content_discovery::announce(connection.clone(), signed_announce).await?;
+
+    let response = query_signed(connection, signed_query(content)?).await?;
+    let hosts = response.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>();
+    assert_eq!(hosts, vec![test_secret_key(1).public()]);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn signed_query_against_current_tracker() -> anyhow::Result<()> {
+    with_timeout(signed_query_current()).await
+}
+
+async fn newer_requests_fail_or_fall_back() -> anyhow::Result<()> {
+    let endpoint = local_endpoint(SecretKey::generate(), vec![ALPN.to_vec()]).await?;
+    let addr = endpoint.my_addr().await?;
+    let server = tokio::spawn(old_tracker(endpoint));
+    let client = local_endpoint(SecretKey::generate(), vec![]).await?;
+    let connection = client.connect(addr, ALPN).await?;
+    let content = HashAndFormat::raw(test_hash(0));
+
+    // requests without a fallback surface the unsupported variant
+    let batch = QueryBatch {
+        content: vec![content],
+        flags: QueryBuilder::new(content).build().flags,
+        namespace: None,
+    };
+    let cause = query_batch(connection.clone(), batch).await.unwrap_err();
+    let unsupported = cause.downcast_ref::<UnsupportedRequest>().unwrap();
+    assert_eq!(unsupported.variant, 8);
+
+    // watching falls back to polling, which yields the current hosts first
+    let tracker = TrackerClient::new(connection, test_secret_key(3), Default::default());
+    let mut updates = tracker.watch(QueryBuilder::new(content).build()).boxed();
+    let update = updates.next().await.unwrap()?;
+    assert_eq!(
+        update.diff.added,
+        BTreeSet::from([test_secret_key(1).public()])
+    );
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn newer_requests_against_old_tracker() -> anyhow::Result<()> {
+    with_timeout(newer_requests_fail_or_fall_back()).await
+}
@@ -0,0 +1,143 @@
+//! Many requests on a single connection to a tracker.
+//!
+//! Clients keep a connection to a tracker open, and send every request on its own
+//! stream of that connection, also concurrently.
+use std::{cell::Cell, time::Duration};
+
+use common::{with_timeout, TestTracker};
+use futures::TryStreamExt;
+use iroh_blobs::HashAndFormat;
+use iroh_mainline_content_discovery::{
+    announce, announce_all, announce_all_with_progress, announce_batch, query, query_page,
+    testing::{test_hash, AnnounceBuilder, QueryBuilder},
+    top_content,
+};
+
+mod common;
+
+async fn sequential_and_concurrent_requests() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let connection = tracker.connect().await?;
+    let content = HashAndFormat::raw(test_hash(0));
+
+    // requests one after the other
+    for seed in 1..=3 {
+        let signed_announce = AnnounceBuilder::new(seed).content(content).sign();
+        announce(connection.clone(), signed_announce).await?;
+    }
+    let response = query(connection.clone(), QueryBuilder::new(content).build()).await?;
+    assert_eq!(response.hosts.len(), 3);
+
+    // requests at the same time
+    let queries = (0..8).map(|_| query(connection.clone(), QueryBuilder::new(content).build()));
+    for response in futures::future::try_join_all(queries).await? {
+        assert_eq!(response.hosts.len(), 3);
+    }
+
+    // and still more requests after that
+    let results = announce_batch(
+        connection.clone(),
+        (4..=6).map(|seed| AnnounceBuilder::new(seed).content(content).sign()),
+    )
+    .try_collect::<Vec<_>>()
+    .await?;
+    assert!(results.iter().all(|result| result.accepted));
+    let response = query(connection.clone(), QueryBuilder::new(content).build()).await?;
+    assert_eq!(response.hosts.len(), 6);
+    top_content(connection.clone(), 10).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn many_requests_on_one_connection() -> anyhow::Result<()> {
+    tokio::time::timeout(
+        Duration::from_secs(30),
+        sequential_and_concurrent_requests(),
+    )
+    .await?
+}
+
+async fn bounded_announces() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let connection = tracker.connect().await?;
+    let content = HashAndFormat::raw(test_hash(0));
+    let max_in_flight = 4;
+
+    // announces are only taken when an earlier one was acknowledged by the tracker
+    let (taken, acked, max_outstanding) = (&Cell::new(0), &Cell::new(0), &Cell::new(0));
+    let announces = (1..=24).map(|seed| {
+        taken.set(taken.get() + 1);
+        max_outstanding.set(max_outstanding.get().max(taken.get() - acked.get()));
+        AnnounceBuilder::new(seed).content(content).sign()
+    });
+    let progress = announce_all_with_progress(
+        connection.clone(),
+        announces,
+        max_in_flight,
+        |_, _, progress| acked.set(progress.accepted + progress.failed),
+    )
+    .await;
+    assert_eq!(progress.accepted, 24);
+    assert_eq!(progress.failed, 0);
+    // the tracker did answer concurrent announces, but never more than allowed
+    assert_eq!(max_outstanding.get(), max_in_flight);
+
+    let announces = (25..=32).map(|seed| AnnounceBuilder::new(seed).content(content).sign());
+    let count = announce_all(connection.clone(), announces, max_in_flight).await?;
+    assert_eq!(count, 8);
+
+    let response = query(connection, QueryBuilder::new(content).build()).await?;
+    assert_eq!(response.hosts.len(), 32);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announce_all_against_tracker() -> anyhow::Result<()> {
+    with_timeout(bounded_announces()).await
+}
+
+async fn paged_queries() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let connection = tracker.connect().await?;
+    let content = HashAndFormat::raw(test_hash(0));
+    let announces = (1..=10).map(|seed| AnnounceBuilder::new(seed).content(content).sign());
+    announce_all(connection.clone(), announces, 4).await?;
+
+    // page by page
+    let mut next = QueryBuilder::new(content).page_size(Some(3)).build();
+    let mut pages = Vec::new();
+    loop {
+        let page = query_page(connection.clone(), next).await?;
+        pages.push(page.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>());
+        match page.next_cursor {
+            Some(cursor) => next.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    let sizes = pages.iter().map(|page| page.len()).collect::<Vec<_>>();
+    assert_eq!(sizes, vec![3, 3, 3, 1]);
+    let mut paged = pages.concat();
+    paged.sort();
+    paged.dedup();
+    assert_eq!(paged.len(), 10);
+
+    // all pages at once, all on the same connection
+    let all = QueryBuilder::new(content).page_size(Some(3)).build();
+    let response = query(connection.clone(), all).await?;
+    assert!(response.next_cursor.is_none());
+    let mut hosts = response.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>();
+    hosts.sort();
+    assert_eq!(hosts, paged);
+
+    // an empty page is no pagination
+    let empty = QueryBuilder::new(content).page_size(Some(0)).build();
+    let page = query_page(connection.clone(), empty).await?;
+    assert_eq!(page.hosts.len(), 10);
+    assert!(page.next_cursor.is_none());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paged_query_against_tracker() -> anyhow::Result<()> {
+    with_timeout(paged_queries()).await
+}
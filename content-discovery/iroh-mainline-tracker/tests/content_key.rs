@@ -0,0 +1,64 @@
+//! Announces and queries referring to the same content in different ways.
+
+use common::{with_timeout, TestTracker};
+use iroh_base::ticket::BlobTicket;
+use iroh_blobs::{BlobFormat, HashAndFormat};
+use iroh_mainline_content_discovery::{
+    announce,
+    protocol::ContentKey,
+    query,
+    testing::{test_hash, test_node_id, AnnounceBuilder, QueryBuilder},
+};
+use iroh_net::NodeAddr;
+
+mod common;
+
+async fn same_content_same_hosts() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let connection = tracker.connect().await?;
+    let hash = test_hash(0);
+    let raw_ticket = BlobTicket::new(NodeAddr::new(test_node_id(1)), hash, BlobFormat::Raw)?;
+    let seq_ticket = BlobTicket::new(NodeAddr::new(test_node_id(2)), hash, BlobFormat::HashSeq)?;
+
+    // host 1 announces the blob via a ticket, host 2 the hash seq with the same hash
+    let signed_announce = AnnounceBuilder::new(1)
+        .content(raw_ticket.content_key())
+        .sign();
+    announce(connection.clone(), signed_announce).await?;
+    let signed_announce = AnnounceBuilder::new(2)
+        .content(seq_ticket.content_key())
+        .sign();
+    announce(connection.clone(), signed_announce).await?;
+
+    let hosts = |content: HashAndFormat| {
+        let connection = connection.clone();
+        async move {
+            let response = query(connection, QueryBuilder::new(content).build()).await?;
+            anyhow::Ok(response.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>())
+        }
+    };
+    // the blob is found via the bare hash, its ticket, or a ticket of another node
+    let other_ticket = BlobTicket::new(NodeAddr::new(test_node_id(3)), hash, BlobFormat::Raw)?;
+    for content in [
+        hash.content_key(),
+        raw_ticket.content_key(),
+        other_ticket.content_key(),
+    ] {
+        assert_eq!(hosts(content).await?, vec![test_node_id(1)]);
+    }
+    // the hash seq is different content, even though the hash is the same
+    assert_eq!(
+        hosts(seq_ticket.content_key()).await?,
+        vec![test_node_id(2)]
+    );
+    assert_eq!(
+        hosts(HashAndFormat::hash_seq(hash).content_key()).await?,
+        vec![test_node_id(2)]
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn content_keys_against_tracker() -> anyhow::Result<()> {
+    with_timeout(same_content_same_hosts()).await
+}
@@ -0,0 +1,106 @@
+//! Announce content to a tracker, find it with a query, and download it from the host.
+//!
+//! This runs a provider, a tracker and a downloader in one process, connected via
+//! local addrs only.
+
+use common::{local_endpoint, with_timeout, TestProvider, TestTracker};
+use iroh_base::ticket::BlobTicket;
+use iroh_blobs::{
+    get::fsm::{ConnectedNext, EndBlobNext},
+    protocol::GetRequest,
+    store::Store,
+    BlobFormat, HashAndFormat,
+};
+use iroh_mainline_content_discovery::{
+    announce,
+    protocol::{
+        AbsoluteTime, Announce, AnnounceKind, AnnounceMetadata, ContentKey, SeedClass,
+        SignedAnnounce, ALPN,
+    },
+    query,
+    testing::QueryBuilder,
+};
+use iroh_net::{key::SecretKey, NodeAddr};
+
+mod common;
+
+async fn announce_query_download() -> anyhow::Result<()> {
+    let data = b"discovery leads to a working download".to_vec();
+
+    // a provider that has the content
+    let store = iroh_blobs::store::mem::Store::new();
+    let tag = store
+        .import_bytes(data.clone().into(), BlobFormat::Raw)
+        .await?;
+    let content = HashAndFormat::raw(*tag.hash());
+    let provider = TestProvider::new(store).await?;
+    let provider_key = provider.secret_key.clone();
+    let ticket = BlobTicket::new(
+        NodeAddr::new(provider_key.public()),
+        content.hash,
+        content.format,
+    )?;
+
+    // the tracker
+    let tracker = TestTracker::new().await?;
+    let tracker_addr = tracker.addr.clone();
+
+    // the provider announces itself, so the tracker learns its addr. It refers to the
+    // content by its ticket, while the downloader below just knows the hash.
+    let announce_msg = Announce {
+        host: provider_key.public(),
+        content: ticket.content_key(),
+        kind: AnnounceKind::Complete,
+        timestamp: AbsoluteTime::now(),
+        metadata: AnnounceMetadata::default(),
+        namespace: None,
+        class: SeedClass::default(),
+        committed_until: None,
+    };
+    let signed_announce = SignedAnnounce::new(announce_msg, &provider_key)?;
+    let connection = provider
+        .endpoint
+        .connect(tracker_addr.clone(), ALPN)
+        .await?;
+    announce(connection, signed_announce).await?;
+
+    // a downloader finds the provider via the tracker
+    let downloader = local_endpoint(SecretKey::generate(), vec![]).await?;
+    let connection = downloader.connect(tracker_addr, ALPN).await?;
+    let query_msg = QueryBuilder::new(content.hash.content_key()).build();
+    let response = query(connection, query_msg).await?;
+    let hosts = response.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>();
+    assert_eq!(hosts, vec![provider_key.public()]);
+    let sources = response.into_download_sources();
+    let host_addr = sources[0].node_addr.clone();
+    anyhow::ensure!(
+        !host_addr.info.direct_addresses.is_empty(),
+        "tracker did not provide the addrs of the host"
+    );
+
+    // and downloads the content
+    let connection = downloader
+        .connect(host_addr, iroh_blobs::protocol::ALPN)
+        .await?;
+    let request = GetRequest::single(content.hash);
+    let at_connected = iroh_blobs::get::fsm::start(connection, request)
+        .next()
+        .await?;
+    let ConnectedNext::StartRoot(start) = at_connected.next().await? else {
+        anyhow::bail!("expected start root");
+    };
+    let (at_content, _size) = start.next().next().await?;
+    let (at_end, downloaded) = at_content.concatenate_into_vec().await?;
+    let EndBlobNext::Closing(closing) = at_end.next() else {
+        anyhow::bail!("expected closing");
+    };
+    closing.next().await?;
+    assert_eq!(downloaded, data);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn end_to_end() -> anyhow::Result<()> {
+    with_timeout(announce_query_download()).await
+}
@@ -0,0 +1,93 @@
+//! Requests with more items than the tracker accepts.
+use std::time::Duration;
+
+use common::{with_timeout, TestTracker};
+use futures::StreamExt;
+use iroh_blobs::HashAndFormat;
+use iroh_mainline_content_discovery::{
+    announce_atomic, announce_batch,
+    protocol::{QueryBatch, TooManyItems},
+    query, query_batch,
+    testing::{test_hash, AnnounceBuilder, QueryBuilder},
+    top_content,
+};
+use iroh_mainline_tracker::options::Options;
+
+mod common;
+
+async fn batches_over_the_limit() -> anyhow::Result<()> {
+    let options = Options {
+        max_request_items: 2,
+        ..Options::default()
+    };
+    let tracker = TestTracker::with_options(options).await?;
+    let connection = tracker.connect().await?;
+    let too_many = TooManyItems {
+        requested: 3,
+        limit: 2,
+    };
+    let announces = (0..3)
+        .map(|seed| AnnounceBuilder::new(seed).sign())
+        .collect::<Vec<_>>();
+
+    // announces can not be split without changing their meaning, so they fail
+    let cause = announce_atomic(connection.clone(), announces.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(cause.downcast_ref::<TooManyItems>(), Some(&too_many));
+    let mut results = announce_batch(connection.clone(), announces.clone()).boxed();
+    let cause = results.next().await.unwrap().unwrap_err();
+    assert_eq!(cause.downcast_ref::<TooManyItems>(), Some(&too_many));
+    assert!(results.next().await.is_none());
+
+    // batch queries are split into batches the tracker accepts
+    let batch = QueryBatch {
+        content: (0..5)
+            .map(|seed| HashAndFormat::raw(test_hash(seed)))
+            .collect(),
+        flags: QueryBuilder::new(HashAndFormat::raw(test_hash(0)))
+            .build()
+            .flags,
+        namespace: None,
+    };
+    let items = query_batch(connection.clone(), batch.clone()).await?;
+    assert!(items.iter().map(|item| item.content).eq(batch.content));
+    // nothing was announced
+    assert!(items.iter().all(|item| item.hosts.is_empty()));
+
+    // batches within the limit are accepted as usual
+    let results = announce_batch(connection, announces[..2].to_vec())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|res| res.as_ref().unwrap().accepted));
+    Ok(())
+}
+
+async fn top_content_over_the_limit() -> anyhow::Result<()> {
+    let options = Options {
+        max_request_items: 2,
+        query_stats_window: Duration::from_secs(60),
+        ..Options::default()
+    };
+    let tracker = TestTracker::with_options(options).await?;
+    let connection = tracker.connect().await?;
+    for seed in 0..3 {
+        let q = QueryBuilder::new(HashAndFormat::raw(test_hash(seed))).build();
+        query(connection.clone(), q).await?;
+    }
+    // the tracker returns at most its limit, however many the client asks for
+    let top = top_content(connection, 10).await?;
+    assert_eq!(top.len(), 2);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batches_over_the_item_limit() -> anyhow::Result<()> {
+    with_timeout(batches_over_the_limit()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn top_content_over_the_item_limit() -> anyhow::Result<()> {
+    with_timeout(top_content_over_the_limit()).await
+}
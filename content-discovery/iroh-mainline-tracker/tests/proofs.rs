@@ -0,0 +1,83 @@
+//! Verification proofs in query responses.
+//!
+//! A proof carries the data of a single chunk and the parents down to it, so the
+//! client can check it against the hash without trusting the tracker.
+
+use common::{with_timeout, TestProvider, TestTracker};
+use iroh_blobs::{store::Store, BlobFormat, HashAndFormat};
+use iroh_mainline_content_discovery::{
+    announce,
+    protocol::{
+        AbsoluteTime, Announce, AnnounceKind, AnnounceMetadata, QueryBatch, SeedClass,
+        SignedAnnounce, ALPN,
+    },
+    query, query_batch,
+    testing::QueryBuilder,
+    verify_proof,
+};
+
+mod common;
+
+async fn proof_of_large_blob() -> anyhow::Result<()> {
+    // 64 full chunks, so every proof has a whole chunk and a parent per tree level
+    let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let store = iroh_blobs::store::mem::Store::new();
+    let tag = store.import_bytes(data.into(), BlobFormat::Raw).await?;
+    let content = HashAndFormat::raw(*tag.hash());
+    let provider = TestProvider::new(store).await?;
+    let tracker = TestTracker::new().await?;
+
+    // the provider announces via its own endpoint, so the tracker can dial it back
+    let announce_msg = Announce {
+        host: provider.secret_key.public(),
+        content,
+        kind: AnnounceKind::Complete,
+        timestamp: AbsoluteTime::now(),
+        metadata: AnnounceMetadata::default(),
+        namespace: None,
+        class: SeedClass::default(),
+        committed_until: None,
+    };
+    let signed_announce = SignedAnnounce::new(announce_msg, &provider.secret_key)?;
+    let connection = provider
+        .endpoint
+        .connect(tracker.addr.clone(), ALPN)
+        .await?;
+    announce(connection, signed_announce).await?;
+
+    // a verified batch probes the host right away
+    let connection = tracker.connect().await?;
+    let flags = QueryBuilder::new(content).verified(true).build().flags;
+    let batch = QueryBatch {
+        content: vec![content],
+        flags,
+        namespace: None,
+    };
+    let items = query_batch(connection.clone(), batch).await?;
+    assert_eq!(items[0].hosts, vec![provider.secret_key.public()]);
+
+    // and the proof of that probe is in the response
+    let query_msg = QueryBuilder::new(content)
+        .verified(true)
+        .include_proofs(true)
+        .build();
+    let response = query(connection, query_msg).await?;
+    let [(host, proof)] = &response.proofs[..] else {
+        anyhow::bail!("expected one proof, got {}", response.proofs.len());
+    };
+    assert_eq!(*host, provider.secret_key.public());
+    assert_eq!(proof.data.len(), 1024);
+    assert_eq!(proof.parents.len(), 6);
+    verify_proof(content.hash, proof)?;
+
+    // a proof with data the host did not serve is rejected
+    let mut tampered = proof.clone();
+    tampered.data[0] ^= 1;
+    assert!(verify_proof(content.hash, &tampered).is_err());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proof_of_large_blob_against_tracker() -> anyhow::Result<()> {
+    with_timeout(proof_of_large_blob()).await
+}
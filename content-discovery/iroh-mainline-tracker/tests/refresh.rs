@@ -0,0 +1,83 @@
+//! Refreshing the verification of hosts on a running tracker.
+use common::{with_timeout, TestProvider, TestTracker};
+use iroh_blobs::{store::Store, BlobFormat, HashAndFormat};
+use iroh_mainline_content_discovery::{
+    announce,
+    protocol::{
+        AbsoluteTime, Announce, AnnounceKind, AnnounceMetadata, Namespace, Refresh, SeedClass,
+        SignedAnnounce, SignedRefresh, ALPN,
+    },
+    refresh_verification,
+    testing::{test_node_id, test_secret_key, AnnounceBuilder},
+};
+
+mod common;
+
+fn refresh_by(
+    secret_key: &iroh_net::key::SecretKey,
+    content: HashAndFormat,
+) -> anyhow::Result<SignedRefresh> {
+    let refresh = Refresh {
+        admin: secret_key.public(),
+        timestamp: AbsoluteTime::now(),
+        content,
+        namespace: None,
+    };
+    SignedRefresh::new(refresh, secret_key)
+}
+
+async fn refresh_hosts() -> anyhow::Result<()> {
+    let store = iroh_blobs::store::mem::Store::new();
+    let tag = store
+        .import_bytes(vec![7u8; 4096].into(), BlobFormat::Raw)
+        .await?;
+    let content = HashAndFormat::raw(*tag.hash());
+    let provider = TestProvider::new(store).await?;
+    let tracker = TestTracker::new().await?;
+
+    // the provider announces via its own endpoint, so the tracker can dial it back
+    let announce_msg = Announce {
+        host: provider.secret_key.public(),
+        content,
+        kind: AnnounceKind::Complete,
+        timestamp: AbsoluteTime::now(),
+        metadata: AnnounceMetadata::default(),
+        namespace: None,
+        class: SeedClass::default(),
+        committed_until: None,
+    };
+    let signed_announce = SignedAnnounce::new(announce_msg, &provider.secret_key)?;
+    let connection = provider
+        .endpoint
+        .connect(tracker.addr.clone(), ALPN)
+        .await?;
+    announce(connection, signed_announce).await?;
+    // a host the tracker has no addrs for
+    let unreachable = AnnounceBuilder::new(1).content(content).sign();
+    announce(tracker.connect().await?, unreachable).await?;
+
+    let refresh = refresh_by(&tracker.secret_key, content)?;
+    let summary = refresh_verification(tracker.connect().await?, refresh).await?;
+    assert_eq!(summary.verified, vec![provider.secret_key.public()]);
+    assert!(summary.failing.is_empty());
+    assert_eq!(summary.unreachable, vec![test_node_id(1)]);
+
+    // only the tracker itself may ask for a refresh
+    let refresh = refresh_by(&test_secret_key(2), content)?;
+    assert!(refresh_verification(tracker.connect().await?, refresh)
+        .await
+        .is_err());
+
+    // and the signature must match the refresh
+    let mut refresh = refresh_by(&tracker.secret_key, content)?;
+    refresh.refresh.namespace = Some(Namespace::from_name("other"));
+    assert!(refresh_verification(tracker.connect().await?, refresh)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refresh_hosts_against_tracker() -> anyhow::Result<()> {
+    with_timeout(refresh_hosts()).await
+}
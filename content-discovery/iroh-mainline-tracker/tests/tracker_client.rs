@@ -0,0 +1,161 @@
+//! A [TrackerClient] against a real tracker.
+use std::{collections::BTreeSet, time::Duration};
+
+use common::{with_timeout, TestTracker};
+use futures::StreamExt;
+use iroh_blobs::HashAndFormat;
+use iroh_mainline_content_discovery::{
+    announce,
+    protocol::{AnnounceKind, Namespace},
+    testing::{test_hash, test_node_id, test_secret_key, AnnounceBuilder, QueryBuilder},
+    TrackerClient, TrackerClientOptions,
+};
+
+mod common;
+
+async fn flush_coalesced() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let options = TrackerClientOptions {
+        // only send when flushed
+        coalesce_window: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    let client = TrackerClient::new(tracker.connect().await?, test_secret_key(1), options);
+    let contents = (0..5)
+        .map(|seed| HashAndFormat::raw(test_hash(seed)))
+        .collect::<Vec<_>>();
+    for content in &contents {
+        client.announce_buffered(*content, AnnounceKind::Partial);
+    }
+    // announcing again is coalesced, and complete wins over partial
+    for content in &contents {
+        client.announce_buffered(*content, AnnounceKind::Complete);
+    }
+
+    // nothing was sent yet
+    let complete = |content| QueryBuilder::new(content).complete(true).build();
+    let response = client.query(complete(contents[0])).await?;
+    assert!(response.hosts.is_empty());
+
+    // one announce per content
+    assert_eq!(client.flush().await?, contents.len());
+    for content in &contents {
+        let response = client.query(complete(*content)).await?;
+        let hosts = response.hosts.iter().map(|sa| sa.host).collect::<Vec<_>>();
+        assert_eq!(hosts, vec![test_secret_key(1).public()]);
+    }
+    // the buffer is empty now
+    assert_eq!(client.flush().await?, 0);
+    client.close().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn buffered_announces_are_sent_together_on_flush() -> anyhow::Result<()> {
+    with_timeout(flush_coalesced()).await
+}
+
+async fn flush_after_window() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let options = TrackerClientOptions {
+        coalesce_window: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let client = TrackerClient::new(tracker.connect().await?, test_secret_key(1), options);
+    let contents = (0..3)
+        .map(|seed| HashAndFormat::raw(test_hash(seed)))
+        .collect::<Vec<_>>();
+    for content in &contents {
+        client.announce_buffered(*content, AnnounceKind::Complete);
+    }
+    // the announces arrive without flushing
+    for content in &contents {
+        loop {
+            let response = client.query(QueryBuilder::new(*content).build()).await?;
+            if !response.hosts.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    // and they were all sent by the scheduled flush
+    assert_eq!(client.flush().await?, 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn buffered_announces_are_sent_after_the_window() -> anyhow::Result<()> {
+    with_timeout(flush_after_window()).await
+}
+
+async fn cached_queries() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let content = HashAndFormat::raw(test_hash(0));
+    let client = TrackerClient::new(
+        tracker.connect().await?,
+        test_secret_key(1),
+        Default::default(),
+    );
+    client.announce(content, AnnounceKind::Complete).await?;
+    let query = QueryBuilder::new(content).build();
+    let response = client.query_cached(query).await?;
+    assert_eq!(response.hosts.len(), 1);
+    // the tracker advises to cache until the announce expires
+    assert!(response.cache_for.is_some());
+
+    // another host announces, on another connection
+    let signed_announce = AnnounceBuilder::new(2).content(content).sign();
+    announce(tracker.connect().await?, signed_announce).await?;
+
+    // the cached response does not know about it yet, a fresh query does
+    assert_eq!(client.query_cached(query).await?.hosts.len(), 1);
+    assert_eq!(client.query(query).await?.hosts.len(), 2);
+
+    // a query for content without hosts has no advice, so it is not cached
+    let other = QueryBuilder::new(HashAndFormat::raw(test_hash(1))).build();
+    let response = client.query_cached(other).await?;
+    assert!(response.hosts.is_empty());
+    assert!(response.cache_for.is_none());
+    let signed_announce = AnnounceBuilder::new(2).content(other.content).sign();
+    announce(tracker.connect().await?, signed_announce).await?;
+    assert_eq!(client.query_cached(other).await?.hosts.len(), 1);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn query_cached_against_tracker() -> anyhow::Result<()> {
+    with_timeout(cached_queries()).await
+}
+
+async fn watch_namespace() -> anyhow::Result<()> {
+    let tracker = TestTracker::new().await?;
+    let connection = tracker.connect().await?;
+    let namespace = Some(Namespace::from_name("watched"));
+    let content = HashAndFormat::raw(test_hash(0));
+    let announce_in = |seed, namespace| {
+        let signed_announce = AnnounceBuilder::new(seed)
+            .content(content)
+            .namespace(namespace)
+            .sign();
+        announce(connection.clone(), signed_announce)
+    };
+    announce_in(1, namespace).await?;
+
+    let client = TrackerClient::new(connection.clone(), test_secret_key(9), Default::default());
+    let query = QueryBuilder::new(content).namespace(namespace).build();
+    let mut updates = client.watch(query).boxed();
+    let update = updates.next().await.unwrap()?;
+    assert_eq!(update.diff.added, BTreeSet::from([test_node_id(1)]));
+
+    // only hosts in the namespace of the query show up
+    announce_in(2, None).await?;
+    announce_in(3, namespace).await?;
+    let update = updates.next().await.unwrap()?;
+    assert_eq!(update.diff.added, BTreeSet::from([test_node_id(3)]));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn watch_in_a_namespace() -> anyhow::Result<()> {
+    with_timeout(watch_namespace()).await
+}